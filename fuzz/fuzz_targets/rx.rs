@@ -0,0 +1,37 @@
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Feeds arbitrary bytes from the serial port through the whole receive path: framing,
+parsing, noting, and reassembly. */
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use std::io::Cursor;
+use std::path::PathBuf;
+use xbnet::ser::XBSerReader;
+use xbnet::xbrx::*;
+
+fuzz_target!(|data: &[u8]| {
+    let mut ser = XBSerReader::new(Box::new(Cursor::new(data.to_vec())), PathBuf::from("fuzz"));
+    let mut reframer = XBReframer::new();
+    while let Ok(frame) = rxxbframe(&mut ser) {
+        if let Some(RXFrame::RX(packet)) = frame {
+            reframer.reassemble(packet);
+        }
+    }
+});
@@ -0,0 +1,531 @@
+/*! Presence beacons, announcing each node's addresses to the others */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* tun and tap learn where an address lives only from traffic sent from it, so the
+first packet to a node goes out as a broadcast, or not at all.  A beacon says up front
+which addresses a node has.  It is broadcast every interval on a channel of its own,
+since nothing else in a beacon tells it apart from an Ethernet frame.  All integers
+are big-endian:
+
+  MAGIC
+  interval: u16       seconds until the next beacon
+  name_len: u8, name  UTF-8
+  nips: u8            then for each: family: u8 (4 or 6), and 4 or 16 bytes
+  nmacs: u8           then for each: 6 bytes
+
+The addresses are read from the tun and tap interfaces each time, so that addresses
+configured after startup are announced too.  A peer whose beacons stop is forgotten
+after EXPIRE_AFTER intervals, along with the tap entries pointing to it that no traffic
+has refreshed since its last beacon.  Its tun entries carry their own expiration,
+which a beacon pushes out to the same time. */
+
+use crate::events;
+use crate::neighbors;
+use crate::tap::{self, MacDests, XBTap, XB_BROADCAST};
+use crate::tun::XBTun;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use log::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::ffi::CStr;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const MAGIC: &[u8] = b"BC";
+
+/// The channel beacons use unless told otherwise
+pub const DEFAULT_CHANNEL: u8 = 255;
+
+/// How many missed beacons it takes to forget a peer
+const EXPIRE_AFTER: u32 = 3;
+
+/// How often expired peers are looked for
+const SWEEP: Duration = Duration::from_secs(1);
+
+/// The longest name sent, in bytes
+const MAX_NAME: usize = 64;
+
+/// What a node says about itself
+#[derive(Debug)]
+struct Announcement {
+    interval: Duration,
+    name: String,
+    ips: Vec<IpAddr>,
+    macs: Vec<[u8; 6]>,
+}
+
+/// A node we have heard beacons from
+struct Peer {
+    name: String,
+    /// When its last beacon was heard
+    heard: Instant,
+    expires: Instant,
+    ips: Vec<IpAddr>,
+    macs: Vec<[u8; 6]>,
+}
+
+#[derive(Clone)]
+pub struct Beacon {
+    pub mymac: u64,
+    pub name: String,
+    pub interval: Duration,
+    pub tun: Option<XBTun>,
+    pub tap: Option<XBTap>,
+    peers: Arc<Mutex<HashMap<u64, Peer>>>,
+}
+
+/// Take n bytes off the front of payload, if it has them.
+fn take(payload: &mut Bytes, n: usize) -> Option<Bytes> {
+    if payload.len() < n {
+        None
+    } else {
+        Some(payload.split_to(n))
+    }
+}
+
+fn mkmsg(a: &Announcement) -> Bytes {
+    let mut namelen = a.name.len().min(MAX_NAME);
+    while !a.name.is_char_boundary(namelen) {
+        namelen -= 1;
+    }
+    let name = &a.name[..namelen];
+    let ips = &a.ips[..a.ips.len().min(255)];
+    let macs = &a.macs[..a.macs.len().min(255)];
+    let mut msg = BytesMut::with_capacity(MAGIC.len() + 5 + name.len() + ips.len() * 17 + macs.len() * 6);
+    msg.put_slice(MAGIC);
+    msg.put_u16(a.interval.as_secs().min(u64::from(u16::MAX)) as u16);
+    msg.put_u8(name.len() as u8);
+    msg.put_slice(name.as_bytes());
+    msg.put_u8(ips.len() as u8);
+    for ip in ips {
+        match ip {
+            IpAddr::V4(ip) => {
+                msg.put_u8(4);
+                msg.put_slice(&ip.octets());
+            }
+            IpAddr::V6(ip) => {
+                msg.put_u8(6);
+                msg.put_slice(&ip.octets());
+            }
+        }
+    }
+    msg.put_u8(macs.len() as u8);
+    for mac in macs {
+        msg.put_slice(mac);
+    }
+    msg.freeze()
+}
+
+fn parsemsg(mut payload: Bytes) -> Option<Announcement> {
+    if !payload.starts_with(MAGIC) {
+        return None;
+    }
+    payload.advance(MAGIC.len());
+    let interval = Duration::from_secs(u64::from(take(&mut payload, 2)?.get_u16()));
+    let namelen = take(&mut payload, 1)?.get_u8();
+    let name = String::from_utf8_lossy(&take(&mut payload, usize::from(namelen))?).into_owned();
+    let nips = take(&mut payload, 1)?.get_u8();
+    let mut ips = Vec::with_capacity(usize::from(nips));
+    for _ in 0..nips {
+        let ip = match take(&mut payload, 1)?.get_u8() {
+            4 => {
+                let octets: [u8; 4] = take(&mut payload, 4)?.as_ref().try_into().unwrap();
+                IpAddr::V4(Ipv4Addr::from(octets))
+            }
+            6 => {
+                let octets: [u8; 16] = take(&mut payload, 16)?.as_ref().try_into().unwrap();
+                IpAddr::V6(Ipv6Addr::from(octets))
+            }
+            _ => return None,
+        };
+        ips.push(ip);
+    }
+    let nmacs = take(&mut payload, 1)?.get_u8();
+    let mut macs = Vec::with_capacity(usize::from(nmacs));
+    for _ in 0..nmacs {
+        macs.push(take(&mut payload, 6)?.as_ref().try_into().unwrap());
+    }
+    Some(Announcement {
+        interval,
+        name,
+        ips,
+        macs,
+    })
+}
+
+/// The IP and Ethernet addresses of the interface named iface
+fn iface_addrs(iface: &str) -> (Vec<IpAddr>, Vec<[u8; 6]>) {
+    let mut ips = Vec::new();
+    let mut macs = Vec::new();
+    let mut addrs: *mut libc::ifaddrs = ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        warn!("BEACON: getifaddrs: {}", io::Error::last_os_error());
+        return (ips, macs);
+    }
+    let mut cur = addrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() || unsafe { CStr::from_ptr(ifa.ifa_name) }.to_bytes() != iface.as_bytes() {
+            continue;
+        }
+        match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let sin = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                ips.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                let sin6 = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                ips.push(IpAddr::V6(Ipv6Addr::from(sin6.sin6_addr.s6_addr)));
+            }
+            libc::AF_PACKET => {
+                let sll = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_ll) };
+                if sll.sll_halen == 6 {
+                    macs.push(sll.sll_addr[..6].try_into().unwrap());
+                }
+            }
+            _ => (),
+        }
+    }
+    unsafe { libc::freeifaddrs(addrs) };
+    (ips, macs)
+}
+
+/// The name of this host, for beacons not given one
+pub fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return String::from("xbnet");
+    }
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+impl Beacon {
+    pub fn new(
+        mymac: u64,
+        name: String,
+        interval: Duration,
+        tun: Option<XBTun>,
+        tap: Option<XBTap>,
+    ) -> Beacon {
+        Beacon {
+            mymac,
+            name,
+            interval,
+            tun,
+            tap,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// What we say about ourselves right now
+    fn announcement(&self) -> Announcement {
+        let mut ips = Vec::new();
+        let mut macs = Vec::new();
+        if let Some(tun) = &self.tun {
+            ips.extend(iface_addrs(&tun.name).0);
+        }
+        if let Some(tap) = &self.tap {
+            let (tapips, tapmacs) = iface_addrs(&tap.name);
+            ips.extend(tapips);
+            macs.extend(tapmacs);
+        }
+        Announcement {
+            interval: self.interval,
+            name: self.name.clone(),
+            ips,
+            macs,
+        }
+    }
+
+    /** Broadcast a beacon every interval, forever.  The first waits an amount derived
+    from mymac, so that nodes started together don't all send at once. */
+    pub fn beacons_to_xb(&self, sender: crossbeam_channel::Sender<XBTX>) -> io::Result<()> {
+        thread::sleep(Duration::from_millis((self.mymac % 16) * 100));
+        loop {
+            let a = self.announcement();
+            debug!(
+                "BEACON: announcing {} with {} IPs and {} MACs",
+                a.name,
+                a.ips.len(),
+                a.macs.len()
+            );
+            sender
+                .send(XBTX::TXData(XBDestAddr::U64(XB_BROADCAST), mkmsg(&a)))
+                .map_err(|e| mkerror(&e.to_string()))?;
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Learn from the beacons of other nodes, and forget those gone quiet, forever.
+    pub fn beacons_from_xb(
+        &self,
+        framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    ) -> io::Result<()> {
+        loop {
+            match framerx.recv_timeout(SWEEP) {
+                Ok((fromu64, _fromu16, payload)) => {
+                    if fromu64 == self.mymac {
+                        continue;
+                    }
+                    match parsemsg(payload) {
+                        Some(a) => self.heard(fromu64, a),
+                        None => debug!("BEACON: ignoring frame from {:x}", fromu64),
+                    }
+                }
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => (),
+                Err(e) => return Err(mkerror(&e.to_string())),
+            }
+            self.sweep();
+        }
+    }
+
+    fn heard(&self, fromu64: u64, a: Announcement) {
+        let expires = Instant::now() + a.interval.max(Duration::from_secs(1)) * EXPIRE_AFTER;
+        debug!(
+            "BEACON: {:x} is {} with {:?} and {:?}",
+            fromu64,
+            a.name,
+            a.ips,
+            a.macs.iter().map(tap::showmac).collect::<Vec<_>>()
+        );
+        neighbors::named(fromu64, &a.name);
+        if let Some(tun) = self.tun.as_ref().filter(|t| !t.broadcast_everything) {
+            for ip in a.ips.iter() {
+                tun.learn(*ip, fromu64, expires);
+            }
+        }
+        if let Some(tap) = self.tap.as_ref().filter(|t| !t.broadcast_everything) {
+            for mac in a.macs.iter() {
+                tap.learn(*mac, fromu64);
+            }
+        }
+        // After learning, so that only traffic from now on refreshes the tap entries.
+        let heard = Instant::now();
+        let old = self.peers.lock().unwrap().insert(
+            fromu64,
+            Peer {
+                name: a.name.clone(),
+                heard,
+                expires,
+                ips: a.ips,
+                macs: a.macs,
+            },
+        );
+        if old.is_none() {
+            info!("BEACON: found {} at {:x}", a.name, fromu64);
+        }
+    }
+
+    /// Forget the peers whose beacons have stopped.
+    fn sweep(&self) {
+        let now = Instant::now();
+        let expired: Vec<(u64, Peer)> = {
+            let mut peers = self.peers.lock().unwrap();
+            let gone: Vec<u64> = peers
+                .iter()
+                .filter(|(_, p)| now >= p.expires)
+                .map(|(addr, _)| *addr)
+                .collect();
+            gone.into_iter()
+                .filter_map(|addr| peers.remove(&addr).map(|p| (addr, p)))
+                .collect()
+        };
+        for (addr, peer) in expired {
+            info!("BEACON: lost {} at {:x}", peer.name, addr);
+            if let Some(tun) = &self.tun {
+                forget_ips(&mut tun.dests.lock().unwrap(), addr, &peer, now);
+            }
+            if let Some(tap) = &self.tap {
+                forget_macs(&mut tap.dests.lock().unwrap(), addr, &peer);
+            }
+            events::emit(
+                "peer_expired",
+                vec![("addr", events::addr(addr)), ("name", peer.name.into())],
+            );
+        }
+    }
+}
+
+/** Remove the tun entries announced by the expired peer at addr that still point to it,
+unless traffic since its last beacon has kept them from expiring. */
+fn forget_ips(dests: &mut HashMap<IpAddr, (u64, Instant)>, addr: u64, peer: &Peer, now: Instant) {
+    for ip in peer.ips.iter() {
+        if dests
+            .get(ip)
+            .is_some_and(|(dest, expires)| *dest == addr && now >= *expires)
+        {
+            dests.remove(ip);
+        }
+    }
+}
+
+/** Remove the tap entries announced by the expired peer at addr that still point to it,
+unless traffic since its last beacon has refreshed them. */
+fn forget_macs(dests: &mut MacDests, addr: u64, peer: &Peer) {
+    for mac in peer.macs.iter() {
+        if dests
+            .get(mac)
+            .is_some_and(|(dest, learned)| *dest == addr && *learned <= peer.heard)
+        {
+            dests.remove(mac);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEER: u64 = 0x0013a20000000002;
+    const OTHER: u64 = 0x0013a20000000003;
+
+    fn announcement() -> Announcement {
+        Announcement {
+            interval: Duration::from_secs(60),
+            name: String::from("north-tower"),
+            ips: vec![
+                IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)),
+                IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2)),
+            ],
+            macs: vec![[2, 0, 0, 0, 0, 2], [2, 0, 0, 0, 0, 3]],
+        }
+    }
+
+    fn peer(heard: Instant, expires: Instant) -> Peer {
+        let a = announcement();
+        Peer {
+            name: a.name,
+            heard,
+            expires,
+            ips: a.ips,
+            macs: a.macs,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let a = announcement();
+        let parsed = parsemsg(mkmsg(&a)).unwrap();
+        assert_eq!(parsed.interval, a.interval);
+        assert_eq!(parsed.name, a.name);
+        assert_eq!(parsed.ips, a.ips);
+        assert_eq!(parsed.macs, a.macs);
+    }
+
+    #[test]
+    fn long_name_truncated_at_char_boundary() {
+        // Two-byte characters after one one-byte one put MAX_NAME mid-character.
+        let a = Announcement {
+            name: format!("a{}", "\u{e9}".repeat(40)),
+            ..announcement()
+        };
+        let parsed = parsemsg(mkmsg(&a)).unwrap();
+        assert_eq!(parsed.name, format!("a{}", "\u{e9}".repeat(31)));
+        assert_eq!(parsed.ips, a.ips);
+    }
+
+    #[test]
+    fn truncated_rejected() {
+        let msg = mkmsg(&announcement());
+        for len in 0..msg.len() {
+            assert!(
+                parsemsg(msg.slice(..len)).is_none(),
+                "accepted {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn bad_family_rejected() {
+        let a = announcement();
+        let mut msg = BytesMut::from(&mkmsg(&a)[..]);
+        // After MAGIC, interval, name, and the IP count
+        let family = MAGIC.len() + 2 + 1 + a.name.len() + 1;
+        assert_eq!(msg[family], 4);
+        msg[family] = 5;
+        assert!(parsemsg(msg.freeze()).is_none());
+    }
+
+    #[test]
+    fn bad_magic_rejected() {
+        let mut msg = BytesMut::from(&mkmsg(&announcement())[..]);
+        msg[0] = b'X';
+        assert!(parsemsg(msg.freeze()).is_none());
+    }
+
+    #[test]
+    fn sweep_forgets_expired_peers() {
+        let beacon = Beacon::new(1, String::from("me"), Duration::from_secs(1), None, None);
+        let now = Instant::now();
+        {
+            let mut peers = beacon.peers.lock().unwrap();
+            peers.insert(PEER, peer(now, now));
+            peers.insert(OTHER, peer(now, now + Duration::from_secs(60)));
+        }
+        beacon.sweep();
+        let peers = beacon.peers.lock().unwrap();
+        assert!(!peers.contains_key(&PEER));
+        assert!(peers.contains_key(&OTHER));
+    }
+
+    #[test]
+    fn forget_macs_keeps_refreshed() {
+        let heard = Instant::now();
+        let p = peer(heard, heard);
+        let mut dests = HashMap::new();
+        // Learned from the beacon, and since from traffic
+        dests.insert(p.macs[0], (PEER, heard));
+        dests.insert(p.macs[1], (PEER, heard + Duration::from_millis(1)));
+        // Not announced, and announced but now elsewhere
+        dests.insert([2, 0, 0, 0, 0, 4], (PEER, heard));
+        forget_macs(&mut dests, PEER, &p);
+        assert!(!dests.contains_key(&p.macs[0]));
+        assert!(dests.contains_key(&p.macs[1]));
+        assert!(dests.contains_key(&[2, 0, 0, 0, 0, 4]));
+
+        dests.insert(p.macs[0], (OTHER, heard));
+        forget_macs(&mut dests, PEER, &p);
+        assert_eq!(dests.get(&p.macs[0]), Some(&(OTHER, heard)));
+    }
+
+    #[test]
+    fn forget_ips_keeps_unexpired() {
+        let now = Instant::now();
+        let p = peer(now, now);
+        let mut dests = HashMap::new();
+        dests.insert(p.ips[0], (PEER, now));
+        dests.insert(p.ips[1], (PEER, now + Duration::from_secs(60)));
+        forget_ips(&mut dests, PEER, &p, now);
+        assert!(!dests.contains_key(&p.ips[0]));
+        assert!(dests.contains_key(&p.ips[1]));
+
+        dests.insert(p.ips[0], (OTHER, now));
+        forget_ips(&mut dests, PEER, &p, now);
+        assert_eq!(dests.get(&p.ips[0]), Some(&(OTHER, now)));
+    }
+}
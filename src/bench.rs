@@ -0,0 +1,500 @@
+/*! Measuring throughput between two radios */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Every message starts with MAGIC, a message type byte, and a u32 identifying the
+run.  All integers are big-endian.
+
+  START    client -> server
+  READY    server -> client
+  DATA     client -> server   seq: u64, then padding to the frame size
+  END      client -> server   frames: u64, packets: u64 of DATA sent
+  RESULT   server -> client   frames: u64, bytes: u64, packets: u64 of DATA received,
+                              millis: u64 from START to the last DATA
+
+START and END are repeated until answered.  DATA is never retransmitted, so what is
+lost shows as loss.  The server counts the packets of a run from stats, as the packets
+received from the client between START and END, so it sees the packets of frames that
+never completed; the client counts them the same way, and the transmit statuses of its
+packets with them.  Goodput counts whole DATA frames, header included. */
+
+use crate::stats;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use log::*;
+use std::collections::HashMap;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: &[u8] = b"BN";
+pub const MSG_START: u8 = b'S';
+pub const MSG_READY: u8 = b'R';
+pub const MSG_DATA: u8 = b'D';
+pub const MSG_END: u8 = b'E';
+pub const MSG_RESULT: u8 = b'T';
+
+/// Length of MAGIC, message type, run, and sequence number
+pub const DATA_HEADER_LEN: usize = 15;
+
+/// How many times START and END are sent before giving up
+const TRIES: u32 = 5;
+
+/// How often the client checks for transmit statuses after sending
+const POLL: Duration = Duration::from_millis(100);
+
+/// How a client runs
+#[derive(Clone, Copy, Debug)]
+pub struct BenchParams {
+    /// Stop sending after this long
+    pub duration: Option<Duration>,
+    /// Stop sending after this many bytes
+    pub bytes: Option<u64>,
+    /// The size of each DATA frame, at least DATA_HEADER_LEN
+    pub size: usize,
+    /// How long to wait for an answer to START or END, and for the packets sent to be
+    /// reported on
+    pub timeout: Duration,
+    /// Whether the radio is asked to acknowledge and retry packets
+    pub xbee_acks: bool,
+    /// Report in JSON rather than text
+    pub json: bool,
+}
+
+/// Build a message with the standard header.
+fn mkmsg(msgtype: u8, run: u32, body: &[u8]) -> Bytes {
+    let mut msg = BytesMut::with_capacity(MAGIC.len() + 5 + body.len());
+    msg.put_slice(MAGIC);
+    msg.put_u8(msgtype);
+    msg.put_u32(run);
+    msg.put_slice(body);
+    msg.freeze()
+}
+
+/// Parse the standard header of a message, returning the type, run, and body.
+fn parsemsg(mut payload: Bytes) -> Option<(u8, u32, Bytes)> {
+    if payload.len() < MAGIC.len() + 5 || !payload.starts_with(MAGIC) {
+        return None;
+    }
+    payload.advance(MAGIC.len());
+    let msgtype = payload.get_u8();
+    let run = payload.get_u32();
+    Some((msgtype, run, payload))
+}
+
+/// Parse a body of u64s, ignoring anything after them.
+fn parsecounts<const N: usize>(mut body: Bytes) -> Option<[u64; N]> {
+    if body.len() < 8 * N {
+        return None;
+    }
+    let mut counts = [0; N];
+    for count in counts.iter_mut() {
+        *count = body.get_u64();
+    }
+    Some(counts)
+}
+
+/// Packets received from peer so far, as counted by stats
+fn packets_in(peer: u64) -> u64 {
+    let s = stats::snapshot();
+    s.peers.get(&peer).map_or(0, |p| p.counters.packets_in)
+}
+
+/// The results of a run, as far as one end knows them
+struct Report {
+    peer: u64,
+    frames_sent: u64,
+    packets_sent: u64,
+    frames_received: u64,
+    bytes_received: u64,
+    packets_received: u64,
+    /// From START to the last DATA, at the server
+    receive_time: Duration,
+    /// Only the client knows the rest.
+    client: Option<ClientReport>,
+}
+
+struct ClientReport {
+    size: usize,
+    xbee_acks: bool,
+    bytes_sent: u64,
+    /// From the first DATA until the radio reported on the last
+    send_time: Duration,
+    tx_ok: u64,
+    tx_failed: u64,
+    tx_retries: u64,
+}
+
+/// part as a percentage of whole, or 0 if whole is
+fn percent(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        100.0 * part as f64 / whole as f64
+    }
+}
+
+impl Report {
+    fn goodput(&self) -> f64 {
+        let secs = self.receive_time.as_secs_f64();
+        if secs > 0.0 {
+            self.bytes_received as f64 * 8.0 / secs
+        } else {
+            0.0
+        }
+    }
+
+    fn frame_loss(&self) -> f64 {
+        percent(self.frames_sent.saturating_sub(self.frames_received), self.frames_sent)
+    }
+
+    fn fragment_loss(&self) -> f64 {
+        percent(self.packets_sent.saturating_sub(self.packets_received), self.packets_sent)
+    }
+
+    fn text(&self) -> Vec<String> {
+        let mut out = Vec::new();
+        if let Some(c) = &self.client {
+            out.push(format!(
+                "--- {:016x} bench: {}-byte frames, XBee ACKs {} ---",
+                self.peer,
+                c.size,
+                if c.xbee_acks { "on" } else { "off" }
+            ));
+            out.push(format!(
+                "sent {} frames, {} bytes, {} packets in {:.1} s",
+                self.frames_sent,
+                c.bytes_sent,
+                self.packets_sent,
+                c.send_time.as_secs_f64()
+            ));
+        } else {
+            out.push(format!("--- {:016x} bench ---", self.peer));
+        }
+        out.push(format!(
+            "received {} frames, {} bytes, {} packets in {:.1} s",
+            self.frames_received,
+            self.bytes_received,
+            self.packets_received,
+            self.receive_time.as_secs_f64()
+        ));
+        out.push(format!(
+            "goodput {:.0} bit/s, frame loss {:.1}%, fragment loss {:.1}%",
+            self.goodput(),
+            self.frame_loss(),
+            self.fragment_loss()
+        ));
+        if let Some(c) = &self.client {
+            let missing = self.packets_sent.saturating_sub(c.tx_ok + c.tx_failed);
+            out.push(format!(
+                "tx status {} ok, {} failed, {} missing, {} retries ({:.2} per packet)",
+                c.tx_ok,
+                c.tx_failed,
+                missing,
+                c.tx_retries,
+                c.tx_retries as f64 / (c.tx_ok + c.tx_failed).max(1) as f64
+            ));
+        }
+        out
+    }
+
+    fn json(&self) -> String {
+        let mut fields = vec![
+            format!("\"peer\":\"{:016x}\"", self.peer),
+            format!("\"frames_sent\":{}", self.frames_sent),
+            format!("\"packets_sent\":{}", self.packets_sent),
+        ];
+        if let Some(c) = &self.client {
+            fields.push(format!("\"frame_size\":{}", c.size));
+            fields.push(format!("\"xbee_acks\":{}", c.xbee_acks));
+            fields.push(format!("\"bytes_sent\":{}", c.bytes_sent));
+            fields.push(format!("\"send_seconds\":{:.3}", c.send_time.as_secs_f64()));
+        }
+        fields.push(format!("\"frames_received\":{}", self.frames_received));
+        fields.push(format!("\"bytes_received\":{}", self.bytes_received));
+        fields.push(format!("\"packets_received\":{}", self.packets_received));
+        fields.push(format!(
+            "\"receive_seconds\":{:.3}",
+            self.receive_time.as_secs_f64()
+        ));
+        fields.push(format!("\"goodput_bps\":{:.0}", self.goodput()));
+        fields.push(format!("\"frame_loss_percent\":{:.1}", self.frame_loss()));
+        fields.push(format!("\"fragment_loss_percent\":{:.1}", self.fragment_loss()));
+        if let Some(c) = &self.client {
+            fields.push(format!("\"tx_ok\":{}", c.tx_ok));
+            fields.push(format!("\"tx_failed\":{}", c.tx_failed));
+            fields.push(format!("\"tx_retries\":{}", c.tx_retries));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+
+    fn print(&self, json: bool) {
+        if json {
+            println!("{}", self.json());
+        } else {
+            for line in self.text() {
+                println!("{}", line);
+            }
+        }
+    }
+}
+
+/** Send msg to dest until it answers with a message of replytype for run, returning
+the body of the answer. */
+fn exchange(
+    dest: u64,
+    sender: &crossbeam_channel::Sender<XBTX>,
+    framerx: &crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    msg: Bytes,
+    run: u32,
+    replytype: u8,
+    timeout: Duration,
+) -> io::Result<Bytes> {
+    for _ in 0..TRIES {
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(dest), msg.clone()))
+            .map_err(|e| mkerror(&e.to_string()))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let (fromu64, _fromu16, payload) =
+                match framerx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(frame) => frame,
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => break,
+                    Err(e) => return Err(mkerror(&e.to_string())),
+                };
+            match parsemsg(payload) {
+                Some((msgtype, r, body)) if fromu64 == dest && msgtype == replytype && r == run => {
+                    return Ok(body)
+                }
+                _ => debug!("BENCH: ignoring frame from {:x}", fromu64),
+            }
+        }
+    }
+    Err(mkerror("no answer from the bench server"))
+}
+
+/** Send DATA on channel to a bench server at dest for as long as params say, then
+fetch its counts and print a report. */
+pub fn client(
+    dest: u64,
+    channel: u8,
+    maxpacketsize: usize,
+    sender: crossbeam_channel::Sender<XBTX>,
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    params: BenchParams,
+) -> io::Result<()> {
+    if params.size < DATA_HEADER_LEN {
+        return Err(mkerror(&format!(
+            "frames must be at least {} bytes",
+            DATA_HEADER_LEN
+        )));
+    }
+    if params.size > max_frame_len(maxpacketsize, channel) {
+        return Err(mkerror(&format!(
+            "frames may be at most {} bytes",
+            max_frame_len(maxpacketsize, channel)
+        )));
+    }
+    let run = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let start = mkmsg(MSG_START, run, &[]);
+    exchange(dest, &sender, &framerx, start, run, MSG_READY, params.timeout)?;
+
+    let peer = |s: &stats::Stats| s.peers.get(&dest).map(|p| p.counters.clone());
+    let before = peer(&stats::snapshot());
+    let started = Instant::now();
+    let mut frames = 0;
+    let mut bytes = 0;
+    loop {
+        if params.duration.is_some_and(|d| started.elapsed() >= d)
+            || params.bytes.is_some_and(|b| bytes >= b)
+        {
+            break;
+        }
+        let mut body = BytesMut::with_capacity(params.size);
+        body.put_u64(frames);
+        body.resize(params.size - DATA_HEADER_LEN + 8, 0);
+        let msg = mkmsg(MSG_DATA, run, &body);
+        bytes += msg.len() as u64;
+        frames += 1;
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(dest), msg))
+            .map_err(|e| mkerror(&e.to_string()))?;
+        // Nothing is expected now, but a full queue would hold up receiving.
+        while framerx.try_recv().is_ok() {}
+    }
+
+    // Wait for every packet to be written, and the radio to report on it.
+    let expected = frames * packet_count(maxpacketsize, channel, params.size) as u64;
+    let diff = |s: &stats::Stats| {
+        let (before, now) = (before.clone(), peer(s));
+        let field = |f: fn(&stats::Counters) -> u64| {
+            now.as_ref().map_or(0, f) - before.as_ref().map_or(0, f)
+        };
+        (
+            field(|c| c.packets_out),
+            field(|c| c.tx_ok),
+            field(|c| c.tx_failed),
+            field(|c| c.tx_retries),
+        )
+    };
+    let waituntil = Instant::now() + params.timeout;
+    let (packets, tx_ok, tx_failed, tx_retries) = loop {
+        let (packets, tx_ok, tx_failed, tx_retries) = diff(&stats::snapshot());
+        let done = packets >= expected && tx_ok + tx_failed >= packets;
+        if done || Instant::now() >= waituntil {
+            break (packets, tx_ok, tx_failed, tx_retries);
+        }
+        while framerx.try_recv().is_ok() {}
+        thread::sleep(POLL);
+    };
+    let send_time = started.elapsed();
+
+    let mut body = BytesMut::with_capacity(16);
+    body.put_u64(frames);
+    body.put_u64(packets);
+    let end = mkmsg(MSG_END, run, &body);
+    let result = exchange(dest, &sender, &framerx, end, run, MSG_RESULT, params.timeout)?;
+    let [frames_received, bytes_received, packets_received, millis] =
+        parsecounts(result).ok_or_else(|| mkerror("malformed result from the bench server"))?;
+
+    let report = Report {
+        peer: dest,
+        frames_sent: frames,
+        packets_sent: packets,
+        frames_received,
+        bytes_received,
+        packets_received,
+        receive_time: Duration::from_millis(millis),
+        client: Some(ClientReport {
+            size: params.size,
+            xbee_acks: params.xbee_acks,
+            bytes_sent: bytes,
+            send_time,
+            tx_ok,
+            tx_failed,
+            tx_retries,
+        }),
+    };
+    report.print(params.json);
+    Ok(())
+}
+
+/// A run in progress at the server, or finished
+struct Run {
+    id: u32,
+    started: Instant,
+    /// When the last DATA arrived
+    last: Instant,
+    /// Packets received from the client as of START
+    baseline: u64,
+    frames: u64,
+    bytes: u64,
+    /// The answer to END, once it has come
+    result: Option<Bytes>,
+}
+
+/// Answer bench clients forever, printing a report at the end of each run.
+pub fn server(
+    sender: crossbeam_channel::Sender<XBTX>,
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    json: bool,
+) -> io::Result<()> {
+    // The latest run of each client
+    let mut runs: HashMap<u64, Run> = HashMap::new();
+    loop {
+        let (fromu64, _fromu16, payload) = framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
+        let len = payload.len() as u64;
+        let (msgtype, id, body) = match parsemsg(payload) {
+            Some(msg) => msg,
+            None => {
+                debug!("BENCH: ignoring frame from {:x}", fromu64);
+                continue;
+            }
+        };
+        let run = runs.get_mut(&fromu64).filter(|run| run.id == id);
+        let reply = match (msgtype, run) {
+            (MSG_START, Some(_)) => mkmsg(MSG_READY, id, &[]),
+            (MSG_START, None) => {
+                info!("BENCH: run {:x} from {:x} starting", id, fromu64);
+                let now = Instant::now();
+                runs.insert(
+                    fromu64,
+                    Run {
+                        id,
+                        started: now,
+                        last: now,
+                        baseline: packets_in(fromu64),
+                        frames: 0,
+                        bytes: 0,
+                        result: None,
+                    },
+                );
+                mkmsg(MSG_READY, id, &[])
+            }
+            (MSG_DATA, Some(run)) if run.result.is_none() => {
+                run.frames += 1;
+                run.bytes += len;
+                run.last = Instant::now();
+                continue;
+            }
+            (MSG_END, Some(run)) => {
+                if run.result.is_none() {
+                    let [frames_sent, packets_sent] = match parsecounts(body) {
+                        Some(counts) => counts,
+                        None => {
+                            debug!("BENCH: malformed END from {:x}", fromu64);
+                            continue;
+                        }
+                    };
+                    // Less the packet of this END
+                    let packets = packets_in(fromu64).saturating_sub(run.baseline + 1);
+                    let elapsed = run.last.duration_since(run.started);
+                    let mut result = BytesMut::with_capacity(32);
+                    result.put_u64(run.frames);
+                    result.put_u64(run.bytes);
+                    result.put_u64(packets);
+                    result.put_u64(elapsed.as_millis() as u64);
+                    run.result = Some(mkmsg(MSG_RESULT, id, &result));
+                    Report {
+                        peer: fromu64,
+                        frames_sent,
+                        packets_sent,
+                        frames_received: run.frames,
+                        bytes_received: run.bytes,
+                        packets_received: packets,
+                        receive_time: elapsed,
+                        client: None,
+                    }
+                    .print(json);
+                }
+                run.result.clone().unwrap()
+            }
+            _ => {
+                debug!("BENCH: ignoring message {:02x} of run {:x} from {:x}", msgtype, id, fromu64);
+                continue;
+            }
+        };
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(fromu64), reply))
+            .map_err(|e| mkerror(&e.to_string()))?;
+    }
+}
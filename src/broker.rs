@@ -0,0 +1,396 @@
+/*! Sharing the radio with other local processes over a Unix socket */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Clients speak the same XBee API frames over the socket that the radio speaks over
+the serial port, so anything that can drive the radio can be a client.  Frames from
+a client are passed to the radio, except that nonzero frame IDs are replaced with
+ones unique across clients.  Responses carrying those IDs (AT command responses and
+TX statuses) go back only to the client that asked, with its own ID restored.  Every
+other frame from the radio goes to each client whose filters match it.
+
+A client sets its filters with a frame of type SUBSCRIBE, containing zero or more
+filters of FILTER_LEN bytes each:
+
+  frame type: u8 (0 for any), sender: u64 (0 for any), flags: u8, channel: u8
+
+where channel is only considered if flags has FILTER_CHANNEL set.  The sender and
+channel only match receive packets (0x90).  A frame goes to the client if any filter
+matches.  Until it subscribes, a client receives every frame. */
+
+use crate::ser::*;
+use crate::xb::*;
+use crate::xbpacket::*;
+use crate::xbrx::*;
+use bytes::*;
+use log::*;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::io::Write;
+use std::net::Shutdown;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The frame type a client uses to set its filters
+pub const SUBSCRIBE: u8 = 0xF0;
+pub const FILTER_LEN: usize = 11;
+pub const FILTER_CHANNEL: u8 = 0x01;
+
+/// Frame types that answer a request, and are routed by frame ID
+const RESPONSE_TYPES: &[u8] = &[0x88, 0x89, 0x8B, 0x97];
+
+/// AT commands answered by any number of responses
+const MULTIPLE_RESPONSES: &[&[u8]] = &[b"ND", b"FN"];
+
+/** How long a frame ID given to the radio is held for its response; longer than node
+discovery can take */
+const FRAMEID_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many frames may wait to be written to a client before it is dropped
+const CLIENT_QUEUE: usize = 512;
+
+/// Which unsolicited frames from the radio a client wants
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    pub frametype: Option<u8>,
+    pub sender: Option<u64>,
+    pub channel: Option<u8>,
+}
+
+impl Filter {
+    pub fn any() -> Self {
+        Filter {
+            frametype: None,
+            sender: None,
+            channel: None,
+        }
+    }
+
+    /// Receive packets on an xbnet channel, from any sender
+    pub fn channel(channel: u8) -> Self {
+        Filter {
+            channel: Some(channel),
+            ..Filter::any()
+        }
+    }
+
+    /// Whether the inside of an API frame, as from rxapiframe, matches.
+    fn matches(&self, inner: &[u8]) -> bool {
+        let frametype = match inner.first() {
+            Some(t) => *t,
+            None => return false,
+        };
+        if self.frametype.is_some_and(|t| t != frametype) {
+            return false;
+        }
+        if self.sender.is_none() && self.channel.is_none() {
+            return true;
+        }
+        // 0x90: type, sender u64, sender u16, options, payload
+        if frametype != 0x90 || inner.len() < 12 {
+            return false;
+        }
+        let sender = u64::from_be_bytes(inner[1..9].try_into().unwrap());
+        if self.sender.is_some_and(|s| s != sender) {
+            return false;
+        }
+        match self.channel {
+            None => true,
+            Some(c) => parse_header(&inner[12..]).is_some_and(|(_, channel, _)| channel == c),
+        }
+    }
+}
+
+/// Build the frame that sets a client's filters.
+pub fn mksubscribe(filters: &[Filter]) -> Bytes {
+    let mut inner = BytesMut::new();
+    inner.put_u8(SUBSCRIBE);
+    for filter in filters {
+        inner.put_u8(filter.frametype.unwrap_or(0));
+        inner.put_u64(filter.sender.unwrap_or(0));
+        inner.put_u8(if filter.channel.is_some() { FILTER_CHANNEL } else { 0 });
+        inner.put_u8(filter.channel.unwrap_or(0));
+    }
+    mkapiframe(&inner).unwrap()
+}
+
+/** As a client of a broker, ask for only the receive packets on channels.  Responses to
+our own requests come regardless. */
+pub fn subscribe(sender: &crossbeam_channel::Sender<XBTX>, channels: &[u8]) -> io::Result<()> {
+    let filters: Vec<Filter> = channels.iter().map(|c| Filter::channel(*c)).collect();
+    sender
+        .send(XBTX::TXRaw(mksubscribe(&filters)))
+        .map_err(|e| mkerror(&e.to_string()))
+}
+
+/// Parse the body of a SUBSCRIBE frame, after the frame type.
+fn parsesubscribe(body: &[u8]) -> Option<Vec<Filter>> {
+    if body.len() % FILTER_LEN != 0 {
+        return None;
+    }
+    Some(
+        body.chunks(FILTER_LEN)
+            .map(|f| Filter {
+                frametype: if f[0] == 0 { None } else { Some(f[0]) },
+                sender: match u64::from_be_bytes(f[1..9].try_into().unwrap()) {
+                    0 => None,
+                    s => Some(s),
+                },
+                channel: if f[9] & FILTER_CHANNEL != 0 {
+                    Some(f[10])
+                } else {
+                    None
+                },
+            })
+            .collect(),
+    )
+}
+
+struct Client {
+    /// Frames waiting to be written to the client by its writer thread
+    queue: crossbeam_channel::Sender<Bytes>,
+    /// For shutting the connection down when the client is dropped
+    stream: UnixStream,
+    filters: Mutex<Vec<Filter>>,
+}
+
+/// State shared between the threads of the broker
+struct Shared {
+    clients: Mutex<HashMap<u64, Arc<Client>>>,
+
+    /** For each frame ID we gave the radio, the client and the frame ID it used, and
+    when the request was sent */
+    frameids: Mutex<HashMap<u8, (u64, u8, Instant)>>,
+    nextframeid: Mutex<u8>,
+}
+
+impl Shared {
+    /** Pick the frame ID to give the radio for a request from a client: the next one
+    that isn't awaiting a response, or if all are, the one awaiting longest. */
+    fn mapframeid(&self, clientid: u64, frameid: u8) -> u8 {
+        let mut frameids = self.frameids.lock().unwrap();
+        frameids.retain(|_, (_, _, sent)| sent.elapsed() < FRAMEID_TIMEOUT);
+        let mut next = self.nextframeid.lock().unwrap();
+        let start = *next;
+        let brokerid = (0..u8::MAX)
+            .map(|i| ((u16::from(start) - 1 + u16::from(i)) % 255 + 1) as u8)
+            .find(|id| !frameids.contains_key(id))
+            .unwrap_or_else(|| {
+                let (oldest, _) = frameids
+                    .iter()
+                    .min_by_key(|(_, (_, _, sent))| *sent)
+                    .unwrap();
+                debug!("BROKER: all frame IDs in use; reusing {:X}", oldest);
+                *oldest
+            });
+        *next = if brokerid == u8::MAX { 1 } else { brokerid + 1 };
+        frameids.insert(brokerid, (clientid, frameid, Instant::now()));
+        brokerid
+    }
+
+    /** Find the client and frame ID for a response from the radio.  Node discovery
+    brings several responses to one request, so those are kept until they expire. */
+    fn responseowner(&self, inner: &[u8]) -> Option<(u64, u8)> {
+        let mut frameids = self.frameids.lock().unwrap();
+        let repeats =
+            inner[0] == 0x88 && inner.len() >= 4 && MULTIPLE_RESPONSES.contains(&&inner[2..4]);
+        let owner = if repeats {
+            frameids.get(&inner[1]).copied()
+        } else {
+            frameids.remove(&inner[1])
+        };
+        owner.map(|(clientid, frameid, _)| (clientid, frameid))
+    }
+
+    /** Queue a frame for a client.  A client that has fallen too far behind is dropped,
+    rather than holding up the radio and the other clients. */
+    fn sendto(&self, clientid: u64, client: &Client, frame: Bytes) {
+        match client.queue.try_send(frame) {
+            Ok(()) => (),
+            Err(crossbeam_channel::TrySendError::Full(_)) => {
+                warn!("BROKER: dropping client {}: it is not keeping up", clientid);
+                self.disconnect(clientid, client);
+            }
+            Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
+                self.disconnect(clientid, client);
+            }
+        }
+    }
+
+    /// Forget a client, and close its connection if that hasn't happened already.
+    fn disconnect(&self, clientid: u64, client: &Client) {
+        self.clients.lock().unwrap().remove(&clientid);
+        self.frameids
+            .lock()
+            .unwrap()
+            .retain(|_, (owner, _, _)| *owner != clientid);
+        let _ = client.stream.shutdown(Shutdown::Both);
+    }
+}
+
+/** Serve clients on a Unix socket at socketpath, using the radio given by ser and
+sender.  Runs until the radio fails. */
+pub fn broker(
+    mut ser: XBSerReader,
+    socketpath: &Path,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let listener = bind_socket(socketpath)?;
+    info!("BROKER: listening on {:?}", socketpath);
+
+    let shared = Arc::new(Shared {
+        clients: Mutex::new(HashMap::new()),
+        frameids: Mutex::new(HashMap::new()),
+        nextframeid: Mutex::new(1),
+    });
+
+    let acceptshared = shared.clone();
+    thread::spawn(move || {
+        for (clientid, stream) in (1..).zip(listener.incoming()) {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = accept(&acceptshared, clientid, stream, sender.clone()) {
+                        warn!("BROKER: failed to accept client: {}", e);
+                    }
+                }
+                Err(e) => warn!("BROKER: failed to accept client: {}", e),
+            }
+        }
+    });
+
+    loop {
+        let mut inner = match rxapiframe(&mut ser)? {
+            Some(inner) => inner.to_vec(),
+            None => continue,
+        };
+
+        if inner.len() >= 2 && RESPONSE_TYPES.contains(&inner[0]) && inner[1] != 0 {
+            let (clientid, frameid) = match shared.responseowner(&inner) {
+                Some(owner) => owner,
+                None => {
+                    debug!("BROKER: no client for response to frame ID {:X}", inner[1]);
+                    continue;
+                }
+            };
+            inner[1] = frameid;
+            let client = shared.clients.lock().unwrap().get(&clientid).cloned();
+            if let Some(client) = client {
+                shared.sendto(clientid, &client, mkapiframe(&inner).unwrap());
+            }
+            continue;
+        }
+
+        let frame = mkapiframe(&inner).unwrap();
+        let clients: Vec<(u64, Arc<Client>)> = shared
+            .clients
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(id, c)| (*id, c.clone()))
+            .collect();
+        for (clientid, client) in clients {
+            let wanted = client
+                .filters
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|f| f.matches(&inner));
+            if wanted {
+                shared.sendto(clientid, &client, frame.clone());
+            }
+        }
+    }
+}
+
+/// Start serving a newly-connected client.
+fn accept(
+    shared: &Arc<Shared>,
+    clientid: u64,
+    stream: UnixStream,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    debug!("BROKER: client {} connected", clientid);
+    let mut clientser = XBSerReader::new(
+        Box::new(stream.try_clone()?),
+        PathBuf::from(format!("client {}", clientid)),
+    );
+    let mut writer = stream.try_clone()?;
+    let (queue, frames) = crossbeam_channel::bounded::<Bytes>(CLIENT_QUEUE);
+    let client = Arc::new(Client {
+        queue,
+        stream,
+        filters: Mutex::new(vec![Filter::any()]),
+    });
+    shared
+        .clients
+        .lock()
+        .unwrap()
+        .insert(clientid, client.clone());
+
+    // Ends when the client is dropped, which closes the queue, or can't be written to.
+    thread::spawn(move || {
+        for frame in frames.iter() {
+            if let Err(e) = writer.write_all(&frame) {
+                debug!("BROKER: failed to write to client {}: {}", clientid, e);
+                break;
+            }
+        }
+        let _ = writer.shutdown(Shutdown::Both);
+    });
+
+    let shared = shared.clone();
+    thread::spawn(move || {
+        loop {
+            let mut inner = match rxapiframe(&mut clientser) {
+                Ok(Some(inner)) if !inner.is_empty() => inner.to_vec(),
+                Ok(_) => continue,
+                Err(e) => {
+                    debug!("BROKER: client {} disconnected: {}", clientid, e);
+                    break;
+                }
+            };
+            if inner[0] == SUBSCRIBE {
+                match parsesubscribe(&inner[1..]) {
+                    Some(filters) => {
+                        debug!("BROKER: client {} subscribed to {:?}", clientid, filters);
+                        *client.filters.lock().unwrap() = filters;
+                    }
+                    None => warn!("BROKER: client {} sent a malformed subscription", clientid),
+                }
+                continue;
+            }
+            if inner.len() >= 2 && inner[1] != 0 {
+                inner[1] = shared.mapframeid(clientid, inner[1]);
+            }
+            match mkapiframe(&inner) {
+                Ok(frame) => {
+                    if sender.send(XBTX::TXRaw(frame)).is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!("BROKER: bad frame from client {}: {:?}", clientid, e),
+            }
+        }
+        shared.disconnect(clientid, &client);
+    });
+    Ok(())
+}
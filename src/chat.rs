@@ -0,0 +1,158 @@
+/*! Line-oriented text chat */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Each line is sent as one xbnet frame:
+
+  MAGIC, kind: u8, nick length: u8, nick, text
+
+where kind is KIND_BROADCAST or KIND_DIRECT. */
+
+use crate::tap::XB_BROADCAST;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use chrono::Local;
+use log::*;
+use std::io;
+use std::io::{BufRead, Write};
+
+pub const MAGIC: &[u8] = b"XC";
+pub const KIND_BROADCAST: u8 = b'B';
+pub const KIND_DIRECT: u8 = b'D';
+
+/// Build a chat message.
+fn mkmsg(kind: u8, nick: &str, text: &str) -> Bytes {
+    let nick = &nick.as_bytes()[..std::cmp::min(nick.len(), 255)];
+    let mut msg = BytesMut::with_capacity(MAGIC.len() + 2 + nick.len() + text.len());
+    msg.put_slice(MAGIC);
+    msg.put_u8(kind);
+    msg.put_u8(nick.len() as u8);
+    msg.put_slice(nick);
+    msg.put_slice(text.as_bytes());
+    msg.freeze()
+}
+
+/// Parse a chat message, returning the kind, nick, and text.
+fn parsemsg(mut payload: Bytes) -> Option<(u8, String, String)> {
+    if payload.len() < MAGIC.len() + 2 || !payload.starts_with(MAGIC) {
+        return None;
+    }
+    payload.advance(MAGIC.len());
+    let kind = payload.get_u8();
+    let nicklen = usize::from(payload.get_u8());
+    if payload.len() < nicklen {
+        return None;
+    }
+    let nick = sanitize(&payload.split_to(nicklen));
+    let text = sanitize(&payload);
+    Some((kind, nick, text))
+}
+
+/// Convert to a string, replacing control characters so they can't mess up the terminal.
+fn sanitize(data: &[u8]) -> String {
+    String::from_utf8_lossy(data)
+        .chars()
+        .map(|c| if c.is_control() { '?' } else { c })
+        .collect()
+}
+
+/** Read lines from stdin and send them on channel.  Lines are broadcast, except for these
+commands:
+
+  /msg ADDRESS TEXT   send TEXT only to the given 64-bit address, in hex
+  /nick NAME          change nickname
+
+A line too long for one frame is refused, with a message saying so.
+*/
+pub fn stdin_processor(
+    mut nick: String,
+    maxpacketsize: usize,
+    channel: u8,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let maxlen = max_frame_len(maxpacketsize, channel);
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+
+        let (dest, msg) = if let Some(rest) = line.strip_prefix("/msg ") {
+            let mut parts = rest.trim_start().splitn(2, ' ');
+            let addr = parts.next().unwrap_or("");
+            let text = parts.next().unwrap_or("");
+            match u64::from_str_radix(addr, 16) {
+                Ok(dest) if !text.is_empty() => (dest, mkmsg(KIND_DIRECT, &nick, text)),
+                _ => {
+                    eprintln!("Usage: /msg ADDRESS TEXT");
+                    continue;
+                }
+            }
+        } else if let Some(rest) = line.strip_prefix("/nick ") {
+            nick = String::from(rest.trim());
+            eprintln!("You are now known as {}", nick);
+            continue;
+        } else if line.starts_with('/') {
+            eprintln!("Unknown command; use /msg ADDRESS TEXT or /nick NAME");
+            continue;
+        } else {
+            (XB_BROADCAST, mkmsg(KIND_BROADCAST, &nick, line))
+        };
+        if msg.len() > maxlen {
+            eprintln!(
+                "Message not sent: it is {} bytes with your nickname, and at most {} fit",
+                msg.len(),
+                maxlen
+            );
+            continue;
+        }
+
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(dest), msg))
+            .unwrap();
+    }
+
+    // EOF
+    sender.send(XBTX::Shutdown).unwrap();
+    Ok(())
+}
+
+/// Display incoming chat messages with their time and sender.
+pub fn stdout_processor(framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    loop {
+        let (fromu64, _fromu16, payload) = framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
+        let (kind, nick, text) = match parsemsg(payload) {
+            Some(msg) => msg,
+            None => {
+                debug!("Ignoring non-chat frame from {:x}", fromu64);
+                continue;
+            }
+        };
+        let time = Local::now().format("%H:%M:%S");
+        if kind == KIND_DIRECT {
+            writeln!(stdout, "[{}] *{}@{:x}* {}", time, nick, fromu64, text)?;
+        } else {
+            writeln!(stdout, "[{}] <{}@{:x}> {}", time, nick, fromu64, text)?;
+        }
+        stdout.flush()?;
+    }
+}
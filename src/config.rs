@@ -0,0 +1,367 @@
+/*! Reading configuration files */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Configuration files are TOML.  Settings before the first [table] header belong to
+the top-level table, and each header starts a table of its own.  A table within a
+table, whether from a dotted header or an inline table, is refused, since no service
+has one. */
+
+use crate::xb::mkerror;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+use toml::Value;
+
+/// The keys and values under one header
+#[derive(Debug)]
+pub struct Table {
+    /// The name in the header; empty for the top-level table
+    pub name: String,
+    entries: BTreeMap<String, Value>,
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub top: Table,
+
+    /// The remaining tables, in the order they appear
+    pub tables: Vec<Table>,
+}
+
+impl Table {
+    fn new(name: &str) -> Self {
+        Table {
+            name: String::from(name),
+            entries: BTreeMap::new(),
+        }
+    }
+
+    /// Describe a key for error messages.
+    fn describe(&self, key: &str) -> String {
+        if self.name.is_empty() {
+            String::from(key)
+        } else {
+            format!("{}.{}", self.name, key)
+        }
+    }
+
+    /// Return an error naming the first key that isn't in allowed.
+    pub fn check_keys(&self, allowed: &[&str]) -> io::Result<()> {
+        match self.entries.keys().find(|k| !allowed.contains(&k.as_str())) {
+            Some(key) => Err(mkerror(&format!("{}: unknown setting", self.describe(key)))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get_str(&self, key: &str) -> io::Result<Option<String>> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(mkerror(&format!(
+                "{}: expected a string",
+                self.describe(key)
+            ))),
+        }
+    }
+
+    pub fn get_bool(&self, key: &str) -> io::Result<Option<bool>> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Boolean(b)) => Ok(Some(*b)),
+            Some(_) => Err(mkerror(&format!(
+                "{}: expected true or false",
+                self.describe(key)
+            ))),
+        }
+    }
+
+    /// Get an integer, which must fit in T.
+    pub fn get_int<T: TryFrom<i64>>(&self, key: &str) -> io::Result<Option<T>> {
+        match self.entries.get(key) {
+            None => Ok(None),
+            Some(Value::Integer(i)) => T::try_from(*i)
+                .map(Some)
+                .map_err(|_| mkerror(&format!("{}: {} is out of range", self.describe(key), i))),
+            Some(_) => Err(mkerror(&format!(
+                "{}: expected an integer",
+                self.describe(key)
+            ))),
+        }
+    }
+
+    /// Get a length of time given in seconds, as an integer or a float.
+    pub fn get_secs(&self, key: &str) -> io::Result<Option<Duration>> {
+        let secs = match self.entries.get(key) {
+            None => return Ok(None),
+            Some(Value::Integer(i)) => *i as f64,
+            Some(Value::Float(f)) => *f,
+            Some(_) => {
+                return Err(mkerror(&format!(
+                    "{}: expected a number of seconds",
+                    self.describe(key)
+                )))
+            }
+        };
+        if !(0.0..=u64::MAX as f64).contains(&secs) {
+            return Err(mkerror(&format!(
+                "{}: {} is out of range",
+                self.describe(key),
+                secs
+            )));
+        }
+        Ok(Some(Duration::from_secs_f64(secs)))
+    }
+
+    /// Get a 64-bit XBee address, given as a hex string.
+    pub fn get_addr(&self, key: &str) -> io::Result<Option<u64>> {
+        match self.get_str(key)? {
+            None => Ok(None),
+            Some(s) => u64::from_str_radix(&s, 16)
+                .map(Some)
+                .map_err(|_| mkerror(&format!("{}: invalid address {:?}", self.describe(key), s))),
+        }
+    }
+}
+
+/// Parse a configuration from text.
+pub fn parse(text: &str) -> io::Result<Config> {
+    let parsed: toml::value::Table = toml::from_str(text).map_err(|e| mkerror(&e.to_string()))?;
+    let mut config = Config {
+        top: Table::new(""),
+        tables: Vec::new(),
+    };
+
+    for (name, value) in parsed {
+        match value {
+            Value::Table(entries) => {
+                let mut table = Table::new(&name);
+                for (key, value) in entries {
+                    if value.is_table() {
+                        return Err(mkerror(&format!(
+                            "{}: tables may not be nested",
+                            table.describe(&key)
+                        )));
+                    }
+                    table.entries.insert(key, value);
+                }
+                config.tables.push(table);
+            }
+            value => {
+                config.top.entries.insert(name, value);
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Read and parse a configuration file.
+pub fn read(path: &Path) -> io::Result<Config> {
+    let text =
+        fs::read_to_string(path).map_err(|e| mkerror(&format!("{}: {}", path.display(), e)))?;
+    parse(&text).map_err(|e| mkerror(&format!("{}: {}", path.display(), e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table<'a>(config: &'a Config, name: &str) -> &'a Table {
+        config.tables.iter().find(|t| t.name == name).unwrap()
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn values() {
+        let config = parse("a = 42\nb = -7\nc = 0x1F\nd = 1_000\ne = true\nf = false\n").unwrap();
+        let top = &config.top;
+        assert_eq!(top.get_int::<i64>("a").unwrap(), Some(42));
+        assert_eq!(top.get_int::<i64>("b").unwrap(), Some(-7));
+        assert_eq!(top.get_int::<u8>("c").unwrap(), Some(0x1f));
+        assert_eq!(top.get_int::<u32>("d").unwrap(), Some(1000));
+        assert_eq!(top.get_bool("e").unwrap(), Some(true));
+        assert_eq!(top.get_bool("f").unwrap(), Some(false));
+        assert_eq!(top.get_int::<i64>("missing").unwrap(), None);
+    }
+
+    #[test]
+    fn quoting() {
+        let config = parse(concat!(
+            "a = \"plain\"\n",
+            "b = \"tab\\there \\\"quoted\\\" back\\\\slash\"\n",
+            "c = 'lit\\eral'\n",
+            "d = \"# not a comment\"\n",
+            "e = \"\"\n",
+        ))
+        .unwrap();
+        let top = &config.top;
+        assert_eq!(top.get_str("a").unwrap().as_deref(), Some("plain"));
+        assert_eq!(
+            top.get_str("b").unwrap().as_deref(),
+            Some("tab\there \"quoted\" back\\slash")
+        );
+        assert_eq!(top.get_str("c").unwrap().as_deref(), Some("lit\\eral"));
+        assert_eq!(
+            top.get_str("d").unwrap().as_deref(),
+            Some("# not a comment")
+        );
+        assert_eq!(top.get_str("e").unwrap().as_deref(), Some(""));
+    }
+
+    #[test]
+    fn comments() {
+        let config = parse(concat!(
+            "# a comment\n",
+            "\n",
+            "   # an indented comment\n",
+            "a = 1 # trailing\n",
+            "b = \"x\"# right after\n",
+            "[tun] # after a header\n",
+            "c = true\t# after a tab\n",
+        ))
+        .unwrap();
+        assert_eq!(config.top.get_int::<i64>("a").unwrap(), Some(1));
+        assert_eq!(config.top.get_str("b").unwrap().as_deref(), Some("x"));
+        assert_eq!(table(&config, "tun").get_bool("c").unwrap(), Some(true));
+    }
+
+    #[test]
+    fn tables() {
+        let config =
+            parse("port = \"p\"\n[tun]\nchannel = 1\n[ pong ]\nchannel = 2\n[bench]\n").unwrap();
+        assert_eq!(config.top.name, "");
+        assert_eq!(config.top.get_str("port").unwrap().as_deref(), Some("p"));
+        let names: Vec<&str> = config.tables.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["tun", "pong", "bench"]);
+        assert_eq!(
+            table(&config, "tun").get_int::<u8>("channel").unwrap(),
+            Some(1)
+        );
+        assert_eq!(
+            table(&config, "pong").get_int::<u8>("channel").unwrap(),
+            Some(2)
+        );
+        assert_eq!(table(&config, "pong").get_str("port").unwrap(), None);
+    }
+
+    #[test]
+    fn seconds() {
+        let config = parse("a = 2\nb = 0.25\nc = -1\nd = \"5\"\ne = nan\n").unwrap();
+        let top = &config.top;
+        assert_eq!(top.get_secs("a").unwrap(), Some(Duration::from_secs(2)));
+        assert_eq!(top.get_secs("b").unwrap(), Some(Duration::from_millis(250)));
+        assert_eq!(top.get_secs("missing").unwrap(), None);
+        assert_eq!(
+            top.get_secs("c").unwrap_err().to_string(),
+            "c: -1 is out of range"
+        );
+        assert_eq!(
+            top.get_secs("d").unwrap_err().to_string(),
+            "d: expected a number of seconds"
+        );
+        assert_eq!(
+            top.get_secs("e").unwrap_err().to_string(),
+            "e: NaN is out of range"
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        // The TOML parser's messages give the line and column.
+        assert!(error("a = 1\nb\n").contains("line 2"));
+        assert!(error("[tun\n").contains("line 1"));
+        assert!(error("[tun]\n[tun]\n").contains("duplicate key `tun`"));
+        assert!(error("a = 1\na = 2\n").contains("duplicate key `a`"));
+        assert!(error("a =\n").contains("line 1"));
+        assert!(error("a = \"open\n").contains("line 1"));
+        assert!(error("a = yes\n").contains("line 1"));
+    }
+
+    #[test]
+    fn nested_tables() {
+        assert_eq!(
+            error("[tun]\nchannel = 1\n[tun.x]\n"),
+            "tun.x: tables may not be nested"
+        );
+        assert_eq!(
+            error("[tun]\nx = { a = 1 }\n"),
+            "tun.x: tables may not be nested"
+        );
+    }
+
+    #[test]
+    fn type_errors() {
+        let config = parse("[tun]\ns = \"x\"\nn = 300\nb = true\nf = 1.5\na = [1, 2]\n").unwrap();
+        let tun = table(&config, "tun");
+        let err = |r: io::Result<()>| r.unwrap_err().to_string();
+        assert_eq!(
+            err(tun.get_int::<i64>("s").map(|_| ())),
+            "tun.s: expected an integer"
+        );
+        assert_eq!(
+            err(tun.get_str("n").map(|_| ())),
+            "tun.n: expected a string"
+        );
+        assert_eq!(
+            err(tun.get_int::<u8>("n").map(|_| ())),
+            "tun.n: 300 is out of range"
+        );
+        assert_eq!(
+            err(tun.get_bool("s").map(|_| ())),
+            "tun.s: expected true or false"
+        );
+        assert_eq!(
+            err(tun.get_int::<i64>("f").map(|_| ())),
+            "tun.f: expected an integer"
+        );
+        assert_eq!(
+            err(tun.get_str("a").map(|_| ())),
+            "tun.a: expected a string"
+        );
+    }
+
+    #[test]
+    fn addresses() {
+        let config = parse("a = \"0013A20040A1B2C3\"\nb = \"nope\"\nc = 5\n").unwrap();
+        let top = &config.top;
+        assert_eq!(top.get_addr("a").unwrap(), Some(0x0013a20040a1b2c3));
+        assert_eq!(top.get_addr("missing").unwrap(), None);
+        assert_eq!(
+            top.get_addr("b").unwrap_err().to_string(),
+            "b: invalid address \"nope\""
+        );
+        assert!(top.get_addr("c").is_err());
+    }
+
+    #[test]
+    fn unknown_keys() {
+        let config = parse("[pong]\nchannel = 2\nchanel = 3\n").unwrap();
+        let pong = table(&config, "pong");
+        assert!(pong.check_keys(&["channel", "chanel"]).is_ok());
+        assert_eq!(
+            pong.check_keys(&["channel"]).unwrap_err().to_string(),
+            "pong.chanel: unknown setting"
+        );
+    }
+}
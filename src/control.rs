@@ -0,0 +1,332 @@
+/*! Inspecting and adjusting a running xbnet over a Unix socket */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The protocol is line-oriented text, so it can be used by hand with a tool such as
+socat.  Each command line gets zero or more lines of output followed by a line
+reading OK, or a single line beginning with ERR. */
+
+use crate::discovery;
+use crate::neighbors;
+use crate::ser::bind_socket;
+use crate::stats;
+use crate::tap::{showmac, XBTap};
+use crate::tun::XBTun;
+use crate::xb::*;
+use log::*;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const HELP: &str = "\
+list [ip|mac]                  show the destination caches
+add ip ADDRESS XBEE [SECONDS]  map an IP address to a 64-bit XBee address, in hex
+add mac MAC XBEE               map an Ethernet MAC address to a 64-bit XBee address
+del ip ADDRESS | del mac MAC   remove a mapping
+flush [ip|mac]                 remove all mappings
+queue                          show the number of frames waiting to be sent
+stats                          show traffic statistics, in total and per peer
+neighbors                      show the quality of the link to each neighbor
+discover [nd|fn]               ask the radio for node discovery; see neighbors for the answers
+loglevel [LEVEL]               show or set the log level: off, error, warn, info, debug, trace
+help                           show this message";
+
+/// The parts of a running xbnet that can be controlled
+pub struct Control {
+    /// The sender to the writer thread, to report its queue
+    sender: Mutex<Option<crossbeam_channel::Sender<XBTX>>>,
+    tun: Mutex<Option<XBTun>>,
+    tap: Mutex<Option<XBTap>>,
+}
+
+impl Control {
+    pub fn new() -> Self {
+        Control {
+            sender: Mutex::new(None),
+            tun: Mutex::new(None),
+            tap: Mutex::new(None),
+        }
+    }
+
+    /// Make the transmit queue available, once the radio is initialized.
+    pub fn attach_sender(&self, sender: &crossbeam_channel::Sender<XBTX>) {
+        *self.sender.lock().unwrap() = Some(sender.clone());
+    }
+
+    /** The number of frames waiting to be sent to the radio, and the size of the queue
+    if it is bounded; None until the radio is initialized. */
+    pub fn queue(&self) -> Option<(usize, Option<usize>)> {
+        self.sender
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|s| (s.len(), s.capacity()))
+    }
+
+    /// Start node discovery with command, ND or FN.
+    fn discover(&self, command: &str) -> Result<Vec<String>, String> {
+        let sender = self.sender.lock().unwrap().clone();
+        let sender = sender.ok_or_else(|| String::from("the radio is not initialized yet"))?;
+        discovery::request(&sender, command).map_err(|e| e.to_string())?;
+        Ok(vec![])
+    }
+
+    /// The number of entries in the IP address cache of tun, if it is running
+    pub fn ip_cache_len(&self) -> Option<usize> {
+        self.tun().ok().map(|tun| tun.dests.lock().unwrap().len())
+    }
+
+    /// The number of entries in the MAC address cache of tap, if it is running
+    pub fn mac_cache_len(&self) -> Option<usize> {
+        self.tap().ok().map(|tap| tap.dests.lock().unwrap().len())
+    }
+
+    /// Make the IP address cache of tun available.
+    pub fn attach_tun(&self, tun: &XBTun) {
+        *self.tun.lock().unwrap() = Some(tun.clone());
+    }
+
+    /// Make the MAC address cache of tap available.
+    pub fn attach_tap(&self, tap: &XBTap) {
+        *self.tap.lock().unwrap() = Some(tap.clone());
+    }
+
+    pub fn tun(&self) -> Result<XBTun, String> {
+        self.tun
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| String::from("no tun interface is running"))
+    }
+
+    pub fn tap(&self) -> Result<XBTap, String> {
+        self.tap
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or_else(|| String::from("no tap interface is running"))
+    }
+
+    /// Run one command, returning its output lines.
+    fn command(&self, line: &str) -> Result<Vec<String>, String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["help"] => Ok(HELP.lines().map(String::from).collect()),
+            ["list"] => {
+                let mut out = Vec::new();
+                if let Ok(tun) = self.tun() {
+                    out.append(&mut listip(&tun));
+                }
+                if let Ok(tap) = self.tap() {
+                    out.append(&mut listmac(&tap));
+                }
+                Ok(out)
+            }
+            ["list", "ip"] => Ok(listip(&self.tun()?)),
+            ["list", "mac"] => Ok(listmac(&self.tap()?)),
+            ["add", "ip", addr, xbee, rest @ ..] if rest.len() <= 1 => {
+                let tun = self.tun()?;
+                let addr = parseip(addr)?;
+                let xbee = parsexbee(xbee)?;
+                let lifetime = match rest.first() {
+                    Some(secs) => Duration::from_secs(
+                        secs.parse()
+                            .map_err(|_| format!("invalid number of seconds {:?}", secs))?,
+                    ),
+                    None => tun.max_ip_cache,
+                };
+                let expires = Instant::now()
+                    .checked_add(lifetime)
+                    .ok_or_else(|| String::from("lifetime is too long"))?;
+                tun.dests.lock().unwrap().insert(addr, (xbee, expires));
+                Ok(vec![])
+            }
+            ["add", "mac", mac, xbee] => {
+                let tap = self.tap()?;
+                let mac = parsemac(mac)?;
+                let xbee = parsexbee(xbee)?;
+                tap.dests
+                    .lock()
+                    .unwrap()
+                    .insert(mac, (xbee, Instant::now()));
+                Ok(vec![])
+            }
+            ["del", "ip", addr] => {
+                let addr = parseip(addr)?;
+                match self.tun()?.dests.lock().unwrap().remove(&addr) {
+                    Some(_) => Ok(vec![]),
+                    None => Err(format!("{} is not in the cache", addr)),
+                }
+            }
+            ["del", "mac", mac] => {
+                let mac = parsemac(mac)?;
+                match self.tap()?.dests.lock().unwrap().remove(&mac) {
+                    Some(_) => Ok(vec![]),
+                    None => Err(format!("{} is not in the cache", showmac(&mac))),
+                }
+            }
+            ["flush"] => {
+                if let Ok(tun) = self.tun() {
+                    fluship(&tun);
+                }
+                if let Ok(tap) = self.tap() {
+                    flushmac(&tap);
+                }
+                Ok(vec![])
+            }
+            ["flush", "ip"] => {
+                fluship(&self.tun()?);
+                Ok(vec![])
+            }
+            ["flush", "mac"] => {
+                flushmac(&self.tap()?);
+                Ok(vec![])
+            }
+            ["queue"] => match self.queue() {
+                Some((len, Some(cap))) => Ok(vec![format!("{} of {}", len, cap)]),
+                Some((len, None)) => Ok(vec![format!("{}", len)]),
+                None => Err(String::from("the radio is not initialized yet")),
+            },
+            ["stats"] => Ok(stats::report()),
+            ["neighbors"] => Ok(neighbors::report()),
+            ["discover"] => self.discover("ND"),
+            ["discover", command] if ["nd", "fn"].contains(command) => {
+                self.discover(&command.to_uppercase())
+            }
+            ["loglevel"] => Ok(vec![log::max_level().to_string().to_lowercase()]),
+            ["loglevel", level] => {
+                let level = LevelFilter::from_str(level)
+                    .map_err(|_| format!("invalid log level {:?}", level))?;
+                log::set_max_level(level);
+                Ok(vec![])
+            }
+            [] => Ok(vec![]),
+            _ => Err(String::from("unknown command; try help")),
+        }
+    }
+}
+
+fn listip(tun: &XBTun) -> Vec<String> {
+    let now = Instant::now();
+    let mut entries: Vec<String> = tun
+        .dests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(addr, (xbee, expires))| {
+            let remaining = expires.saturating_duration_since(now).as_secs();
+            format!("ip {} {:016x} expires {}s", addr, xbee, remaining)
+        })
+        .collect();
+    entries.sort();
+    entries
+}
+
+fn listmac(tap: &XBTap) -> Vec<String> {
+    let mut entries: Vec<String> = tap
+        .dests
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(mac, (xbee, _))| format!("mac {} {:016x} expires never", showmac(mac), xbee))
+        .collect();
+    entries.sort();
+    entries
+}
+
+fn fluship(tun: &XBTun) {
+    tun.dests.lock().unwrap().clear();
+}
+
+fn flushmac(tap: &XBTap) {
+    let mut dests = tap.dests.lock().unwrap();
+    dests.clear();
+    // Keep the mapping every tap starts with.
+    dests.insert(
+        crate::tap::ETHER_BROADCAST,
+        (crate::tap::XB_BROADCAST, Instant::now()),
+    );
+}
+
+fn parseip(s: &str) -> Result<IpAddr, String> {
+    s.parse().map_err(|_| format!("invalid IP address {:?}", s))
+}
+
+fn parsexbee(s: &str) -> Result<u64, String> {
+    u64::from_str_radix(s, 16).map_err(|_| format!("invalid XBee address {:?}", s))
+}
+
+fn parsemac(s: &str) -> Result<[u8; 6], String> {
+    let mut mac = [0u8; 6];
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 6 {
+        return Err(format!("invalid MAC address {:?}", s));
+    }
+    for (byte, part) in mac.iter_mut().zip(parts) {
+        *byte = u8::from_str_radix(part, 16).map_err(|_| format!("invalid MAC address {:?}", s))?;
+    }
+    Ok(mac)
+}
+
+/// Answer commands from one connection until it closes.
+fn session(control: &Control, stream: UnixStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        debug!("CONTROL: {}", line);
+        match control.command(&line) {
+            Ok(out) => {
+                for outline in out {
+                    writeln!(writer, "{}", outline)?;
+                }
+                writeln!(writer, "OK")?;
+            }
+            Err(e) => writeln!(writer, "ERR {}", e)?,
+        }
+    }
+    Ok(())
+}
+
+/** Listen for commands on a Unix socket at socketpath, in a new thread.  Each
+connection is served by its own thread. */
+pub fn serve(socketpath: &Path, control: Arc<Control>) -> io::Result<()> {
+    let listener = bind_socket(socketpath)?;
+    info!("CONTROL: listening on {:?}", socketpath);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let control = control.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = session(&control, stream) {
+                            debug!("CONTROL: session ended: {}", e);
+                        }
+                    });
+                }
+                Err(e) => warn!("CONTROL: failed to accept connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,604 @@
+/*! Running several services on one radio, as given in a configuration file */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The top-level table of the configuration file holds the global options, and each
+other table enables the service of the same name, except that [control] gives the
+path of the control socket:
+
+  port = "/dev/ttyUSB0"
+  serial_speed = 115200
+
+  [tun]
+  channel = 1
+  max_ip_cache = 300
+
+  [pong]
+  channel = 2
+
+[beacon] sends presence beacons for tun and tap, and so is started after them.  Only
+one service may write to stdout.  Subcommands that run once, or interactively, are
+not services. */
+
+use crate::beacon;
+use crate::bench;
+use crate::broker;
+use crate::config::{self, Table};
+use crate::control::*;
+use crate::discovery;
+use crate::events;
+use crate::metrics;
+use crate::mux::*;
+use crate::pcap;
+use crate::ping;
+use crate::pipe;
+use crate::rangetest;
+use crate::ser;
+use crate::spool;
+use crate::stats;
+use crate::tap;
+use crate::tun;
+use crate::xb::*;
+use crate::xbpacket::*;
+use crate::xbrx::*;
+use log::*;
+use simplelog::{LevelFilter, WriteLogger};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+const GLOBAL_KEYS: &[&str] = &[
+    "port",
+    "broker",
+    "serial_speed",
+    "initfile",
+    "debug",
+    "disable_xbee_acks",
+    "request_xbee_tx_reports",
+    "discover",
+    "discover_interval",
+    "metrics",
+    "events",
+    "pcap",
+    "stats",
+];
+
+/// The services that write to stdout, of which only one may run
+const STDOUT_SERVICES: &[&str] = &["pipe", "ping", "bench", "rangetest"];
+
+/// Subcommands that can't run as services, since they run once or interactively
+const NOT_SERVICES: &[&str] = &[
+    "send",
+    "recv",
+    "sendfile",
+    "recvfile",
+    "distribute",
+    "chat",
+    "enqueue",
+    "discover",
+    "at",
+    "monitor",
+    "decode",
+    "broker",
+    "daemon",
+];
+
+/** Settings from the command line, which override the ones in the file.  Flags that
+are set turn the setting on whatever the file says. */
+pub struct Overrides {
+    pub port: Option<PathBuf>,
+    pub broker: Option<PathBuf>,
+    pub initfile: Option<PathBuf>,
+    pub serial_speed: Option<u32>,
+    pub disable_xbee_acks: bool,
+    pub request_xbee_tx_reports: bool,
+    pub discover: bool,
+    pub discover_interval: Option<u64>,
+    pub control: Option<PathBuf>,
+    pub metrics: Option<String>,
+    pub events: Option<String>,
+    pub pcap: Option<PathBuf>,
+    pub record: Option<PathBuf>,
+    pub replay: Option<PathBuf>,
+    pub stats: bool,
+}
+
+/** Run the services in the configuration file at configpath until one of them fails.
+logging tells whether the logger has already been set up. */
+pub fn run(configpath: &Path, overrides: Overrides, logging: bool) -> io::Result<()> {
+    let cfg = config::read(configpath)?;
+    let globals = check(&cfg, overrides)
+        .map_err(|e| mkerror(&format!("{}: {}", configpath.display(), e)))?;
+    // Before any thread is started, as print_on_exit requires
+    if globals.stats {
+        stats::print_on_exit()?;
+    }
+
+    // The control socket can turn logging on later.
+    if !logging && (globals.debug || globals.control.is_some()) {
+        WriteLogger::init(LevelFilter::Trace, simplelog::Config::default(), io::stderr())
+            .map_err(|e| mkerror(&e.to_string()))?;
+        if !globals.debug {
+            log::set_max_level(LevelFilter::Off);
+        }
+    }
+    if let Some(target) = &globals.events {
+        events::open(target)?;
+    }
+    if let Some(path) = &globals.pcap {
+        pcap::open(path)?;
+    }
+    let showsrssi = cfg.tables.iter().any(|t| t.name == "ping" || t.name == "rangetest");
+    if showsrssi || globals.stats || globals.control.is_some() || globals.metrics.is_some() {
+        stats::want_rssi();
+    }
+    let control = Arc::new(Control::new());
+    if let Some(path) = &globals.control {
+        serve(path, control.clone())?;
+    }
+    if let Some(address) = &globals.metrics {
+        metrics::serve(address, control.clone())?;
+    }
+
+    let (ser_reader, ser_writer) = match (&globals.replay, &globals.broker, globals.port) {
+        (Some(path), _, _) => ser::replay(path, true)?,
+        (None, Some(socket), _) => ser::connect(socket.clone())
+            .map_err(|e| mkerror(&format!("{}: {}", socket.display(), e)))?,
+        (None, None, Some(port)) => ser::new(port, globals.serial_speed)?,
+        (None, None, None) => unreachable!(),
+    };
+    let (ser_reader, ser_writer) = match &globals.record {
+        Some(path) => ser::record(ser_reader, ser_writer, path)?,
+        None => (ser_reader, ser_writer),
+    };
+    let (xb, xbeesender, _writerthread) = match globals.broker {
+        Some(_) => {
+            let client = XB::new_client(
+                ser_reader,
+                ser_writer,
+                globals.disable_xbee_acks,
+                globals.request_xbee_tx_reports,
+            )?;
+            let channels = cfg
+                .tables
+                .iter()
+                .filter(|t| t.name != "control")
+                .map(channel_of)
+                .collect::<io::Result<Vec<u8>>>()?;
+            broker::subscribe(&client.1, &channels)?;
+            client
+        }
+        None => XB::new(
+            ser_reader,
+            ser_writer,
+            globals.initfile,
+            globals.disable_xbee_acks,
+            globals.request_xbee_tx_reports,
+        ),
+    };
+    control.attach_sender(&xbeesender);
+    if globals.discover || globals.discover_interval > 0 {
+        let interval = Some(Duration::from_secs(globals.discover_interval)).filter(|i| !i.is_zero());
+        discovery::spawn(xbeesender.clone(), interval);
+    }
+
+    // Each service reports here when it stops, which should be never.
+    let (donetx, donerx) = crossbeam_channel::unbounded();
+    let mut dispatcher = Dispatcher::new(xbeesender);
+    let piping = cfg.tables.iter().any(|t| t.name == "pipe");
+    let mut tables: Vec<&Table> = cfg.tables.iter().filter(|t| t.name != "control").collect();
+    tables.sort_by_key(|t| t.name == "beacon");
+    for table in tables {
+        let channel = channel_of(table)?;
+        let service = dispatcher
+            .open(channel)
+            .map_err(|e| mkerror(&format!("[{}]: {}", table.name, e)))?;
+        let threads = match table.name.as_str() {
+            "tun" => start_tun(table, xb.mymac, service, &control)?,
+            "tap" => start_tap(table, xb.mymac, service, &control)?,
+            "pong" => start_pong(service, piping)?,
+            "beacon" => start_beacon(table, xb.mymac, service, &control)?,
+            "pipe" => start_pipe(table, xb.maxpacketsize, channel, service)?,
+            "ping" => start_ping(table, xb.maxpacketsize, channel, service)?,
+            "forward" => start_forward(table, xb.maxpacketsize, channel, service)?,
+            "bench" => start_bench(table, service)?,
+            "rangetest" => start_rangetest(service)?,
+            _ => unreachable!(),
+        };
+        info!("DAEMON: started {} on channel {}", table.name, channel);
+        for thread in threads {
+            let donetx = donetx.clone();
+            let name = table.name.clone();
+            thread::spawn(move || {
+                let res = thread();
+                let _ = donetx.send((name, res));
+            });
+        }
+    }
+    dispatcher.spawn(XBReframer::new(), xb.ser_reader);
+
+    loop {
+        let (name, res) = donerx.recv().map_err(|e| mkerror(&e.to_string()))?;
+        match res {
+            // After its count or deadline, or SIGINT, as it would on its own
+            Ok(()) if name == "ping" => return Ok(()),
+            // Frames received are still written to stdout.
+            Ok(()) if name == "pipe" => info!("DAEMON: pipe reached the end of stdin"),
+            Ok(()) => return Err(mkerror(&format!("{} stopped unexpectedly", name))),
+            Err(e) => return Err(mkerror(&format!("{}: {}", name, e))),
+        }
+    }
+}
+
+/// The global options from the top-level table
+struct Globals {
+    /// Not needed when replaying, or with broker
+    port: Option<PathBuf>,
+    broker: Option<PathBuf>,
+    control: Option<PathBuf>,
+    metrics: Option<String>,
+    events: Option<String>,
+    pcap: Option<PathBuf>,
+    record: Option<PathBuf>,
+    replay: Option<PathBuf>,
+    serial_speed: u32,
+    initfile: Option<PathBuf>,
+    debug: bool,
+    disable_xbee_acks: bool,
+    request_xbee_tx_reports: bool,
+    discover: bool,
+    discover_interval: u64,
+    stats: bool,
+}
+
+/** Read the global options, and catch mistakes in the services before touching the
+radio. */
+fn check(cfg: &config::Config, overrides: Overrides) -> io::Result<Globals> {
+    let top = &cfg.top;
+    top.check_keys(GLOBAL_KEYS)?;
+    let port = overrides.port.or(top.get_str("port")?.map(PathBuf::from));
+    let broker = overrides
+        .broker
+        .or(top.get_str("broker")?.map(PathBuf::from));
+    if port.is_none() && broker.is_none() && overrides.replay.is_none() {
+        return Err(mkerror("no serial port given"));
+    }
+    let controltable = cfg.tables.iter().find(|t| t.name == "control");
+    let control = match (overrides.control, controltable) {
+        (Some(control), _) => Some(control),
+        (None, Some(table)) => table.get_str("socket")?.map(PathBuf::from),
+        (None, None) => None,
+    };
+    let globals = Globals {
+        port,
+        broker,
+        control,
+        metrics: overrides.metrics.or(top.get_str("metrics")?),
+        events: overrides.events.or(top.get_str("events")?),
+        pcap: overrides.pcap.or(top.get_str("pcap")?.map(PathBuf::from)),
+        record: overrides.record,
+        replay: overrides.replay,
+        serial_speed: match overrides.serial_speed {
+            Some(speed) => speed,
+            None => top.get_int("serial_speed")?.unwrap_or(9600),
+        },
+        initfile: overrides
+            .initfile
+            .or(top.get_str("initfile")?.map(PathBuf::from)),
+        debug: top.get_bool("debug")?.unwrap_or(false),
+        disable_xbee_acks: overrides.disable_xbee_acks
+            || top.get_bool("disable_xbee_acks")?.unwrap_or(false),
+        // forward detects failed deliveries with them.
+        request_xbee_tx_reports: overrides.request_xbee_tx_reports
+            || top.get_bool("request_xbee_tx_reports")?.unwrap_or(false)
+            || cfg.tables.iter().any(|t| t.name == "forward"),
+        discover: overrides.discover || top.get_bool("discover")?.unwrap_or(false),
+        discover_interval: match overrides.discover_interval {
+            Some(interval) => interval,
+            None => top.get_int("discover_interval")?.unwrap_or(0),
+        },
+        stats: overrides.stats || top.get_bool("stats")?.unwrap_or(false),
+    };
+
+    if cfg.tables.iter().all(|t| t.name == "control") {
+        return Err(mkerror("no services are enabled"));
+    }
+    let mut channels = HashMap::new();
+    for table in cfg.tables.iter() {
+        if table.name == "control" {
+            table.check_keys(&["socket"])?;
+            continue;
+        }
+        match service_keys(&table.name) {
+            Some(keys) => table.check_keys(keys)?,
+            None if NOT_SERVICES.contains(&table.name.as_str()) => {
+                return Err(mkerror(&format!(
+                    "[{}]: runs once or interactively, so it can't be a service",
+                    table.name
+                )))
+            }
+            None => return Err(mkerror(&format!("[{}]: unknown service", table.name))),
+        }
+        match table.name.as_str() {
+            "pipe" | "ping" => {
+                required(table, "dest", table.get_addr("dest")?)?;
+            }
+            "forward" => {
+                required(table, "spool", table.get_str("spool")?)?;
+            }
+            _ => (),
+        }
+        let channel = channel_of(table)?;
+        if let Some(other) = channels.insert(channel, &table.name) {
+            return Err(mkerror(&format!(
+                "[{}] and [{}] both use channel {}",
+                other, table.name, channel
+            )));
+        }
+    }
+    let mut stdout = cfg
+        .tables
+        .iter()
+        .filter(|t| STDOUT_SERVICES.contains(&t.name.as_str()));
+    if let (Some(first), Some(second)) = (stdout.next(), stdout.next()) {
+        return Err(mkerror(&format!(
+            "[{}] and [{}] both write to stdout",
+            first.name, second.name
+        )));
+    }
+    if globals.events.as_deref() == Some("-") {
+        // pong prints the pings it answers, too.
+        if let Some(table) = cfg
+            .tables
+            .iter()
+            .find(|t| STDOUT_SERVICES.contains(&t.name.as_str()) || t.name == "pong")
+        {
+            return Err(mkerror(&format!(
+                "events: [{}] writes to stdout; give a file or stderr instead",
+                table.name
+            )));
+        }
+    }
+    if let Some(table) = cfg.tables.iter().find(|t| t.name == "beacon") {
+        if !cfg.tables.iter().any(|t| t.name == "tun" || t.name == "tap") {
+            return Err(mkerror("[beacon] needs [tun] or [tap]"));
+        }
+        if table.get_int::<u64>("interval")? == Some(0) {
+            return Err(mkerror("[beacon]: interval must be at least 1"));
+        }
+    }
+    Ok(globals)
+}
+
+/// Return value, the setting key of table, or an error if it was missing.
+fn required<T>(table: &Table, key: &str, value: Option<T>) -> io::Result<T> {
+    value.ok_or_else(|| mkerror(&format!("[{}]: {} is required", table.name, key)))
+}
+
+/// The channel a service uses
+fn channel_of(table: &Table) -> io::Result<u8> {
+    let default = if table.name == "beacon" {
+        beacon::DEFAULT_CHANNEL
+    } else {
+        DEFAULT_CHANNEL
+    };
+    Ok(table.get_int("channel")?.unwrap_or(default))
+}
+
+/// The settings allowed for each service
+fn service_keys(name: &str) -> Option<&'static [&'static str]> {
+    match name {
+        "tun" => Some(&[
+            "channel",
+            "broadcast_everything",
+            "max_ip_cache",
+            "iface_name",
+            "disable_ipv4",
+            "disable_ipv6",
+        ]),
+        "tap" => Some(&[
+            "channel",
+            "broadcast_unknown",
+            "broadcast_everything",
+            "iface_name",
+        ]),
+        "pong" => Some(&["channel"]),
+        "beacon" => Some(&["channel", "interval", "name"]),
+        "pipe" => Some(&["channel", "dest", "coalesce_delay_ms", "coalesce_min_fill"]),
+        "ping" => Some(&[
+            "channel", "dest", "count", "interval", "size", "timeout", "deadline",
+        ]),
+        "forward" => Some(&["channel", "spool", "retry_interval"]),
+        // Only the server sides, which run until stopped
+        "bench" => Some(&["channel", "json"]),
+        "rangetest" => Some(&["channel"]),
+        _ => None,
+    }
+}
+
+/// The body of a thread run by a service
+type ServiceThread = Box<dyn FnOnce() -> io::Result<()> + Send>;
+
+fn start_tun(
+    table: &Table,
+    mymac: u64,
+    service: Service,
+    control: &Control,
+) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let tun_reader = tun::XBTun::new_tun(
+        mymac,
+        table.get_bool("broadcast_everything")?.unwrap_or(false),
+        table
+            .get_str("iface_name")?
+            .unwrap_or_else(|| String::from("xbnet%d")),
+        Duration::from_secs(table.get_int("max_ip_cache")?.unwrap_or(300)),
+        table.get_bool("disable_ipv4")?.unwrap_or(false),
+        table.get_bool("disable_ipv6")?.unwrap_or(false),
+    )?;
+    control.attach_tun(&tun_reader);
+    let tun_writer = tun_reader.clone();
+    Ok(vec![
+        Box::new(move || tun_writer.frames_from_xb_processor(framerx)),
+        Box::new(move || tun_reader.frames_from_tun_processor(sender)),
+    ])
+}
+
+fn start_tap(
+    table: &Table,
+    mymac: u64,
+    service: Service,
+    control: &Control,
+) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let tap_reader = tap::XBTap::new_tap(
+        mymac,
+        table.get_bool("broadcast_unknown")?.unwrap_or(false),
+        table.get_bool("broadcast_everything")?.unwrap_or(false),
+        table
+            .get_str("iface_name")?
+            .unwrap_or_else(|| String::from("xbnet%d")),
+    )?;
+    control.attach_tap(&tap_reader);
+    let tap_writer = tap_reader.clone();
+    Ok(vec![
+        Box::new(move || tap_writer.frames_from_xb_processor(framerx)),
+        Box::new(move || tap_reader.frames_from_tap_processor(sender)),
+    ])
+}
+
+/// Answer pings, printing them unless pipe has stdout.
+fn start_pong(service: Service, piping: bool) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    if piping {
+        Ok(vec![Box::new(move || ping::pong_quietly(framerx, sender))])
+    } else {
+        Ok(vec![Box::new(move || ping::pong(framerx, sender))])
+    }
+}
+
+fn start_pipe(
+    table: &Table,
+    maxpacketsize: usize,
+    channel: u8,
+    service: Service,
+) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let dest = required(table, "dest", table.get_addr("dest")?)?;
+    let maxframesize = maxpacketsize - header_len(channel);
+    let coalesce_delay = Duration::from_millis(table.get_int("coalesce_delay_ms")?.unwrap_or(0));
+    let coalesce_min_fill = table.get_int("coalesce_min_fill")?.unwrap_or(100);
+    Ok(vec![
+        Box::new(move || pipe::stdout_processor(framerx)),
+        Box::new(move || {
+            pipe::stdin_processor(
+                dest,
+                maxframesize,
+                coalesce_delay,
+                coalesce_min_fill,
+                sender,
+            )
+        }),
+    ])
+}
+
+fn start_ping(
+    table: &Table,
+    maxpacketsize: usize,
+    channel: u8,
+    service: Service,
+) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let dest = required(table, "dest", table.get_addr("dest")?)?;
+    let size = table.get_int("size")?.unwrap_or(0);
+    if size > max_frame_len(maxpacketsize, channel) {
+        return Err(mkerror(&format!(
+            "[ping]: size may be at most {}",
+            max_frame_len(maxpacketsize, channel)
+        )));
+    }
+    let params = ping::PingParams {
+        count: table.get_int("count")?,
+        interval: table.get_secs("interval")?.unwrap_or(Duration::from_secs(5)),
+        size,
+        timeout: table.get_secs("timeout")?.unwrap_or(Duration::from_secs(10)),
+        deadline: table.get_int("deadline")?.map(Duration::from_secs),
+    };
+    Ok(vec![Box::new(move || {
+        ping::ping(dest, sender, framerx, params)
+    })])
+}
+
+fn start_forward(
+    table: &Table,
+    maxpacketsize: usize,
+    channel: u8,
+    service: Service,
+) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let spooldir = PathBuf::from(required(table, "spool", table.get_str("spool")?)?);
+    let retry_interval = Some(Duration::from_secs(
+        table.get_int("retry_interval")?.unwrap_or(0),
+    ))
+    .filter(|i| !i.is_zero());
+    Ok(vec![Box::new(move || {
+        spool::forward(
+            &spooldir,
+            maxpacketsize,
+            channel,
+            retry_interval,
+            sender,
+            framerx,
+        )
+    })])
+}
+
+fn start_bench(table: &Table, service: Service) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let json = table.get_bool("json")?.unwrap_or(false);
+    Ok(vec![Box::new(move || bench::server(sender, framerx, json))])
+}
+
+fn start_rangetest(service: Service) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    Ok(vec![Box::new(move || rangetest::server(sender, framerx))])
+}
+
+fn start_beacon(
+    table: &Table,
+    mymac: u64,
+    service: Service,
+    control: &Control,
+) -> io::Result<Vec<ServiceThread>> {
+    let (sender, framerx) = service;
+    let beacon_sender = beacon::Beacon::new(
+        mymac,
+        table.get_str("name")?.unwrap_or_else(beacon::hostname),
+        Duration::from_secs(table.get_int("interval")?.unwrap_or(60)),
+        control.tun().ok(),
+        control.tap().ok(),
+    );
+    let beacon_receiver = beacon_sender.clone();
+    Ok(vec![
+        Box::new(move || beacon_sender.beacons_to_xb(sender)),
+        Box::new(move || beacon_receiver.beacons_from_xb(framerx)),
+    ])
+}
@@ -0,0 +1,208 @@
+/*! Decoding API frames from hex dumps and captures, without a radio */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Hex input is taken a line at a time, so that lines can be pasted from the debug
+log.  Lines beginning with # are ignored.  A line that is nothing but hex, ignoring spaces and colons, is used whole;
+otherwise its longest word of hex is, which in a log line is the data.  Hex that starts
+with 0x7E is a run of complete API frames, as written to the serial port, and a frame
+cut off at the end of a line of nothing but hex continues on the next such line, as
+in the output of xxd -p.
+Anything else is the inside of a single frame, from the frame type on, as logged on
+receipt.  Binary input is what was read from or written to the serial port. */
+
+use crate::monitor::Decoder;
+use crate::xbpacket::xbchecksum;
+use crate::xbrx::MAX_API_FRAME_LEN;
+use bytes::Bytes;
+use std::io;
+use std::io::{BufRead, BufReader, Read};
+
+/// The hex in a line of input, and whether it was the whole line; or None if there is none.
+fn linehex(line: &str) -> Option<(Vec<u8>, bool)> {
+    if line.trim_start().starts_with('#') {
+        return None;
+    }
+    let whole: String = line
+        .chars()
+        .filter(|c| !c.is_whitespace() && *c != ':')
+        .collect();
+    if !whole.is_empty() {
+        if let Ok(data) = hex::decode(&whole) {
+            return Some((data, true));
+        }
+    }
+    line.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|word| !word.is_empty() && word.len() % 2 == 0)
+        .filter(|word| word.chars().all(|c| c.is_ascii_hexdigit()))
+        .max_by_key(|word| word.len())
+        .and_then(|word| hex::decode(word).ok())
+        .map(|data| (data, false))
+}
+
+/// Print the description of a frame, under a heading saying where it was found.
+fn show(place: &str, lines: Vec<String>) {
+    let mut lines = lines.into_iter();
+    if let Some(first) = lines.next() {
+        println!("{}: {}", place, first);
+    }
+    for line in lines {
+        println!("{}", line);
+    }
+}
+
+/// Describe the inside of a frame.
+fn inner(decoder: &mut Decoder, place: &str, inner: &[u8]) {
+    if inner.is_empty() {
+        show(place, vec![String::from("empty frame")]);
+    } else {
+        show(place, decoder.describe(&Bytes::copy_from_slice(inner)));
+    }
+}
+
+/** Describe the complete frames at the start of data, using place to say where the
+byte at a position in data came from.  Return the number of bytes used; the rest is
+a partial frame. */
+fn frames(decoder: &mut Decoder, data: &[u8], place: &dyn Fn(usize) -> String) -> usize {
+    let junk = |start: usize, end: usize| {
+        if end > start {
+            println!(
+                "{}: {} bytes of junk: {}",
+                place(start),
+                end - start,
+                hex::encode(&data[start..end])
+            );
+        }
+    };
+
+    // Frames are found as rxapiframe finds them.
+    let mut junkstart = 0;
+    let mut pos = 0;
+    while pos < data.len() {
+        if data[pos] != 0x7e {
+            pos += 1;
+            continue;
+        }
+        if data.len() < pos + 3 {
+            break;
+        }
+        let length = usize::from(u16::from_be_bytes([data[pos + 1], data[pos + 2]]));
+        if length == 0 || length > MAX_API_FRAME_LEN {
+            pos += 1;
+            continue;
+        }
+        if data.len() < pos + 4 + length {
+            break;
+        }
+        junk(junkstart, pos);
+        let end = pos + 4 + length;
+        let content = &data[pos + 3..end - 1];
+        let checksum = data[end - 1];
+        if xbchecksum(content) == checksum {
+            inner(decoder, &place(pos), content);
+            pos = end;
+        } else {
+            println!(
+                "{}: checksum mismatch, {:02x} instead of {:02x}: {}",
+                place(pos),
+                checksum,
+                xbchecksum(content),
+                hex::encode(content)
+            );
+            // Perhaps the length was noise, and a frame begins inside this one.
+            pos = match data[pos + 1..end].iter().position(|b| *b == 0x7e) {
+                Some(resync) => pos + 1 + resync,
+                None => end,
+            };
+        }
+        junkstart = pos;
+    }
+    junk(junkstart, pos);
+    pos
+}
+
+/// Report the partial frame, if any, left at the end of the input.
+fn truncated(data: &[u8], place: &str) {
+    if data.is_empty() {
+        return;
+    }
+    if data.len() < 3 {
+        println!("{}: frame cut off before its length", place);
+    } else {
+        let length = usize::from(u16::from_be_bytes([data[1], data[2]]));
+        println!(
+            "{}: frame cut off after {} of {} bytes: {}",
+            place,
+            data.len(),
+            length + 4,
+            hex::encode(data)
+        );
+    }
+}
+
+/// Decode the API frames in input, in hex or, if binary, as raw bytes, printing each to stdout.
+pub fn decode(input: Box<dyn Read>, binary: bool) -> io::Result<()> {
+    let mut decoder = Decoder::new();
+    if binary {
+        let mut data = Vec::new();
+        BufReader::new(input).read_to_end(&mut data)?;
+        let used = frames(&mut decoder, &data, &|pos| format!("offset {}", pos));
+        truncated(&data[used..], &format!("offset {}", used));
+        return Ok(());
+    }
+
+    // A partial frame continued from earlier lines, with the position in it where each
+    // of those lines starts and its line number
+    let mut pending: Vec<u8> = Vec::new();
+    let mut starts: Vec<(usize, usize)> = Vec::new();
+    let lineof = |starts: &[(usize, usize)], pos: usize| {
+        let found = starts.iter().rev().find(|(start, _)| *start <= pos);
+        found.map_or(0, |(_, line)| *line)
+    };
+    for (i, line) in BufReader::new(input).lines().enumerate() {
+        let (data, whole) = match linehex(&line?) {
+            Some(found) => found,
+            None => continue,
+        };
+        if !whole && !pending.is_empty() {
+            truncated(&pending, &format!("line {}", lineof(&starts, 0)));
+            pending.clear();
+            starts.clear();
+        }
+        if pending.is_empty() && data.first() != Some(&0x7e) {
+            inner(&mut decoder, &format!("line {}", i + 1), &data);
+            continue;
+        }
+        starts.push((pending.len(), i + 1));
+        pending.extend_from_slice(&data);
+        let used = frames(&mut decoder, &pending, &|pos| {
+            format!("line {}", lineof(&starts, pos))
+        });
+        let line = lineof(&starts, used);
+        pending.drain(..used);
+        starts.clear();
+        if !whole {
+            truncated(&pending, &format!("line {}", line));
+            pending.clear();
+        } else if !pending.is_empty() {
+            starts.push((0, line));
+        }
+    }
+    truncated(&pending, &format!("line {}", lineof(&starts, 0)));
+    Ok(())
+}
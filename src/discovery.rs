@@ -0,0 +1,85 @@
+/*! Asking the radio which nodes are in range, with its own node discovery */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* ATND has the radio broadcast a discovery request, then answer with one AT command
+response for each node that replies within ATNT, and an empty one at the end.  ATFN
+does the same for only the nodes in direct range.  The answers are handled in the
+receive path, like those to ATDB, and go into the neighbors table, so this module only
+sends the requests; whatever is reading from the radio at the time takes care of the
+rest. */
+
+use crate::neighbors;
+use crate::xb::*;
+use log::*;
+use std::io;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// The frame ID of discovery requests
+const FRAME_ID: u8 = 0xDD;
+
+/// Ask the radio for node discovery with command, ND or FN.
+pub fn request(sender: &crossbeam_channel::Sender<XBTX>, command: &str) -> io::Result<()> {
+    let frame = atframe(FRAME_ID, command, &[])?;
+    sender
+        .send(XBTX::TXRaw(frame))
+        .map_err(|e| mkerror(&e.to_string()))
+}
+
+/// Run ATND now, and then every interval if given, in the background.
+pub fn spawn(sender: crossbeam_channel::Sender<XBTX>, interval: Option<Duration>) {
+    thread::spawn(move || loop {
+        if let Err(e) = request(&sender, "ND") {
+            warn!("DISCOVERY: {}", e);
+            return;
+        }
+        match interval {
+            Some(interval) => thread::sleep(interval),
+            None => return,
+        }
+    });
+}
+
+/** Run node discovery with command, wait, and print the nodes that answered.  Someone
+must be reading from the radio meanwhile. */
+pub fn discover(
+    sender: &crossbeam_channel::Sender<XBTX>,
+    command: &str,
+    wait: Duration,
+) -> io::Result<()> {
+    let start = SystemTime::now();
+    request(sender, command)?;
+    thread::sleep(wait);
+    let found: Vec<_> = neighbors::snapshot()
+        .into_iter()
+        .filter(|(_, n)| n.discovered.is_some_and(|t| t >= start))
+        .collect();
+    println!("{:<16}  {:<20}  {:<11}  {:>4}", "ADDRESS", "NAME", "TYPE", "RSSI");
+    for (addr, n) in found.iter() {
+        println!(
+            "{:016x}  {:<20}  {:<11}  {:>4}",
+            addr,
+            n.name.as_deref().unwrap_or(""),
+            n.device_type.map_or_else(String::new, neighbors::device_type),
+            n.last_rssi.map_or_else(String::new, |r| format!("-{}", r))
+        );
+    }
+    println!("{} nodes found", found.len());
+    Ok(())
+}
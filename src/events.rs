@@ -0,0 +1,160 @@
+/*! A machine-readable stream of notable events, as JSON lines */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Each event is one line holding a JSON object, with at least "time", an RFC 3339
+timestamp in UTC, and "event", its kind.  64-bit XBee addresses are given as strings
+of 16 hex digits, since many JSON parsers can't hold them as numbers.  Like stats,
+the destination is global, since events come from all over. */
+
+use crate::ser::bind_socket;
+use chrono::{SecondsFormat, Utc};
+use log::*;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+
+/// A value in an event
+pub enum Field {
+    Str(String),
+    Num(u64),
+}
+
+impl From<&str> for Field {
+    fn from(s: &str) -> Self {
+        Field::Str(String::from(s))
+    }
+}
+
+impl From<String> for Field {
+    fn from(s: String) -> Self {
+        Field::Str(s)
+    }
+}
+
+impl From<u8> for Field {
+    fn from(n: u8) -> Self {
+        Field::Num(u64::from(n))
+    }
+}
+
+/// A 64-bit XBee address, in the form used in events
+pub fn addr(addr: u64) -> Field {
+    Field::Str(format!("{:016x}", addr))
+}
+
+enum Sink {
+    Writer(Box<dyn Write + Send>),
+    /// Clients connected to the event socket
+    Clients(Vec<UnixStream>),
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+/** Send events to target: "-" for stdout, "stderr" for stderr, "unix:PATH" to listen
+on the Unix socket PATH and send to every client connected to it, or else a file to
+append to. */
+pub fn open(target: &str) -> io::Result<()> {
+    let sink = if target == "-" {
+        Sink::Writer(Box::new(io::stdout()))
+    } else if target == "stderr" {
+        Sink::Writer(Box::new(io::stderr()))
+    } else if let Some(socketpath) = target.strip_prefix("unix:") {
+        // In place before listening, so that no client is missed.
+        *SINK.lock().unwrap() = Some(Sink::Clients(Vec::new()));
+        return listen(Path::new(socketpath));
+    } else {
+        let file = fs::OpenOptions::new().create(true).append(true).open(target)?;
+        Sink::Writer(Box::new(file))
+    };
+    *SINK.lock().unwrap() = Some(sink);
+    Ok(())
+}
+
+fn listen(socketpath: &Path) -> io::Result<()> {
+    let listener = bind_socket(socketpath)?;
+    info!("EVENTS: listening on {:?}", socketpath);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    // A client that can't keep up is dropped rather than holding up xbnet.
+                    if let Err(e) = stream.set_nonblocking(true) {
+                        warn!("EVENTS: failed to set up connection: {}", e);
+                        continue;
+                    }
+                    if let Some(Sink::Clients(clients)) = SINK.lock().unwrap().as_mut() {
+                        clients.push(stream);
+                    }
+                }
+                Err(e) => warn!("EVENTS: failed to accept connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Emit an event of the given kind, with the given fields.
+pub fn emit(event: &str, fields: Vec<(&str, Field)>) {
+    let mut sink = SINK.lock().unwrap();
+    let sink = match sink.as_mut() {
+        Some(sink) => sink,
+        None => return,
+    };
+
+    let mut line = format!(
+        "{{\"time\":{},\"event\":{}",
+        quote(&Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)),
+        quote(event)
+    );
+    for (name, value) in fields {
+        let value = match value {
+            Field::Str(s) => quote(&s),
+            Field::Num(n) => n.to_string(),
+        };
+        line.push_str(&format!(",{}:{}", quote(name), value));
+    }
+    line.push_str("}\n");
+
+    match sink {
+        Sink::Writer(w) => {
+            if let Err(e) = w.write_all(line.as_bytes()).and_then(|_| w.flush()) {
+                warn!("EVENTS: failed to write event: {}", e);
+            }
+        }
+        Sink::Clients(clients) => clients.retain_mut(|c| c.write_all(line.as_bytes()).is_ok()),
+    }
+}
@@ -0,0 +1,442 @@
+/*! One-to-many file distribution over broadcast */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* This uses the message header from filexfer, with these additional types:
+
+  BOFFER   sender -> broadcast  size: u64, chunksize: u16, name: remaining bytes
+  BDATA    sender -> broadcast  index: u32, data: remaining bytes
+  POLL     sender -> broadcast  (no body)
+  NACK     receiver -> sender   any number of (first index: u32, count: u16)
+
+The sender broadcasts every chunk once, then polls.  Receivers answer a poll with
+either a NACK listing chunks they are missing, or the COMPLETE (or REJECT) from
+filexfer.  The sender then rebroadcasts only the chunks somebody is missing, and
+polls again, until every listed recipient has confirmed completion. */
+
+use crate::filexfer::*;
+use crate::tap::XB_BROADCAST;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use log::*;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const MSG_BOFFER: u8 = b'o';
+pub const MSG_BDATA: u8 = b'd';
+pub const MSG_POLL: u8 = b'P';
+pub const MSG_NACK: u8 = b'N';
+
+/// Length of MAGIC, message type, CRC, and chunk index
+const BDATA_HEADER_LEN: usize = 11;
+
+/// How long the receiver keeps answering polls after a transfer completes.
+const LINGER: Duration = Duration::from_secs(10);
+
+/** The most chunks a receiver will track.  Offers come from anyone in range, and
+each chunk costs memory before any data arrives. */
+const MAX_CHUNKS: u64 = 1 << 20;
+
+/// Timing parameters for a distribution
+#[derive(Clone, Copy, Debug)]
+pub struct DistParams {
+    /// How long to collect responses after each poll
+    pub round_timeout: Duration,
+
+    /// Give up on recipients that haven't confirmed after this long
+    pub deadline: Duration,
+}
+
+/** Broadcast the file at path, calling it name on the remote end.  If recipients
+is not empty, continue until each of them has confirmed completion; otherwise,
+continue until a poll draws no NACKs.  framerx supplies received frames, as from
+mux::Dispatcher::open.  maxframesize is the most data that fits in one XBee packet. */
+pub fn distribute(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    path: &Path,
+    name: &str,
+    maxframesize: usize,
+    recipients: &[u64],
+    params: DistParams,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let size = data.len() as u64;
+    let crc = crc32(&data);
+    let chunksize = match maxframesize.checked_sub(BDATA_HEADER_LEN) {
+        // The offer gives the chunk size in 16 bits.
+        Some(chunksize) if chunksize > 0 => std::cmp::min(chunksize, usize::from(u16::MAX)),
+        _ => {
+            return Err(mkerror(&format!(
+                "Radio packets of {} bytes are too small to carry file data",
+                maxframesize
+            )))
+        }
+    };
+    let nchunks = ((data.len() + chunksize - 1) / chunksize) as u32;
+    let end = Instant::now() + params.deadline;
+
+    let mut offer = BytesMut::new();
+    offer.put_u64(size);
+    offer.put_u16(chunksize as u16);
+    offer.put_slice(name.as_bytes());
+    let offer = mkmsg(MSG_BOFFER, crc, &offer);
+    let poll = mkmsg(MSG_POLL, crc, &[]);
+
+    let mut missing: BTreeSet<u32> = (0..nchunks).collect();
+    // Recipients that have finished, and whether they were successful
+    let mut done: HashMap<u64, bool> = HashMap::new();
+    let mut round = 0;
+
+    loop {
+        round += 1;
+        debug!(
+            "FILEDIST: round {}, broadcasting {} chunks",
+            round,
+            missing.len()
+        );
+        broadcast(&sender, offer.clone());
+        for index in std::mem::take(&mut missing) {
+            let start = index as usize * chunksize;
+            let chunkend = std::cmp::min(start + chunksize, data.len());
+            let mut body = BytesMut::new();
+            body.put_u32(index);
+            body.put_slice(&data[start..chunkend]);
+            broadcast(&sender, mkmsg(MSG_BDATA, crc, &body));
+
+            // Pick up any early completions.
+            while let Ok((fromu64, _fromu16, payload)) = framerx.try_recv() {
+                handle_response(fromu64, payload, crc, nchunks, &mut missing, &mut done);
+            }
+        }
+        // Anything NACKed while we were still sending is covered by the next poll.
+        missing.clear();
+
+        broadcast(&sender, poll.clone());
+        let roundend = Instant::now() + params.round_timeout;
+        while let Ok((fromu64, _fromu16, payload)) =
+            framerx.recv_timeout(roundend.saturating_duration_since(Instant::now()))
+        {
+            handle_response(fromu64, payload, crc, nchunks, &mut missing, &mut done);
+        }
+
+        let finished = if recipients.is_empty() {
+            missing.is_empty()
+        } else {
+            recipients.iter().all(|r| done.contains_key(r))
+        };
+        if finished || Instant::now() >= end {
+            break;
+        }
+    }
+
+    println!(
+        "Distributed {} ({} bytes, CRC {:08x}) in {} rounds",
+        name, size, crc, round
+    );
+    let mut failures = 0;
+    for (addr, ok) in done.iter() {
+        println!(
+            "{:x}: {}",
+            addr,
+            if *ok { "complete" } else { "failed" }
+        );
+        if !ok {
+            failures += 1;
+        }
+    }
+    for addr in recipients.iter().filter(|r| !done.contains_key(r)) {
+        println!("{:x}: no confirmation", addr);
+        failures += 1;
+    }
+
+    if failures > 0 {
+        Err(mkerror(&format!("{} recipients did not complete", failures)))
+    } else {
+        Ok(())
+    }
+}
+
+fn broadcast(sender: &crossbeam_channel::Sender<XBTX>, msg: Bytes) {
+    sender
+        .send(XBTX::TXData(XBDestAddr::U64(XB_BROADCAST), msg))
+        .unwrap();
+}
+
+/// Process a response from a receiver.
+fn handle_response(
+    fromu64: u64,
+    payload: Bytes,
+    crc: u32,
+    nchunks: u32,
+    missing: &mut BTreeSet<u32>,
+    done: &mut HashMap<u64, bool>,
+) {
+    let (msgtype, _, mut body) = match parsemsg(payload) {
+        Some(msg) if msg.1 == crc => msg,
+        _ => return,
+    };
+    match msgtype {
+        MSG_NACK => {
+            while body.len() >= 6 {
+                let first = body.get_u32();
+                let count = body.get_u16();
+                trace!(
+                    "FILEDIST: {:x} missing {} chunks from {}",
+                    fromu64,
+                    count,
+                    first
+                );
+                let last = std::cmp::min(first.saturating_add(u32::from(count)), nchunks);
+                missing.extend(first..last);
+            }
+        }
+        MSG_COMPLETE => {
+            let ok = body.first() == Some(&1);
+            if done.insert(fromu64, ok).is_none() {
+                info!("FILEDIST: {:x} finished; success: {}", fromu64, ok);
+            }
+        }
+        MSG_REJECT => {
+            let first = done.insert(fromu64, false).is_none();
+            if first {
+                println!(
+                    "{:x} rejected transfer: {}",
+                    fromu64,
+                    String::from_utf8_lossy(&body)
+                );
+            }
+        }
+        _ => (),
+    }
+}
+
+/// State of a broadcast transfer being received
+struct Incoming {
+    sender: u64,
+    crc: u32,
+    size: u64,
+    chunksize: usize,
+    have: Vec<bool>,
+    remaining: usize,
+    partpath: PathBuf,
+    finalpath: PathBuf,
+    file: fs::File,
+}
+
+/** Receive a broadcast transfer, starting with the given BOFFER message from
+fromu64.  This is called by filexfer::recvfile when it sees one.  Offers of files
+larger than max_size are rejected.  Replies to polls are delayed by an amount derived
+from mymac, so receivers don't all answer at once. */
+#[allow(clippy::too_many_arguments)]
+pub fn recvdist(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    fromu64: u64,
+    offer: Bytes,
+    dir: &Path,
+    overwrite: bool,
+    max_size: u64,
+    mymac: u64,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let reply = |msg: Bytes| {
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(fromu64), msg))
+            .unwrap()
+    };
+    let jitter = Duration::from_millis((mymac % 16) * 100);
+
+    let (crc, mut offer) = match parsemsg(offer) {
+        Some((MSG_BOFFER, crc, body)) if body.len() >= 10 => (crc, body),
+        _ => return Err(mkerror("Malformed broadcast offer")),
+    };
+    let size = offer.get_u64();
+    let chunksize = usize::from(offer.get_u16());
+    // The sender sized BDATA to fill a frame; a NACK may be just as large.
+    let maxranges = (chunksize + BDATA_HEADER_LEN - MAGIC.len() - 5) / 6;
+    let name = String::from_utf8_lossy(&offer).into_owned();
+    let name = match Path::new(&name).file_name() {
+        Some(n) if chunksize > 0 => n.to_owned(),
+        _ => {
+            reply(mkmsg(MSG_REJECT, crc, b"invalid offer"));
+            return Err(mkerror("Invalid broadcast offer"));
+        }
+    };
+    let chunksize64 = chunksize as u64;
+    let chunks = size / chunksize64 + u64::from(size % chunksize64 != 0);
+    if size > max_size || chunks > MAX_CHUNKS {
+        reply(mkmsg(MSG_REJECT, crc, b"too large"));
+        return Err(mkerror(&format!(
+            "Broadcast offer of {} bytes in chunks of {} is too large",
+            size, chunksize
+        )));
+    }
+    let finalpath = dir.join(&name);
+    if !overwrite && finalpath.exists() {
+        reply(mkmsg(MSG_REJECT, crc, b"file exists"));
+        return Err(mkerror(&format!("{:?} already exists", finalpath)));
+    }
+    let partpath = dir.join(format!(".{}.{:08x}.dpart", name.to_string_lossy(), crc));
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&partpath)?;
+    file.set_len(size)?;
+    let nchunks = (size as usize + chunksize - 1) / chunksize;
+    println!(
+        "Receiving broadcast {} ({} bytes) from {:x}",
+        name.to_string_lossy(),
+        size,
+        fromu64
+    );
+
+    let mut inc = Incoming {
+        sender: fromu64,
+        crc,
+        size,
+        chunksize,
+        have: vec![false; nchunks],
+        remaining: nchunks,
+        partpath,
+        finalpath,
+        file,
+    };
+    let mut completed: Option<bool> = None;
+    let mut lingerdeadline = Instant::now();
+
+    loop {
+        if completed.is_none() && inc.remaining == 0 {
+            let ok = crc32(&fs::read(&inc.partpath)?) == inc.crc;
+            if ok {
+                fs::rename(&inc.partpath, &inc.finalpath)?;
+                println!("Received {:?} (CRC {:08x} verified)", inc.finalpath, inc.crc);
+            } else {
+                error!("CRC mismatch on {:?}; discarding", inc.finalpath);
+                fs::remove_file(&inc.partpath)?;
+            }
+            reply(mkmsg(MSG_COMPLETE, inc.crc, &[ok as u8]));
+            completed = Some(ok);
+            lingerdeadline = Instant::now() + LINGER;
+        }
+
+        let frame = match completed {
+            Some(_) => {
+                framerx.recv_timeout(lingerdeadline.saturating_duration_since(Instant::now()))
+            }
+            None => framerx
+                .recv()
+                .map_err(|_| crossbeam_channel::RecvTimeoutError::Disconnected),
+        };
+        let payload = match frame {
+            Ok((f, _fromu16, payload)) if f == inc.sender => payload,
+            Ok(_) => continue,
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                return if completed == Some(true) {
+                    Ok(())
+                } else {
+                    Err(mkerror("CRC mismatch on received file"))
+                };
+            }
+            Err(e) => return Err(mkerror(&e.to_string())),
+        };
+        let (msgtype, _, mut body) = match parsemsg(payload) {
+            Some(msg) if msg.1 == inc.crc => msg,
+            _ => continue,
+        };
+
+        match (msgtype, completed) {
+            (MSG_POLL, Some(ok)) => {
+                thread::sleep(jitter);
+                reply(mkmsg(MSG_COMPLETE, inc.crc, &[ok as u8]));
+            }
+            (MSG_POLL, None) => {
+                thread::sleep(jitter);
+                let mut nack = BytesMut::new();
+                for (first, count) in missing_ranges(&inc.have).into_iter().take(maxranges) {
+                    nack.put_u32(first);
+                    nack.put_u16(count);
+                }
+                reply(mkmsg(MSG_NACK, inc.crc, &nack));
+            }
+            (MSG_BDATA, None) => {
+                if body.len() < 4 {
+                    continue;
+                }
+                let index = body.get_u32() as usize;
+                let offset = (index * inc.chunksize) as u64;
+                if index < inc.have.len()
+                    && !inc.have[index]
+                    && offset + body.len() as u64 <= inc.size
+                {
+                    inc.file.write_all_at(&body, offset)?;
+                    inc.have[index] = true;
+                    inc.remaining -= 1;
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Return the (first, count) runs of false entries in have.
+fn missing_ranges(have: &[bool]) -> Vec<(u32, u16)> {
+    let mut ranges: Vec<(u32, u16)> = Vec::new();
+    for (index, _) in have.iter().enumerate().filter(|(_, h)| !**h) {
+        match ranges.last_mut() {
+            Some((first, count))
+                if *first as usize + *count as usize == index && *count < u16::MAX =>
+            {
+                *count += 1
+            }
+            _ => ranges.push((index as u32, 1)),
+        }
+    }
+    ranges
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_ranges_runs() {
+        let have = [true, false, false, true, false, true, true, false];
+        assert_eq!(missing_ranges(&have), vec![(1, 2), (4, 1), (7, 1)]);
+        assert_eq!(missing_ranges(&[true; 5]), vec![]);
+        assert_eq!(missing_ranges(&[false; 5]), vec![(0, 5)]);
+        assert_eq!(missing_ranges(&[]), vec![]);
+    }
+
+    #[test]
+    fn missing_ranges_split_at_u16() {
+        let have = vec![false; usize::from(u16::MAX) + 2];
+        assert_eq!(
+            missing_ranges(&have),
+            vec![(0, u16::MAX), (u32::from(u16::MAX), 2)]
+        );
+    }
+}
+
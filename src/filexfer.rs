@@ -0,0 +1,498 @@
+/*! File transfer with integrity check and resume */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The protocol is a simple go-back-N scheme.  Every message starts with MAGIC,
+a message type byte, and the CRC-32 of the complete file, which identifies the
+transfer.  All integers are big-endian.
+
+  OFFER    sender -> receiver   size: u64, name: remaining bytes
+  ACCEPT   receiver -> sender   offset: u64 at which to start (nonzero on resume)
+  REJECT   receiver -> sender   reason: remaining bytes
+  DATA     sender -> receiver   offset: u64, data: remaining bytes
+  ACK      receiver -> sender   offset: u64 of the next byte expected
+  COMPLETE receiver -> sender   ok: u8, 1 if the CRC of the received file matched
+
+The receiver only accepts DATA at the offset it expects next, and writes it to a
+partial file.  DATA at any other offset is answered with a duplicate ACK, which
+prompts the sender to go back without waiting for its timeout.  The length of that file is therefore always the last confirmed
+offset, and a later OFFER of the same file resumes from there. */
+
+use crate::filedist;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use log::*;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const MAGIC: &[u8] = b"XF";
+pub const MSG_OFFER: u8 = b'O';
+pub const MSG_ACCEPT: u8 = b'A';
+pub const MSG_REJECT: u8 = b'R';
+pub const MSG_DATA: u8 = b'D';
+pub const MSG_ACK: u8 = b'K';
+pub const MSG_COMPLETE: u8 = b'C';
+
+/// Length of MAGIC, message type, CRC, and offset
+pub const DATA_HEADER_LEN: usize = 15;
+
+/// How long the receiver keeps answering retransmissions after a transfer completes.
+const LINGER: Duration = Duration::from_secs(10);
+
+/// Calculate the CRC-32 (IEEE 802.3) of the data
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Build a message with the standard header.
+pub fn mkmsg(msgtype: u8, crc: u32, body: &[u8]) -> Bytes {
+    let mut msg = BytesMut::with_capacity(MAGIC.len() + 5 + body.len());
+    msg.put_slice(MAGIC);
+    msg.put_u8(msgtype);
+    msg.put_u32(crc);
+    msg.put_slice(body);
+    msg.freeze()
+}
+
+/// Parse the standard header of a message, returning the type, CRC, and body.
+pub fn parsemsg(mut payload: Bytes) -> Option<(u8, u32, Bytes)> {
+    if payload.len() < MAGIC.len() + 5 || !payload.starts_with(MAGIC) {
+        return None;
+    }
+    payload.advance(MAGIC.len());
+    let msgtype = payload.get_u8();
+    let crc = payload.get_u32();
+    Some((msgtype, crc, payload))
+}
+
+/// Parse a message body that begins with an offset.
+pub fn parseoffset(mut body: Bytes) -> Option<(u64, Bytes)> {
+    if body.len() < 8 {
+        return None;
+    }
+    let offset = body.get_u64();
+    Some((offset, body))
+}
+
+/// Retransmission parameters for a transfer
+#[derive(Clone, Copy, Debug)]
+pub struct XferParams {
+    /// How long to wait for a response before retransmitting
+    pub timeout: Duration,
+
+    /// How many consecutive timeouts to tolerate before giving up
+    pub retries: u32,
+
+    /// How many chunks to send ahead of the last acknowledgment
+    pub window: u64,
+}
+
+fn send(sender: &crossbeam_channel::Sender<XBTX>, dest: u64, msg: Bytes) {
+    sender.send(XBTX::TXData(XBDestAddr::U64(dest), msg)).unwrap();
+}
+
+/** Send the file at path to dest, calling it name on the remote end.  framerx
+supplies received frames, as from mux::Dispatcher::open.  maxframesize is the most
+data that fits in one XBee packet. */
+pub fn sendfile(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    dest: u64,
+    path: &Path,
+    name: &str,
+    maxframesize: usize,
+    params: XferParams,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let data = fs::read(path)?;
+    let size = data.len() as u64;
+    let crc = crc32(&data);
+    let chunksize = match maxframesize.checked_sub(DATA_HEADER_LEN) {
+        Some(chunksize) if chunksize > 0 => chunksize as u64,
+        _ => {
+            return Err(mkerror(&format!(
+                "Radio packets of {} bytes are too small to carry file data",
+                maxframesize
+            )))
+        }
+    };
+
+    let mut offer = BytesMut::new();
+    offer.put_u64(size);
+    offer.put_slice(name.as_bytes());
+    let offer = mkmsg(MSG_OFFER, crc, &offer);
+
+    let mkdata = |offset: u64| {
+        let end = std::cmp::min(offset + chunksize, size);
+        let mut body = BytesMut::new();
+        body.put_u64(offset);
+        body.put_slice(&data[offset as usize..end as usize]);
+        mkmsg(MSG_DATA, crc, &body)
+    };
+
+    // The lowest offset not yet acknowledged, and the next offset to transmit.
+    let mut base: Option<u64> = None;
+    let mut next: u64 = 0;
+    let mut failures = 0;
+    // The base for which we last went back on a duplicate ACK
+    let mut fastretransmit: Option<u64> = None;
+
+    send(&sender, dest, offer.clone());
+    loop {
+        if let Some(b) = base {
+            while next < size && next < b + params.window * chunksize {
+                send(&sender, dest, mkdata(next));
+                next += chunksize;
+            }
+        }
+
+        let (fromu64, payload) = match framerx.recv_timeout(params.timeout) {
+            Ok((fromu64, _fromu16, payload)) => (fromu64, payload),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                failures += 1;
+                if failures > params.retries {
+                    return Err(mkerror("Timed out waiting for the receiver"));
+                }
+                match base {
+                    // Either the offer wasn't answered, or everything is acknowledged
+                    // but the completion was lost.  The receiver answers anything for
+                    // this transfer with it again.
+                    None => send(&sender, dest, offer.clone()),
+                    Some(b) if b >= size => send(&sender, dest, offer.clone()),
+                    Some(b) => {
+                        debug!("FILEXFER: timeout; resending from offset {}", b);
+                        next = b;
+                    }
+                }
+                continue;
+            }
+            Err(e) => return Err(mkerror(&e.to_string())),
+        };
+
+        if fromu64 != dest {
+            continue;
+        }
+        let (msgtype, msgcrc, body) = match parsemsg(payload) {
+            Some(msg) if msg.1 == crc => msg,
+            _ => continue,
+        };
+
+        match msgtype {
+            MSG_ACCEPT if base.is_none() => {
+                if let Some((offset, _)) = parseoffset(body) {
+                    if offset > 0 {
+                        println!("Resuming {} at offset {} of {}", name, offset, size);
+                    }
+                    base = Some(std::cmp::min(offset, size));
+                    next = std::cmp::min(offset, size);
+                    failures = 0;
+                }
+            }
+            MSG_REJECT => {
+                return Err(mkerror(&format!(
+                    "Receiver rejected transfer: {}",
+                    String::from_utf8_lossy(&body)
+                )));
+            }
+            MSG_ACK => {
+                if let (Some(b), Some((offset, _))) = (base, parseoffset(body)) {
+                    if offset > b {
+                        trace!("FILEXFER: {:x} acknowledged through {}", msgcrc, offset);
+                        base = Some(std::cmp::min(offset, size));
+                        next = std::cmp::max(next, offset);
+                        failures = 0;
+                    } else if offset == b && next > b && fastretransmit != Some(b) {
+                        // The receiver saw a gap; don't wait for the timeout.
+                        debug!("FILEXFER: duplicate ACK; resending from offset {}", b);
+                        next = b;
+                        fastretransmit = Some(b);
+                    }
+                }
+            }
+            MSG_COMPLETE => {
+                if body.first() == Some(&1) {
+                    println!("Sent {} ({} bytes, CRC {:08x})", name, size, crc);
+                    return Ok(());
+                } else {
+                    return Err(mkerror("Receiver reports CRC mismatch"));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// State of a transfer being received
+struct Incoming {
+    sender: u64,
+    crc: u32,
+    size: u64,
+    partpath: PathBuf,
+    finalpath: PathBuf,
+    file: fs::File,
+    offset: u64,
+}
+
+/** Open the partial file for a transfer of size bytes, for appending, and return it with
+the offset at which to resume.  A partial file longer than the transfer can't be part
+of it, so it is started over. */
+fn open_partial(partpath: &Path, size: u64) -> io::Result<(fs::File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(partpath)?;
+    let mut offset = file.metadata()?.len();
+    if offset > size {
+        file.set_len(0)?;
+        offset = 0;
+    }
+    Ok((file, offset))
+}
+
+/** Receive a single file into dir, optionally only from the given sender, rejecting
+files larger than max_size.  If timeout is given, give up if no transfer has been
+offered in that time.  Broadcast transfers are handed off to filedist::recvdist. */
+#[allow(clippy::too_many_arguments)]
+pub fn recvfile(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    dir: &Path,
+    from: Option<u64>,
+    overwrite: bool,
+    max_size: u64,
+    timeout: Option<Duration>,
+    mymac: u64,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let startdeadline = timeout.map(|t| Instant::now() + t);
+    let mut incoming: Option<Incoming> = None;
+    let mut completed: Option<(u64, u32, bool)> = None;
+    let mut lingerdeadline = Instant::now();
+
+    loop {
+        let frame = match (&completed, &incoming, startdeadline) {
+            (Some(_), _, _) => {
+                framerx.recv_timeout(lingerdeadline.saturating_duration_since(Instant::now()))
+            }
+            (None, None, Some(d)) => {
+                framerx.recv_timeout(d.saturating_duration_since(Instant::now()))
+            }
+            _ => framerx
+                .recv()
+                .map_err(|_| crossbeam_channel::RecvTimeoutError::Disconnected),
+        };
+        let (fromu64, payload) = match frame {
+            Ok((fromu64, _fromu16, payload)) => (fromu64, payload),
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => match completed {
+                Some((_, _, true)) => return Ok(()),
+                Some((_, _, false)) => return Err(mkerror("CRC mismatch on received file")),
+                None => return Err(mkerror("Timed out waiting for a file")),
+            },
+            Err(e) => return Err(mkerror(&e.to_string())),
+        };
+
+        if from.is_some() && from != Some(fromu64) {
+            continue;
+        }
+        let (msgtype, crc, body) = match parsemsg(payload.clone()) {
+            Some(msg) => msg,
+            None => continue,
+        };
+
+        if msgtype == filedist::MSG_BOFFER && incoming.is_none() && completed.is_none() {
+            return filedist::recvdist(
+                framerx, fromu64, payload, dir, overwrite, max_size, mymac, sender,
+            );
+        }
+
+        // After completion, just answer retransmissions until things go quiet.
+        if let Some((csender, ccrc, ok)) = completed {
+            if csender == fromu64 && ccrc == crc {
+                send(&sender, fromu64, mkmsg(MSG_COMPLETE, crc, &[ok as u8]));
+            }
+            continue;
+        }
+
+        match msgtype {
+            MSG_OFFER => {
+                if let Some(inc) = &incoming {
+                    if inc.sender != fromu64 || inc.crc != crc {
+                        send(&sender, fromu64, mkmsg(MSG_REJECT, crc, b"busy"));
+                        continue;
+                    }
+                }
+                let (size, name) = match parseoffset(body) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                let name = String::from_utf8_lossy(&name);
+                let name = match Path::new(name.as_ref()).file_name() {
+                    Some(n) => n.to_owned(),
+                    None => {
+                        send(&sender, fromu64, mkmsg(MSG_REJECT, crc, b"invalid name"));
+                        continue;
+                    }
+                };
+                if size > max_size {
+                    send(&sender, fromu64, mkmsg(MSG_REJECT, crc, b"too large"));
+                    continue;
+                }
+                let finalpath = dir.join(&name);
+                if incoming.is_none() && !overwrite && finalpath.exists() {
+                    send(&sender, fromu64, mkmsg(MSG_REJECT, crc, b"file exists"));
+                    continue;
+                }
+                let partpath = dir.join(format!(
+                    ".{}.{:08x}.part",
+                    name.to_string_lossy(),
+                    crc
+                ));
+                let (file, offset) = open_partial(&partpath, size)?;
+                println!(
+                    "Receiving {} ({} bytes) from {:x}{}",
+                    name.to_string_lossy(),
+                    size,
+                    fromu64,
+                    if offset > 0 {
+                        format!(", resuming at {}", offset)
+                    } else {
+                        String::new()
+                    }
+                );
+                let mut body = BytesMut::new();
+                body.put_u64(offset);
+                send(&sender, fromu64, mkmsg(MSG_ACCEPT, crc, &body));
+                incoming = Some(Incoming {
+                    sender: fromu64,
+                    crc,
+                    size,
+                    partpath,
+                    finalpath,
+                    file,
+                    offset,
+                });
+            }
+            MSG_DATA => {
+                let inc = match &mut incoming {
+                    Some(inc) if inc.sender == fromu64 && inc.crc == crc => inc,
+                    _ => continue,
+                };
+                let (offset, data) = match parseoffset(body) {
+                    Some(x) => x,
+                    None => continue,
+                };
+                if offset == inc.offset && inc.offset + data.len() as u64 <= inc.size {
+                    inc.file.write_all(&data)?;
+                    inc.offset += data.len() as u64;
+                }
+                if inc.offset < inc.size {
+                    let mut body = BytesMut::new();
+                    body.put_u64(inc.offset);
+                    send(&sender, fromu64, mkmsg(MSG_ACK, crc, &body));
+                }
+            }
+            _ => continue,
+        }
+
+        // Zero-length files are complete as soon as they are accepted.
+        if let Some(inc) = &mut incoming {
+            if inc.offset == inc.size {
+                inc.file.flush()?;
+                let ok = crc32(&fs::read(&inc.partpath)?) == inc.crc;
+                if ok {
+                    fs::rename(&inc.partpath, &inc.finalpath)?;
+                    println!("Received {:?} (CRC {:08x} verified)", inc.finalpath, inc.crc);
+                } else {
+                    error!("CRC mismatch on {:?}; discarding", inc.finalpath);
+                    fs::remove_file(&inc.partpath)?;
+                }
+                send(&sender, inc.sender, mkmsg(MSG_COMPLETE, inc.crc, &[ok as u8]));
+                completed = Some((inc.sender, inc.crc, ok));
+                lingerdeadline = Instant::now() + LINGER;
+                incoming = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"a"), 0xe8b7be43);
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let mut body = BytesMut::new();
+        body.put_u64(4096);
+        body.put_slice(b"data");
+        let msg = mkmsg(MSG_DATA, 0xcbf43926, &body);
+        let (msgtype, crc, body) = parsemsg(msg).unwrap();
+        assert_eq!((msgtype, crc), (MSG_DATA, 0xcbf43926));
+        assert_eq!(parseoffset(body), Some((4096, Bytes::from_static(b"data"))));
+        assert_eq!(parsemsg(Bytes::from_static(b"XF")), None);
+        assert_eq!(parseoffset(Bytes::from_static(b"short")), None);
+    }
+
+    #[test]
+    fn resume_from_partial_file() {
+        let partpath =
+            std::env::temp_dir().join(format!(".xbnet-test-{}.part", process::id()));
+        let _ = fs::remove_file(&partpath);
+
+        // Nothing received yet
+        let (mut file, offset) = open_partial(&partpath, 10).unwrap();
+        assert_eq!(offset, 0);
+        file.write_all(b"0123").unwrap();
+        drop(file);
+
+        // Some received: resume after it, and append
+        let (mut file, offset) = open_partial(&partpath, 10).unwrap();
+        assert_eq!(offset, 4);
+        file.write_all(b"45").unwrap();
+        drop(file);
+        assert_eq!(fs::read(&partpath).unwrap(), b"012345");
+
+        // Longer than the file offered: start over
+        let (_, offset) = open_partial(&partpath, 3).unwrap();
+        assert_eq!(offset, 0);
+        assert_eq!(fs::metadata(&partpath).unwrap().len(), 0);
+
+        fs::remove_file(&partpath).unwrap();
+    }
+}
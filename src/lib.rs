@@ -0,0 +1,29 @@
+/*! Talking to the radio: framing, parsing, and reassembly, and the records kept of it */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The receive path is a library so that it can be fuzzed as it is built; see fuzz/.
+The rest of xbnet is in the binary, which uses these modules through the library. */
+
+pub mod events;
+pub mod neighbors;
+pub mod pcap;
+pub mod ser;
+pub mod stats;
+pub mod xbpacket;
+pub mod xbrx;
@@ -18,18 +18,38 @@
 
 use log::*;
 use simplelog::*;
+use std::fs;
 use std::io;
+use std::process;
+use std::sync::Arc;
 use std::thread;
 
+mod beacon;
+mod bench;
+mod broker;
+mod chat;
+mod config;
+mod control;
+mod daemon;
+mod decode;
+mod discovery;
+mod filedist;
+mod filexfer;
+mod metrics;
+mod monitor;
+mod mux;
+mod oneshot;
 mod ping;
 mod pipe;
-mod ser;
+mod rangetest;
+mod spool;
 mod tap;
 mod tun;
 mod xb;
-mod xbpacket;
-mod xbrx;
 
+use xbnet::{events, neighbors, pcap, ser, stats, xbpacket, xbrx};
+
+use bytes::Bytes;
 use std::path::PathBuf;
 use std::time::Duration;
 use structopt::StructOpt;
@@ -50,42 +70,369 @@ struct Opt {
     #[structopt(long, parse(from_os_str))]
     initfile: Option<PathBuf>,
 
-    /// Serial port to use to communicate with radio
+    /// Serial port to use to communicate with radio.  Not needed by enqueue, or with --broker.
     #[structopt(parse(from_os_str))]
-    port: PathBuf,
+    port: Option<PathBuf>,
+
+    /// Use the radio through the xbnet broker listening on this socket, instead of a serial port
+    #[structopt(long, parse(from_os_str))]
+    broker: Option<PathBuf>,
 
-    /// The speed in bps (baud rate) to use to communicate on the serial port
-    #[structopt(long, default_value = "9600")]
-    serial_speed: u32,
+    /// Record the raw bytes exchanged with the radio, with timestamps, to this file
+    #[structopt(long, parse(from_os_str))]
+    record: Option<PathBuf>,
+
+    /// Replay a session recorded with --record instead of using a radio.  With --broker, the
+    /// recording is of a session through a broker.
+    #[structopt(long, parse(from_os_str))]
+    replay: Option<PathBuf>,
+
+    /// The speed in bps (baud rate) to use to communicate on the serial port; defaults to 9600
+    #[structopt(long)]
+    serial_speed: Option<u32>,
 
     /// Disable the Xbee-level ACKs
     #[structopt(long)]
     disable_xbee_acks: bool,
 
-    /// Request XBee transmit reports.  These will appear in debug mode but otherwise are not considered.
+    /// Request XBee transmit reports.  These judge the link to each neighbor, which tun and tap
+    /// take into account, and appear in debug mode.
     #[structopt(long)]
     request_xbee_tx_reports: bool,
 
+    /// Ask the radio for node discovery (ATND) at startup, learning the names of the
+    /// nodes that answer
+    #[structopt(long)]
+    discover: bool,
+
+    /// Also repeat node discovery every this many seconds.  0 does not repeat it.
+    #[structopt(long, default_value = "0")]
+    discover_interval: u64,
+
+    /// Accept commands to inspect and adjust xbnet while it runs on this Unix socket
+    #[structopt(long, parse(from_os_str))]
+    control: Option<PathBuf>,
+
+    /// Write events as JSON lines to this file; "-" for stdout, "stderr" for stderr, or "unix:PATH" to serve them on a Unix socket
+    #[structopt(long)]
+    events: Option<String>,
+
+    /// Capture every packet sent or received over the radio to this pcap file
+    #[structopt(long, parse(from_os_str))]
+    pcap: Option<PathBuf>,
+
+    /// Serve Prometheus metrics over HTTP at this address, such as 127.0.0.1:9101
+    #[structopt(long)]
+    metrics: Option<String>,
+
+    /// Print traffic statistics to stderr on exit, including on SIGINT or SIGTERM
+    #[structopt(long)]
+    stats: bool,
+
+    /// Channel (0-255) to send and receive on.  Frames on other channels are ignored.
+    #[structopt(long, default_value = "0")]
+    channel: u8,
+
+    /// Also answer pings on this channel, sharing the radio with the subcommand
+    #[structopt(long)]
+    pong_channel: Option<u8>,
+
     #[structopt(subcommand)]
     cmd: Command,
 }
 
 #[derive(Debug, StructOpt)]
 enum Command {
-    /// Transmit ping requests
+    /// Transmit ping requests and report round-trip times
     Ping {
-        /// The 64-bit destination for the ping, in hex
+        /// The 64-bit destination for the ping, in hex; ffff to find every node that answers
         #[structopt(long)]
         dest: String,
+
+        /// Stop after sending this many probes
+        #[structopt(long)]
+        count: Option<u64>,
+
+        /// Seconds between probes
+        #[structopt(long, default_value = "5")]
+        interval: f64,
+
+        /// Pad each probe to this many bytes
+        #[structopt(long, default_value = "0")]
+        size: usize,
+
+        /// Seconds to wait for the reply to each probe, or with --dest ffff, to collect replies
+        #[structopt(long, default_value = "10")]
+        timeout: f64,
+
+        /// Stop after this many seconds, however many probes have been sent
+        #[structopt(long)]
+        deadline: Option<u64>,
     },
     /// Receive ping requests and transmit pongs
     Pong,
+    /// Measure throughput to another node running bench --server
+    Bench {
+        /// The 64-bit address of the bench server, in hex
+        #[structopt(long, required_unless = "server", conflicts_with = "server")]
+        dest: Option<String>,
+
+        /// Answer bench clients instead, reporting each run
+        #[structopt(long)]
+        server: bool,
+
+        /// Seconds to send for; defaults to 10 unless --bytes is given
+        #[structopt(long)]
+        duration: Option<f64>,
+
+        /// Stop after sending this many bytes
+        #[structopt(long)]
+        bytes: Option<u64>,
+
+        /// The size of each frame sent; defaults to what fits in one radio packet
+        #[structopt(long)]
+        size: Option<usize>,
+
+        /// Seconds to wait for the server to answer, and for the radio to report on
+        /// the packets sent
+        #[structopt(long, default_value = "5")]
+        timeout: f64,
+
+        /// Report in JSON, one object per line
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Exchange numbered probes with another node running rangetest --server, showing the
+    /// success rate and signal strength at both ends
+    Rangetest {
+        /// The 64-bit address of the rangetest server, in hex
+        #[structopt(long, required_unless = "server", conflicts_with = "server")]
+        dest: Option<String>,
+
+        /// Answer probes instead
+        #[structopt(long)]
+        server: bool,
+
+        /// Stop after sending this many probes
+        #[structopt(long)]
+        count: Option<u64>,
+
+        /// Seconds between probes
+        #[structopt(long, default_value = "1")]
+        interval: f64,
+
+        /// Seconds to wait for the reply to each probe
+        #[structopt(long, default_value = "2")]
+        timeout: f64,
+
+        /// The number of the latest probes that the success rate covers
+        #[structopt(long, default_value = "10")]
+        window: usize,
+
+        /// Also log each probe, with a timestamp, to this CSV file
+        #[structopt(long, parse(from_os_str))]
+        csv: Option<PathBuf>,
+    },
     /// Pipe data across radios using the xbnet protocol
     Pipe {
         /// The 64-bit destination for the pipe, in hex
         #[structopt(long)]
         dest: String,
         // FIXME: add a paremter to accept data from only that place
+
+        /// Hold small writes from stdin for up to this many milliseconds so they can be
+        /// combined into fuller radio frames.  0 disables coalescing.
+        #[structopt(long, default_value = "0")]
+        coalesce_delay_ms: u64,
+
+        /// When coalescing, transmit as soon as this percentage of a radio payload
+        /// has accumulated.
+        #[structopt(long, default_value = "100")]
+        coalesce_min_fill: u8,
+    },
+    /// Transmit a single message and exit
+    Send {
+        /// The 64-bit destination for the message, in hex
+        #[structopt(long)]
+        dest: String,
+
+        /// Treat MESSAGE as the name of a file whose contents are to be sent
+        #[structopt(long)]
+        file: bool,
+
+        /// Wait up to this many seconds for the radio to report successful delivery.
+        /// 0 exits as soon as the message has been handed to the radio.
+        #[structopt(long, default_value = "0")]
+        confirm_timeout: u64,
+
+        /// The message to send
+        message: String,
+    },
+    /// Wait for a single message, write it to stdout, and exit
+    Recv {
+        /// Only accept a message from this 64-bit address, in hex
+        #[structopt(long)]
+        from: Option<String>,
+
+        /// Give up after this many seconds.  0 waits forever.
+        #[structopt(long, default_value = "0")]
+        timeout: u64,
+    },
+    /// Send a file, with integrity checking and resume
+    Sendfile {
+        /// The 64-bit destination for the file, in hex
+        #[structopt(long)]
+        dest: String,
+
+        /// The name to give the file on the receiving end; defaults to the name of FILE
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Seconds to wait for a response from the receiver before retransmitting
+        #[structopt(long, default_value = "5")]
+        timeout: u64,
+
+        /// Number of consecutive timeouts before giving up
+        #[structopt(long, default_value = "10")]
+        retries: u32,
+
+        /// Number of chunks to transmit ahead of the last acknowledgment
+        #[structopt(long, default_value = "4")]
+        window: u64,
+
+        /// The file to send
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Receive a single file sent with sendfile
+    Recvfile {
+        /// Only accept a file from this 64-bit address, in hex
+        #[structopt(long)]
+        from: Option<String>,
+
+        /// Directory in which to save the file
+        #[structopt(long, parse(from_os_str), default_value = ".")]
+        dir: PathBuf,
+
+        /// Replace an existing file of the same name
+        #[structopt(long)]
+        overwrite: bool,
+
+        /// Reject files larger than this many bytes
+        #[structopt(long, default_value = "104857600")]
+        max_size: u64,
+
+        /// Give up if no file is offered within this many seconds.  0 waits forever.
+        #[structopt(long, default_value = "0")]
+        timeout: u64,
+    },
+    /// Broadcast a file to many receivers at once, repairing only what they missed
+    Distribute {
+        /// 64-bit addresses, in hex, that must confirm receipt.  If none are given,
+        /// finish once a poll draws no requests for missing data.
+        #[structopt(long, number_of_values = 1)]
+        recipient: Vec<String>,
+
+        /// The name to give the file on the receiving end; defaults to the name of FILE
+        #[structopt(long)]
+        name: Option<String>,
+
+        /// Seconds to collect responses after each poll of the receivers
+        #[structopt(long, default_value = "5")]
+        round_timeout: u64,
+
+        /// Give up on recipients that haven't confirmed after this many seconds
+        #[structopt(long, default_value = "600")]
+        deadline: u64,
+
+        /// The file to send
+        #[structopt(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Line-oriented text chat with other nodes
+    Chat {
+        /// The name to show to others; defaults to $USER
+        #[structopt(long)]
+        nick: Option<String>,
+    },
+    /// Run the services given in a configuration file together on one radio.  The port
+    /// may be given in the file instead of on the command line.
+    Daemon {
+        /// The configuration file
+        #[structopt(long, parse(from_os_str))]
+        config: PathBuf,
+    },
+    /// Let other xbnet processes share the radio through a Unix socket
+    Broker {
+        /// The path of the socket to create
+        #[structopt(long, parse(from_os_str))]
+        socket: PathBuf,
+    },
+    /// Issue an AT command to the local radio and display the result, in hex
+    At {
+        /// The two-letter command, such as DB
+        command: String,
+
+        /// The parameter to set, in hex.  Omit to query the current value.
+        parameter: Option<String>,
+    },
+    /// List the nodes that answer node discovery, with their names, device types, and RSSI
+    Discover {
+        /// Only find the nodes in direct range, with ATFN instead of ATND
+        #[structopt(long)]
+        neighbors: bool,
+
+        /// Seconds to wait for answers; should exceed the radio's ATNT
+        #[structopt(long, default_value = "15")]
+        timeout: f64,
+    },
+    /// Display every frame the radio receives, decoded, without acting on any of them
+    Monitor {
+        /// Also show each frame in hex
+        #[structopt(long)]
+        hex: bool,
+    },
+    /// Decode API frames given in hex, one or more per line, or in binary, without using the radio
+    Decode {
+        /// The file to decode; standard input if not given
+        #[structopt(parse(from_os_str))]
+        file: Option<PathBuf>,
+
+        /// The input is the raw bytes exchanged with the radio, not hex
+        #[structopt(long)]
+        binary: bool,
+    },
+    /// Add a message to a store-and-forward spool, without using the radio
+    Enqueue {
+        /// The spool directory
+        #[structopt(long, parse(from_os_str))]
+        spool: PathBuf,
+
+        /// The 64-bit destination for the message, in hex
+        #[structopt(long)]
+        dest: String,
+
+        /// Discard the message if it hasn't been delivered within this many seconds
+        #[structopt(long, default_value = "86400")]
+        ttl: u64,
+
+        /// Treat MESSAGE as the name of a file whose contents are to be sent
+        #[structopt(long)]
+        file: bool,
+
+        /// The message to send
+        message: String,
+    },
+    /// Deliver messages from a store-and-forward spool as destinations become reachable
+    Forward {
+        /// The spool directory
+        #[structopt(long, parse(from_os_str))]
+        spool: PathBuf,
+
+        /// Also retry a failed destination after this many seconds, even if nothing
+        /// has been heard from it.  0 waits to hear from it.
+        #[structopt(long, default_value = "0")]
+        retry_interval: u64,
     },
     /// Create a virtual Ethernet interface and send frames across XBee
     Tap {
@@ -102,6 +449,9 @@ enum Command {
         /// at startup.
         #[structopt(long, default_value = "xbnet%d")]
         iface_name: String,
+
+        #[structopt(flatten)]
+        beacon: BeaconOpts,
     },
     /// Create a virtual IP interface and send frames across XBee
     Tun {
@@ -127,60 +477,701 @@ enum Command {
         #[structopt(long)]
         disable_ipv6: bool,
 
+        #[structopt(flatten)]
+        beacon: BeaconOpts,
     },
 }
 
+/// Presence beacons, for tun and tap
+#[derive(Debug, StructOpt)]
+struct BeaconOpts {
+    /// Broadcast a beacon with our addresses every this many seconds, and learn the
+    /// addresses of other nodes from theirs.  0 disables beacons.
+    #[structopt(long, default_value = "0")]
+    beacon_interval: u64,
+
+    /// The name to give in beacons; defaults to the host name
+    #[structopt(long)]
+    beacon_name: Option<String>,
+
+    /// The channel for beacons, which must differ from --channel
+    #[structopt(long, default_value = "255")]
+    beacon_channel: u8,
+}
+
+/// Convert a number of seconds to a Duration, where 0 means none.
+fn optional_secs(secs: u64) -> Option<Duration> {
+    if secs > 0 {
+        Some(Duration::from_secs(secs))
+    } else {
+        None
+    }
+}
+
+/// Give the radio to a single service on the given channel, returning its sender and frame receiver.
+fn open_single(
+    xbeesender: crossbeam_channel::Sender<xb::XBTX>,
+    xbreframer: xbrx::XBReframer,
+    ser: ser::XBSerReader,
+    channel: u8,
+    pong_channel: Option<u8>,
+) -> mux::Service {
+    let mut dispatcher = mux::Dispatcher::new(xbeesender);
+    let service = dispatcher.open(channel).expect("Failure opening channel");
+    start_pong(&mut dispatcher, pong_channel);
+    dispatcher.spawn(xbreframer, ser);
+    service
+}
+
+/** Like open_single, but also open the beacon channel if beacons are on, returning its
+service too. */
+fn open_with_beacon(
+    xbeesender: crossbeam_channel::Sender<xb::XBTX>,
+    xbreframer: xbrx::XBReframer,
+    ser: ser::XBSerReader,
+    channel: u8,
+    pong_channel: Option<u8>,
+    beacon: &BeaconOpts,
+) -> (mux::Service, Option<mux::Service>) {
+    let mut dispatcher = mux::Dispatcher::new(xbeesender);
+    let service = dispatcher.open(channel).expect("Failure opening channel");
+    let beaconservice = if beacon.beacon_interval > 0 {
+        Some(
+            dispatcher
+                .open(beacon.beacon_channel)
+                .expect("Failure opening beacon channel"),
+        )
+    } else {
+        None
+    };
+    start_pong(&mut dispatcher, pong_channel);
+    dispatcher.spawn(xbreframer, ser);
+    (service, beaconservice)
+}
+
+/** The channels to ask a broker for, or None for every frame.  Either way, responses to
+our own requests come too. */
+fn broker_channels(opt: &Opt) -> Option<Vec<u8>> {
+    let mut channels = vec![opt.channel];
+    match &opt.cmd {
+        Command::Monitor { .. } => return None,
+        Command::Tun { beacon, .. } | Command::Tap { beacon, .. } if beacon.beacon_interval > 0 => {
+            channels.push(beacon.beacon_channel)
+        }
+        _ => (),
+    }
+    channels.extend(opt.pong_channel);
+    Some(channels)
+}
+
+/// Whether cmd writes what it receives or reports to stdout, leaving no room there for events
+fn data_on_stdout(cmd: &Command) -> bool {
+    matches!(
+        cmd,
+        Command::Pipe { .. }
+            | Command::Recv { .. }
+            | Command::Chat { .. }
+            | Command::Monitor { .. }
+            | Command::Decode { .. }
+            | Command::Ping { .. }
+            | Command::Pong
+            | Command::Rangetest { .. }
+            | Command::Bench { .. }
+            | Command::At { .. }
+            | Command::Discover { .. }
+    )
+}
+
+/// Answer pings on pong_channel, if given, alongside the other services of dispatcher.
+fn start_pong(dispatcher: &mut mux::Dispatcher, pong_channel: Option<u8>) {
+    if let Some(pong_channel) = pong_channel {
+        let (sender, framerx) = match dispatcher.open_auxiliary(pong_channel) {
+            Ok(service) => service,
+            Err(e) => {
+                eprintln!("xbnet: --pong-channel: {}", e);
+                process::exit(1);
+            }
+        };
+        thread::spawn(move || {
+            if let Err(e) = ping::pong_quietly(framerx, sender) {
+                debug!("PONG: stopped: {}", e);
+            }
+        });
+    }
+}
+
+/// Send and receive beacons on service, for whichever of tun and tap is running.
+fn start_beacon(
+    opts: BeaconOpts,
+    mymac: u64,
+    tun: Option<tun::XBTun>,
+    tap: Option<tap::XBTap>,
+    service: mux::Service,
+) {
+    let name = opts.beacon_name.unwrap_or_else(beacon::hostname);
+    let beacon_sender = beacon::Beacon::new(
+        mymac,
+        name,
+        Duration::from_secs(opts.beacon_interval),
+        tun,
+        tap,
+    );
+    let beacon_receiver = beacon_sender.clone();
+    let (sender, framerx) = service;
+    thread::spawn(move || {
+        beacon_sender
+            .beacons_to_xb(sender)
+            .expect("Failure in beacons_to_xb");
+    });
+    thread::spawn(move || {
+        beacon_receiver
+            .beacons_from_xb(framerx)
+            .expect("Failure in beacons_from_xb");
+    });
+}
+
+/// For subcommands used from scripts: report an error and exit with a failure status.
+fn exit_on_error(res: io::Result<()>) {
+    if let Err(e) = res {
+        eprintln!("xbnet: {}", e);
+        stats::exiting();
+        process::exit(1);
+    }
+}
+
 fn main() {
     let opt = Opt::from_args();
+    if let Some(pong_channel) = opt.pong_channel {
+        if pong_channel == opt.channel {
+            eprintln!("xbnet: --pong-channel must differ from --channel");
+            process::exit(1);
+        }
+        // These don't share the radio through a dispatcher.
+        if matches!(
+            opt.cmd,
+            Command::Send { .. }
+                | Command::Broker { .. }
+                | Command::Monitor { .. }
+                | Command::At { .. }
+                | Command::Daemon { .. }
+                | Command::Decode { .. }
+                | Command::Enqueue { .. }
+        ) {
+            eprintln!("xbnet: --pong-channel is not supported by this subcommand");
+            process::exit(1);
+        }
+    }
+    if opt.stats
+        || opt.control.is_some()
+        || opt.metrics.is_some()
+        || matches!(opt.cmd, Command::Ping { .. } | Command::Rangetest { .. })
+    {
+        stats::want_rssi();
+    }
+    // ping stops on SIGINT itself, to print its summary.  The daemon sees to it, since
+    // its configuration can also ask for the statistics.
+    if opt.stats && matches!(opt.cmd, Command::Ping { .. }) {
+        stats::print_at_end();
+    } else if opt.stats && !matches!(opt.cmd, Command::Daemon { .. }) {
+        stats::print_on_exit().expect("Failed to set up signal handling");
+    }
 
-    if opt.debug {
+    // The control socket can turn logging on later.
+    if opt.debug || opt.control.is_some() {
         WriteLogger::init(LevelFilter::Trace, Config::default(), io::stderr())
             .expect("Failed to init log");
+        if !opt.debug {
+            log::set_max_level(LevelFilter::Off);
+        }
     }
     info!("xbnet starting");
 
-    let (ser_reader, ser_writer) = ser::new(opt.port, opt.serial_speed).expect("Failed to initialize serial port");
-    let (mut xb, xbeesender, writerthread) = xb::XB::new(
-        ser_reader,
-        ser_writer,
-        opt.initfile,
-        opt.disable_xbee_acks,
-        opt.request_xbee_tx_reports,
-    );
-    let mut xbreframer = xbrx::XBReframer::new();
+    // Commands that don't use the radio
+    if let Command::Decode { file, binary } = &opt.cmd {
+        let input: Box<dyn io::Read> = match file {
+            Some(file) => Box::new(fs::File::open(file).expect("Failed to open input")),
+            None => Box::new(io::stdin()),
+        };
+        exit_on_error(decode::decode(input, *binary));
+        return;
+    }
+    if let Command::Enqueue {
+        spool,
+        dest,
+        ttl,
+        file,
+        message,
+    } = &opt.cmd
+    {
+        let dest_u64: u64 = u64::from_str_radix(dest, 16).expect("Invalid destination");
+        let data = if *file {
+            fs::read(message).expect("Failure reading message file")
+        } else {
+            message.clone().into_bytes()
+        };
+        exit_on_error(spool::enqueue(
+            spool,
+            dest_u64,
+            &data,
+            Duration::from_secs(*ttl),
+        ));
+        return;
+    }
+
+    if let Command::Daemon { config } = &opt.cmd {
+        if opt.channel != mux::DEFAULT_CHANNEL {
+            eprintln!("xbnet: the daemon takes the channel of each service from its configuration");
+            process::exit(1);
+        }
+        let logging = opt.debug || opt.control.is_some();
+        let overrides = daemon::Overrides {
+            port: opt.port,
+            broker: opt.broker,
+            initfile: opt.initfile,
+            serial_speed: opt.serial_speed,
+            disable_xbee_acks: opt.disable_xbee_acks,
+            request_xbee_tx_reports: opt.request_xbee_tx_reports,
+            discover: opt.discover,
+            discover_interval: Some(opt.discover_interval).filter(|i| *i > 0),
+            control: opt.control,
+            metrics: opt.metrics,
+            events: opt.events,
+            pcap: opt.pcap,
+            record: opt.record,
+            replay: opt.replay,
+            stats: opt.stats,
+        };
+        exit_on_error(daemon::run(config, overrides, logging));
+        stats::exiting();
+        return;
+    }
+
+    if let Some(target) = &opt.events {
+        if target == "-" && data_on_stdout(&opt.cmd) {
+            eprintln!("xbnet: --events: this command writes its output to stdout; give a file or stderr instead");
+            process::exit(1);
+        }
+        events::open(target).expect("Failed to open event stream");
+    }
+    if let Some(path) = &opt.pcap {
+        pcap::open(path).expect("Failed to open pcap file");
+    }
+
+    // Started before the radio, so that its initialization can be watched.
+    let control = if opt.control.is_some() || opt.metrics.is_some() {
+        let control = Arc::new(control::Control::new());
+        if let Some(path) = &opt.control {
+            control::serve(path, control.clone()).expect("Failed to start control socket");
+        }
+        if let Some(address) = &opt.metrics {
+            metrics::serve(address, control.clone()).expect("Failed to start metrics listener");
+        }
+        Some(control)
+    } else {
+        None
+    };
+
+    // Confirming delivery of a one-shot send, or of spooled messages, and counting the
+    // retries of bench, require TX reports.
+    let request_xbee_tx_reports = opt.request_xbee_tx_reports
+        || matches!(opt.cmd, Command::Send { confirm_timeout, .. } if confirm_timeout > 0)
+        || matches!(opt.cmd, Command::Forward { .. })
+        || matches!(opt.cmd, Command::Bench { server: false, .. });
+
+    let recordpath = opt.record.as_ref();
+    let record = |(ser_reader, ser_writer)| match recordpath {
+        Some(path) => ser::record(ser_reader, ser_writer, path).expect("Failed to open recording"),
+        None => (ser_reader, ser_writer),
+    };
+    let replay = opt.replay.as_ref().map(|path| ser::replay(path, true).expect("Failed to read recording"));
+
+    let subscription = broker_channels(&opt);
+    let (xb, xbeesender, writerthread) = match (opt.broker, replay, opt.port) {
+        (Some(_), _, _) if matches!(opt.cmd, Command::Broker { .. }) => {
+            eprintln!("xbnet: the broker requires a serial port");
+            process::exit(1);
+        }
+        (Some(socket), replay, _) => {
+            let (ser_reader, ser_writer) = match replay {
+                Some(replay) => replay,
+                None => record(ser::connect(socket).expect("Failed to connect to broker")),
+            };
+            let client = xb::XB::new_client(
+                ser_reader,
+                ser_writer,
+                opt.disable_xbee_acks,
+                request_xbee_tx_reports,
+            )
+            .expect("Failed to initialize radio through broker");
+            if let Some(channels) = &subscription {
+                broker::subscribe(&client.1, channels).expect("Failed to subscribe to broker");
+            }
+            client
+        }
+        (None, Some((ser_reader, ser_writer)), _) => xb::XB::new(
+            ser_reader,
+            ser_writer,
+            opt.initfile,
+            opt.disable_xbee_acks,
+            request_xbee_tx_reports,
+        ),
+        (None, None, Some(port)) => {
+            let (ser_reader, ser_writer) =
+                record(ser::new(port, opt.serial_speed.unwrap_or(9600)).expect("Failed to initialize serial port"));
+            xb::XB::new(
+                ser_reader,
+                ser_writer,
+                opt.initfile,
+                opt.disable_xbee_acks,
+                request_xbee_tx_reports,
+            )
+        }
+        (None, None, None) => {
+            eprintln!("xbnet: a serial port is required for this command");
+            process::exit(1);
+        }
+    };
+    if let Some(control) = &control {
+        control.attach_sender(&xbeesender);
+    }
+    if opt.discover || opt.discover_interval > 0 {
+        discovery::spawn(xbeesender.clone(), optional_secs(opt.discover_interval));
+    }
+    let xbreframer = xbrx::XBReframer::new();
+    let channel = opt.channel;
+    let pong_channel = opt.pong_channel;
 
     match opt.cmd {
-        Command::Ping { dest } => {
+        Command::Ping {
+            dest,
+            count,
+            interval,
+            size,
+            timeout,
+            deadline,
+        } => {
+            let dest_u64: u64 = u64::from_str_radix(&dest, 16).expect("Invalid destination");
+            if size > xbpacket::max_frame_len(xb.maxpacketsize, channel) {
+                eprintln!(
+                    "xbnet: --size may be at most {}",
+                    xbpacket::max_frame_len(xb.maxpacketsize, channel)
+                );
+                process::exit(1);
+            }
+            let params = ping::PingParams {
+                count,
+                interval: Duration::from_secs_f64(interval),
+                size,
+                timeout: Duration::from_secs_f64(timeout),
+                deadline: deadline.map(Duration::from_secs),
+            };
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            exit_on_error(ping::ping(dest_u64, sender, framerx, params));
+        }
+        Command::Pong => {
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            ping::pong(framerx, sender).expect("Failure in pong");
+            // Make sure queued up data is sent
+            let _ = writerthread.join();
+        }
+        Command::Bench {
+            dest,
+            server,
+            duration,
+            bytes,
+            size,
+            timeout,
+            json,
+        } => {
+            let maxframesize = xb.maxpacketsize - xbpacket::header_len(channel);
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            if server {
+                exit_on_error(bench::server(sender, framerx, json));
+            } else {
+                let dest_u64 = u64::from_str_radix(&dest.unwrap(), 16).expect("Invalid destination");
+                let duration = match (duration, bytes) {
+                    (None, None) => Some(10.0),
+                    (duration, _) => duration,
+                };
+                let params = bench::BenchParams {
+                    duration: duration.map(Duration::from_secs_f64),
+                    bytes,
+                    size: size.unwrap_or(maxframesize),
+                    timeout: Duration::from_secs_f64(timeout),
+                    xbee_acks: !opt.disable_xbee_acks,
+                    json,
+                };
+                exit_on_error(bench::client(
+                    dest_u64,
+                    channel,
+                    xb.maxpacketsize,
+                    sender,
+                    framerx,
+                    params,
+                ));
+            }
+        }
+        Command::Rangetest {
+            dest,
+            server,
+            count,
+            interval,
+            timeout,
+            window,
+            csv,
+        } => {
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            if server {
+                exit_on_error(rangetest::server(sender, framerx));
+            } else {
+                let dest_u64 = u64::from_str_radix(&dest.unwrap(), 16).expect("Invalid destination");
+                let params = rangetest::RangeParams {
+                    count,
+                    interval: Duration::from_secs_f64(interval),
+                    timeout: Duration::from_secs_f64(timeout),
+                    window,
+                };
+                exit_on_error(rangetest::client(
+                    dest_u64,
+                    sender,
+                    framerx,
+                    params,
+                    csv.as_deref(),
+                ));
+            }
+        }
+        Command::Pipe {
+            dest,
+            coalesce_delay_ms,
+            coalesce_min_fill,
+        } => {
             let dest_u64: u64 = u64::from_str_radix(&dest, 16).expect("Invalid destination");
+            let maxframesize = xb.maxpacketsize - xbpacket::header_len(channel);
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
             thread::spawn(move || {
-                ping::genpings(dest_u64, xbeesender).expect("Failure in genpings")
+                pipe::stdout_processor(framerx).expect("Failure in stdout_processor")
             });
-            ping::displaypongs(&mut xbreframer, &mut xb.ser_reader);
+            pipe::stdin_processor(
+                dest_u64,
+                maxframesize,
+                Duration::from_millis(coalesce_delay_ms),
+                coalesce_min_fill,
+                sender,
+            )
+            .expect("Failure in stdin_processor");
             // Make sure queued up data is sent
             let _ = writerthread.join();
         }
-        Command::Pong => {
-            ping::pong(&mut xbreframer, &mut xb.ser_reader, xbeesender).expect("Failure in pong");
+        Command::Send {
+            dest,
+            file,
+            confirm_timeout,
+            message,
+        } => {
+            let dest_u64: u64 = u64::from_str_radix(&dest, 16).expect("Invalid destination");
+            let data = if file {
+                fs::read(&message).expect("Failure reading message file")
+            } else {
+                message.into_bytes()
+            };
+            let confirm_timeout = optional_secs(confirm_timeout);
+            let res = oneshot::send(
+                xb.ser_reader,
+                dest_u64,
+                channel,
+                Bytes::from(data),
+                xb.maxpacketsize,
+                confirm_timeout,
+                xbeesender,
+            );
             // Make sure queued up data is sent
             let _ = writerthread.join();
+            exit_on_error(res);
         }
-        Command::Pipe { dest } => {
+        Command::Recv { from, timeout } => {
+            let from_u64 = from.map(|f| u64::from_str_radix(&f, 16).expect("Invalid sender"));
+            let (_sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            exit_on_error(oneshot::recv(
+                framerx,
+                from_u64,
+                optional_secs(timeout),
+            ));
+        }
+        Command::Sendfile {
+            dest,
+            name,
+            timeout,
+            retries,
+            window,
+            file,
+        } => {
             let dest_u64: u64 = u64::from_str_radix(&dest, 16).expect("Invalid destination");
-            let maxpacketsize = xb.maxpacketsize;
+            let name = name.unwrap_or_else(|| {
+                file.file_name()
+                    .expect("Invalid file name")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let params = filexfer::XferParams {
+                timeout: Duration::from_secs(timeout),
+                retries,
+                window,
+            };
+            let maxframesize = xb.maxpacketsize.saturating_sub(xbpacket::header_len(channel));
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            let res = filexfer::sendfile(
+                framerx,
+                dest_u64,
+                &file,
+                &name,
+                maxframesize,
+                params,
+                sender.clone(),
+            );
+            sender.send(xb::XBTX::Shutdown).unwrap();
+            let _ = writerthread.join();
+            exit_on_error(res);
+        }
+        Command::Recvfile {
+            from,
+            dir,
+            overwrite,
+            max_size,
+            timeout,
+        } => {
+            let from_u64 = from.map(|f| u64::from_str_radix(&f, 16).expect("Invalid sender"));
+            let mymac = xb.mymac;
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            let res = filexfer::recvfile(
+                framerx,
+                &dir,
+                from_u64,
+                overwrite,
+                max_size,
+                optional_secs(timeout),
+                mymac,
+                sender.clone(),
+            );
+            sender.send(xb::XBTX::Shutdown).unwrap();
+            let _ = writerthread.join();
+            exit_on_error(res);
+        }
+        Command::Distribute {
+            recipient,
+            name,
+            round_timeout,
+            deadline,
+            file,
+        } => {
+            let recipients: Vec<u64> = recipient
+                .iter()
+                .map(|r| u64::from_str_radix(r, 16).expect("Invalid recipient"))
+                .collect();
+            let name = name.unwrap_or_else(|| {
+                file.file_name()
+                    .expect("Invalid file name")
+                    .to_string_lossy()
+                    .into_owned()
+            });
+            let params = filedist::DistParams {
+                round_timeout: Duration::from_secs(round_timeout),
+                deadline: Duration::from_secs(deadline),
+            };
+            let maxframesize = xb.maxpacketsize - xbpacket::header_len(channel);
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            let res = filedist::distribute(
+                framerx,
+                &file,
+                &name,
+                maxframesize,
+                &recipients,
+                params,
+                sender.clone(),
+            );
+            sender.send(xb::XBTX::Shutdown).unwrap();
+            let _ = writerthread.join();
+            exit_on_error(res);
+        }
+        Command::Chat { nick } => {
+            let nick = nick
+                .or_else(|| std::env::var("USER").ok())
+                .unwrap_or_else(|| String::from("anonymous"));
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
             thread::spawn(move || {
-                pipe::stdout_processor(&mut xbreframer, &mut xb.ser_reader)
-                    .expect("Failure in stdout_processor")
+                chat::stdout_processor(framerx).expect("Failure in chat stdout_processor")
             });
-            pipe::stdin_processor(dest_u64, maxpacketsize - 1, xbeesender)
-                .expect("Failure in stdin_processor");
+            chat::stdin_processor(nick, xb.maxpacketsize, channel, sender)
+                .expect("Failure in chat stdin_processor");
             // Make sure queued up data is sent
             let _ = writerthread.join();
         }
+        Command::Broker { socket } => {
+            exit_on_error(broker::broker(xb.ser_reader, &socket, xbeesender));
+        }
+        Command::Monitor { hex } => {
+            exit_on_error(monitor::monitor(xb.ser_reader, hex));
+        }
+        Command::Discover { neighbors, timeout } => {
+            let (sender, _framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            exit_on_error(discovery::discover(
+                &sender,
+                if neighbors { "FN" } else { "ND" },
+                Duration::from_secs_f64(timeout),
+            ));
+        }
+        Command::At { command, parameter } => {
+            let parameter = parameter.map_or_else(Vec::new, |p| {
+                hex::decode(p).expect("Invalid parameter; it must be given in hex")
+            });
+            let mut ser_reader = xb.ser_reader;
+            let res = xb::atframe(1, &command, &parameter).and_then(|frame| {
+                xbeesender.send(xb::XBTX::TXRaw(frame)).unwrap();
+                xb::atresponse(&mut ser_reader, 1)
+            });
+            match res {
+                Ok(data) if data.is_empty() => println!("OK"),
+                Ok(data) => println!("{}", hex::encode_upper(&data)),
+                Err(e) => exit_on_error(Err(e)),
+            }
+        }
+        Command::Daemon { .. } | Command::Decode { .. } | Command::Enqueue { .. } => unreachable!(),
+        Command::Forward {
+            spool,
+            retry_interval,
+        } => {
+            let (sender, framerx) =
+                open_single(xbeesender, xbreframer, xb.ser_reader, channel, pong_channel);
+            spool::forward(
+                &spool,
+                xb.maxpacketsize,
+                channel,
+                optional_secs(retry_interval),
+                sender,
+                framerx,
+            )
+            .expect("Failure in forward");
+        }
         Command::Tap {
             broadcast_unknown,
             broadcast_everything,
             iface_name,
+            beacon,
         } => {
+            if beacon.beacon_interval > 0 && beacon.beacon_channel == channel {
+                eprintln!("xbnet: --beacon-channel must differ from --channel");
+                process::exit(1);
+            }
             let tap_reader = tap::XBTap::new_tap(
                 xb.mymac,
                 broadcast_unknown,
@@ -188,14 +1179,28 @@ fn main() {
                 iface_name,
             )
             .expect("Failure initializing tap");
+            if let Some(control) = &control {
+                control.attach_tap(&tap_reader);
+            }
             let tap_writer = tap_reader.clone();
+            let ((sender, framerx), beaconservice) = open_with_beacon(
+                xbeesender,
+                xbreframer,
+                xb.ser_reader,
+                channel,
+                pong_channel,
+                &beacon,
+            );
+            if let Some(service) = beaconservice {
+                start_beacon(beacon, xb.mymac, None, Some(tap_reader.clone()), service);
+            }
             thread::spawn(move || {
                 tap_writer
-                    .frames_from_xb_processor(&mut xbreframer, &mut xb.ser_reader)
+                    .frames_from_xb_processor(framerx)
                     .expect("Failure in frames_from_xb_processor");
             });
             tap_reader
-                .frames_from_tap_processor(xbeesender)
+                .frames_from_tap_processor(sender)
                 .expect("Failure in frames_from_tap_processor");
             // Make sure queued up data is sent
             let _ = writerthread.join();
@@ -206,22 +1211,42 @@ fn main() {
             max_ip_cache,
             disable_ipv4,
             disable_ipv6,
+            beacon,
         } => {
+            if beacon.beacon_interval > 0 && beacon.beacon_channel == channel {
+                eprintln!("xbnet: --beacon-channel must differ from --channel");
+                process::exit(1);
+            }
             let max_ip_cache = Duration::from_secs(max_ip_cache);
             let tun_reader =
                 tun::XBTun::new_tun(xb.mymac, broadcast_everything, iface_name, max_ip_cache, disable_ipv4, disable_ipv6)
                     .expect("Failure initializing tun");
+            if let Some(control) = &control {
+                control.attach_tun(&tun_reader);
+            }
             let tun_writer = tun_reader.clone();
+            let ((sender, framerx), beaconservice) = open_with_beacon(
+                xbeesender,
+                xbreframer,
+                xb.ser_reader,
+                channel,
+                pong_channel,
+                &beacon,
+            );
+            if let Some(service) = beaconservice {
+                start_beacon(beacon, xb.mymac, Some(tun_reader.clone()), None, service);
+            }
             thread::spawn(move || {
                 tun_writer
-                    .frames_from_xb_processor(&mut xbreframer, &mut xb.ser_reader)
+                    .frames_from_xb_processor(framerx)
                     .expect("Failure in frames_from_xb_processor");
             });
             tun_reader
-                .frames_from_tun_processor(xbeesender)
+                .frames_from_tun_processor(sender)
                 .expect("Failure in frames_from_tap_processor");
             // Make sure queued up data is sent
             let _ = writerthread.join();
         }
     }
+    stats::exiting();
 }
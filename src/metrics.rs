@@ -0,0 +1,233 @@
+/*! Exporting statistics to Prometheus over HTTP */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Only enough of HTTP is implemented for a scraper: every GET request is answered
+with the metrics, in the Prometheus text exposition format, and the connection is
+closed. */
+
+use crate::control::Control;
+use crate::stats::{self, Counters};
+use log::*;
+use std::fmt::Write as FmtWrite;
+use std::io;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Accumulates metrics in the text exposition format.
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    /// Start a metric family.
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        writeln!(self.out, "# HELP xbnet_{} {}", name, help).unwrap();
+        writeln!(self.out, "# TYPE xbnet_{} {}", name, kind).unwrap();
+    }
+
+    /// Add a sample to the current family.  labels is empty, or like `peer="..."`.
+    fn sample<T: std::fmt::Display>(&mut self, name: &str, labels: &str, value: T) {
+        if labels.is_empty() {
+            writeln!(self.out, "xbnet_{} {}", name, value).unwrap();
+        } else {
+            writeln!(self.out, "xbnet_{}{{{}}} {}", name, labels, value).unwrap();
+        }
+    }
+
+    /// A family with a single sample.
+    fn single<T: std::fmt::Display>(&mut self, name: &str, kind: &str, help: &str, value: T) {
+        self.family(name, kind, help);
+        self.sample(name, "", value);
+    }
+}
+
+/// The counters common to the totals and each peer, as (name, help, value)
+fn counters(c: &Counters) -> [(&'static str, &'static str, u64); 7] {
+    [
+        ("frames_received_total", "Frames received", c.frames_in),
+        ("bytes_received_total", "Bytes of frames received", c.bytes_in),
+        ("frames_sent_total", "Frames sent", c.frames_out),
+        ("bytes_sent_total", "Bytes of frames sent", c.bytes_out),
+        ("packets_received_total", "Radio packets received", c.packets_in),
+        ("packets_sent_total", "Radio packets sent", c.packets_out),
+        ("tx_retries_total", "Retransmissions reported by the radio", c.tx_retries),
+    ]
+}
+
+/// Render all metrics.
+fn render(control: &Control) -> String {
+    let s = stats::snapshot();
+    let mut e = Exposition { out: String::new() };
+
+    e.single(
+        "radio_initialized",
+        "gauge",
+        "Whether the radio has been initialized",
+        u8::from(s.radio.is_some()),
+    );
+    if let Some((mymac, maxpacketsize)) = s.radio {
+        e.family("radio_info", "gauge", "The 64-bit address of the radio");
+        e.sample("radio_info", &format!("mac=\"{:016x}\"", mymac), 1);
+        e.single(
+            "radio_max_packet_size_bytes",
+            "gauge",
+            "The largest payload the radio accepts in one packet",
+            maxpacketsize,
+        );
+    }
+    if let Some((len, cap)) = control.queue() {
+        e.single("tx_queue_depth", "gauge", "Frames waiting to be sent to the radio", len);
+        if let Some(cap) = cap {
+            e.single("tx_queue_capacity", "gauge", "Frames that fit in the transmit queue", cap);
+        }
+    }
+    if let Some(len) = control.ip_cache_len() {
+        e.single("ip_cache_entries", "gauge", "Entries in the tun IP address cache", len);
+    }
+    if let Some(len) = control.mac_cache_len() {
+        e.single("mac_cache_entries", "gauge", "Entries in the tap MAC address cache", len);
+    }
+
+    for (name, help, value) in counters(&s.total).iter() {
+        e.single(name, "counter", help, value);
+    }
+    e.family(
+        "tx_status_total",
+        "counter",
+        "Transmit reports from the radio, by delivery status (0 is success)",
+    );
+    for (status, n) in s.tx_statuses.iter() {
+        e.sample("tx_status_total", &format!("status=\"{}\"", status), n);
+    }
+    e.single(
+        "reassembly_failures_total",
+        "counter",
+        "Partial frames discarded because a packet never arrived",
+        s.reassembly_failures,
+    );
+    e.single(
+        "checksum_errors_total",
+        "counter",
+        "API frames from the radio with a bad checksum",
+        s.checksum_errors,
+    );
+    e.single(
+        "junk_bytes_total",
+        "counter",
+        "Bytes from the radio outside of any API frame",
+        s.junk_bytes,
+    );
+    e.family("drops_total", "counter", "Packets and frames discarded, by reason");
+    for (reason, n) in s.drops.iter() {
+        e.sample("drops_total", &format!("reason=\"{}\"", reason), n);
+    }
+
+    for (i, (name, help, _)) in counters(&s.total).iter().enumerate() {
+        let name = format!("peer_{}", name);
+        e.family(&name, "counter", &format!("{}, by peer", help));
+        for (addr, peer) in s.peers.iter() {
+            let value = counters(&peer.counters)[i].2;
+            e.sample(&name, &format!("peer=\"{:016x}\"", addr), value);
+        }
+    }
+    e.family(
+        "peer_tx_status_total",
+        "counter",
+        "Transmit reports from the radio, by peer and outcome",
+    );
+    for (addr, peer) in s.peers.iter() {
+        let ok = format!("peer=\"{:016x}\",outcome=\"ok\"", addr);
+        let failed = format!("peer=\"{:016x}\",outcome=\"failed\"", addr);
+        e.sample("peer_tx_status_total", &ok, peer.counters.tx_ok);
+        e.sample("peer_tx_status_total", &failed, peer.counters.tx_failed);
+    }
+    e.family(
+        "peer_last_seen_timestamp_seconds",
+        "gauge",
+        "When a packet was last received from the peer",
+    );
+    for (addr, peer) in s.peers.iter() {
+        if let Some(t) = peer.last_seen.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+            let labels = format!("peer=\"{:016x}\"", addr);
+            e.sample("peer_last_seen_timestamp_seconds", &labels, t.as_secs());
+        }
+    }
+    e.family(
+        "peer_rssi_dbm",
+        "gauge",
+        "Signal strength of the last packet received from the peer",
+    );
+    for (addr, peer) in s.peers.iter() {
+        if let Some(rssi) = peer.last_rssi {
+            let labels = format!("peer=\"{:016x}\"", addr);
+            e.sample("peer_rssi_dbm", &labels, -i32::from(rssi));
+        }
+    }
+    e.out
+}
+
+/// Answer one HTTP request.
+fn respond(control: &Control, mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    // Read the request head; its contents don't matter beyond the method.
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|w| w == b"\r\n\r\n") && head.len() < 16384 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    let (status, body) = if head.starts_with(b"GET ") {
+        ("200 OK", render(control))
+    } else {
+        ("405 Method Not Allowed", String::new())
+    };
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+/// Serve metrics over HTTP at address, such as 127.0.0.1:9101, in a new thread.
+pub fn serve(address: &str, control: Arc<Control>) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    info!("METRICS: listening on {}", address);
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(&control, stream) {
+                        debug!("METRICS: request failed: {}", e);
+                    }
+                }
+                Err(e) => warn!("METRICS: failed to accept connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
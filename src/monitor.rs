@@ -0,0 +1,307 @@
+/*! Displaying everything the radio receives */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Nothing is transmitted, so through a broker the monitor sees the traffic of the
+other clients without disturbing it.  RSSI is shown only where the radio supplies it
+unasked: in the 0x80 and 0x81 receive frames of 802.15.4 firmware, and in the
+responses to ATDB queries made by others. */
+
+use crate::ser::*;
+use crate::tun::extract_ips;
+use crate::xbpacket::*;
+use crate::xbrx::*;
+use bytes::Bytes;
+use chrono::Local;
+use etherparse::*;
+use std::convert::TryInto;
+use std::io;
+
+/// Describe a completed xbnet frame, guessing from its contents whether it came
+/// from tun (IP) or tap (Ethernet).
+pub fn dissect(data: &[u8]) -> String {
+    let version = data.first().map(|b| b >> 4);
+    if version == Some(4) || version == Some(6) {
+        if let Ok(packet) = SlicedPacket::from_ip(data) {
+            return describe_ip(&packet);
+        }
+    }
+    if let Ok(packet) = SlicedPacket::from_ethernet(data) {
+        if let Some(LinkSlice::Ethernet2(header)) = &packet.link {
+            let mut out = format!(
+                "Ethernet {} -> {} type {:04x}",
+                crate::tap::showmac(header.source().try_into().unwrap()),
+                crate::tap::showmac(header.destination().try_into().unwrap()),
+                header.ether_type()
+            );
+            if packet.ip.is_some() {
+                out.push_str(", ");
+                out.push_str(&describe_ip(&packet));
+            }
+            return out;
+        }
+    }
+    String::from("not IP or Ethernet")
+}
+
+fn describe_ip(packet: &SlicedPacket) -> String {
+    let (source, destination) = match extract_ips(packet) {
+        Some(ips) => ips,
+        None => return String::from("no IP header"),
+    };
+    let (version, protocol) = match &packet.ip {
+        Some(InternetSlice::Ipv4(header)) => ("IPv4", header.protocol()),
+        Some(InternetSlice::Ipv6(header, _)) => ("IPv6", header.next_header()),
+        None => unreachable!(),
+    };
+    let transport = match &packet.transport {
+        Some(TransportSlice::Udp(udp)) => format!(
+            "UDP {} -> {}, {} bytes",
+            udp.source_port(),
+            udp.destination_port(),
+            packet.payload.len()
+        ),
+        Some(TransportSlice::Tcp(tcp)) => {
+            let flags: Vec<&str> = [
+                (tcp.syn(), "SYN"),
+                (tcp.ack(), "ACK"),
+                (tcp.fin(), "FIN"),
+                (tcp.rst(), "RST"),
+                (tcp.psh(), "PSH"),
+            ]
+            .iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, name)| *name)
+            .collect();
+            format!(
+                "TCP {} -> {} [{}] seq {}, {} bytes",
+                tcp.source_port(),
+                tcp.destination_port(),
+                flags.join(","),
+                tcp.sequence_number(),
+                packet.payload.len()
+            )
+        }
+        None => format!("protocol {}, {} bytes", protocol, packet.payload.len()),
+    };
+    format!("{} {} -> {} {}", version, source, destination, transport)
+}
+
+/// The meaning of a delivery status from an extended TX status frame
+fn delivery_status(status: u8) -> &'static str {
+    match status {
+        0x00 => "success",
+        0x01 => "MAC ACK failure",
+        0x02 => "CCA failure",
+        0x21 => "network ACK failure",
+        0x22 => "not joined to network",
+        0x24 => "address not found",
+        0x25 => "route not found",
+        0x31 => "internal resource error",
+        0x74 => "payload too large",
+        _ => "failure",
+    }
+}
+
+/// Describes API frames, reassembling the xbnet frames carried by their packets.
+pub struct Decoder {
+    rx: XBReframer,
+    /// Transmit requests are reassembled separately, keyed by destination.
+    tx: XBReframer,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            rx: XBReframer::new(),
+            tx: XBReframer::new(),
+        }
+    }
+
+    /** Describe the inside of an API frame, as from rxapiframe, as one or more lines.
+    Frames to and from the radio are both understood. */
+    pub fn describe(&mut self, inner: &Bytes) -> Vec<String> {
+        describe(inner, &mut self.rx, &mut self.tx)
+    }
+}
+
+/// Describe the packet in an RX or TX frame, and the frame it completes, if any.
+fn describe_packet(reframer: &mut XBReframer, packet: RXPacket, out: &mut Vec<String>) {
+    match parse_header(&packet.payload) {
+        Some((remaining, channel, _)) => out.push(format!(
+            "    xbnet channel {}, {} packets remaining",
+            channel, remaining
+        )),
+        None => out.push(String::from("    no xbnet header")),
+    }
+    if let Some((_, _, _, frame)) = reframer.reassemble(packet) {
+        out.push(format!("    frame of {} bytes: {}", frame.len(), dissect(&frame)));
+    }
+}
+
+/** Describe an API frame, as from rxapiframe.  Receive packets are passed through
+rx, and transmit requests through tx, and the frames they complete dissected. */
+fn describe(inner: &Bytes, rx: &mut XBReframer, tx: &mut XBReframer) -> Vec<String> {
+    let frametype = inner[0];
+    // parseframe checks the lengths of the frames it interprets.
+    let short = match frametype {
+        0x10 => inner.len() < 14,
+        0x08 => inner.len() < 4,
+        _ => false,
+    };
+    if short {
+        return vec![format!("FRAME {:02X}, too short at {} bytes", frametype, inner.len())];
+    }
+
+    match frametype {
+        0x10 => {
+            let mut dest64 = [0u8; 8];
+            dest64.copy_from_slice(&inner[2..10]);
+            let dest64 = u64::from_be_bytes(dest64);
+            let options = inner[13];
+            let mut out = vec![format!(
+                "TX REQUEST frame {} to {:016x}/{} radius {} options {:02x}{}, {} bytes",
+                inner[1],
+                dest64,
+                hex::encode(&inner[10..12]),
+                inner[12],
+                options,
+                if options & 0x01 != 0 { " (no ACK)" } else { "" },
+                inner.len() - 14
+            )];
+            let packet = RXPacket {
+                sender_addr64: dest64,
+                sender_addr16: u16::from_be_bytes([inner[10], inner[11]]),
+                rx_options: options,
+                payload: inner.slice(14..),
+            };
+            describe_packet(tx, packet, &mut out);
+            out
+        }
+        0x08 => {
+            let mut line = format!(
+                "AT COMMAND frame {} {}",
+                inner[1],
+                String::from_utf8_lossy(&inner[2..4])
+            );
+            if inner.len() > 4 {
+                line.push_str(&format!(" parameter {}", hex::encode_upper(&inner[4..])));
+            }
+            vec![line]
+        }
+        0x80 | 0x81 if inner.len() >= if frametype == 0x80 { 11 } else { 5 } => {
+            let (sender, rest) = if frametype == 0x80 {
+                (hex::encode(&inner[1..9]), &inner[9..])
+            } else {
+                (hex::encode(&inner[1..3]), &inner[3..])
+            };
+            vec![format!(
+                "RX (802.15.4) from {} RSSI -{} dBm options {:02x}, {} bytes",
+                sender,
+                rest[0],
+                rest[1],
+                rest.len() - 2
+            )]
+        }
+        0x8A if inner.len() == 2 => {
+            let meaning = match inner[1] {
+                0 => "hardware reset",
+                1 => "watchdog timer reset",
+                2 => "joined network",
+                3 => "disassociated",
+                6 => "coordinator started",
+                _ => "other",
+            };
+            vec![format!("MODEM STATUS {:02X} ({})", inner[1], meaning)]
+        }
+        _ => match parseframe(inner.clone()) {
+            RXFrame::RX(packet) => {
+                let mut out = vec![format!(
+                    "RX from {:016x}/{:04x} options {:02x}{}, {} bytes",
+                    packet.sender_addr64,
+                    packet.sender_addr16,
+                    packet.rx_options,
+                    if packet.rx_options & 0x02 != 0 { " (broadcast)" } else { "" },
+                    packet.payload.len()
+                )];
+                describe_packet(rx, packet, &mut out);
+                out
+            }
+            RXFrame::TXStatus(status) => vec![format!(
+                "TX STATUS frame {} to {:04x} retries {} delivery {:02X} ({}) discovery {:02X}",
+                status.frame_id,
+                status.dest_addr_16,
+                status.tx_retry_count,
+                status.delivery_status,
+                delivery_status(status.delivery_status),
+                status.discovery_status
+            )],
+            RXFrame::ATResponse(resp) => {
+                let mut line = format!(
+                    "AT RESPONSE frame {} {} status {} data {}",
+                    resp.frame_id,
+                    String::from_utf8_lossy(&resp.command),
+                    resp.status,
+                    hex::encode_upper(&resp.data)
+                );
+                if &resp.command == b"DB" && resp.data.len() == 1 {
+                    line.push_str(&format!(" (RSSI -{} dBm)", resp.data[0]));
+                }
+                vec![line]
+            }
+            RXFrame::Other(frametype, data) => {
+                vec![format!("FRAME {:02X}, {} bytes", frametype, data.len())]
+            }
+            RXFrame::Malformed(data) => vec![format!(
+                "FRAME {:02X}, too short at {} bytes",
+                frametype,
+                data.len()
+            )],
+        },
+    }
+}
+
+/// Print every API frame received from ser until it fails.  If showhex, also print the raw frame.
+pub fn monitor(mut ser: XBSerReader, showhex: bool) -> io::Result<()> {
+    let mut decoder = Decoder::new();
+    loop {
+        let frame = rxapiframe(&mut ser)?;
+        let time = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        let inner = match frame {
+            Some(inner) if !inner.is_empty() => inner,
+            Some(_) => {
+                println!("{} empty frame", time);
+                continue;
+            }
+            None => {
+                println!("{} checksum mismatch", time);
+                continue;
+            }
+        };
+        let mut lines = decoder.describe(&inner).into_iter();
+        if let Some(first) = lines.next() {
+            println!("{} {}", time, first);
+        }
+        for line in lines {
+            println!("{}", line);
+        }
+        if showhex {
+            println!("    hex {}", hex::encode(&inner));
+        }
+    }
+}
@@ -0,0 +1,191 @@
+/*! Sharing one radio among several services by channel */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Every xbnet frame is sent on a channel, 0 through 255, given in the packet header
+(see xbpacket::HEADER_CHANNEL_FLAG).  A service opens a channel with the Dispatcher,
+and gets back a sender and a receiver that look just like the ones it would have
+had with the radio to itself: data sent with XBTX::TXData goes out on its channel,
+and only frames received on its channel are delivered to it. */
+
+use crate::ser::*;
+use crate::stats;
+use crate::xb::*;
+use crate::xbrx::*;
+use bytes::*;
+use log::*;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The channel used when none is given, and by versions of xbnet without channels
+pub const DEFAULT_CHANNEL: u8 = 0;
+
+/// The received frames that may wait for a service before more are dropped
+const SERVICE_QUEUE: usize = 16;
+
+/// The least time between ATDB queries for the RSSI of frames from one peer
+const RSSI_INTERVAL: Duration = Duration::from_millis(250);
+
+/** A service's end of a channel: a sender to transmit with, and a receiver of the
+frames (sender u64, sender u16, payload) that arrive on the channel */
+pub type Service = (
+    crossbeam_channel::Sender<XBTX>,
+    crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+);
+
+/// Routes received frames to the service on their channel.
+pub struct Dispatcher {
+    writer: crossbeam_channel::Sender<XBTX>,
+
+    /// The frame receiver of each service, by channel
+    routes: HashMap<u8, crossbeam_channel::Sender<(u64, u16, Bytes)>>,
+
+    /// The number of services that have not yet shut down
+    open_services: Arc<AtomicUsize>,
+}
+
+impl Dispatcher {
+    pub fn new(writer: crossbeam_channel::Sender<XBTX>) -> Self {
+        Dispatcher {
+            writer,
+            routes: HashMap::new(),
+            open_services: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /** Open a channel for a service.  When the service sends XBTX::Shutdown, or drops
+    its sender, it is closed; the writer thread is shut down once every service is
+    closed. */
+    pub fn open(&mut self, channel: u8) -> io::Result<Service> {
+        self.open_service(channel, true)
+    }
+
+    /** Open a channel for a service that runs alongside the others, such as a ping
+    responder: the writer thread is shut down when the others close, whether or not
+    this one has. */
+    pub fn open_auxiliary(&mut self, channel: u8) -> io::Result<Service> {
+        self.open_service(channel, false)
+    }
+
+    fn open_service(&mut self, channel: u8, counted: bool) -> io::Result<Service> {
+        if self.routes.contains_key(&channel) {
+            return Err(mkerror(&format!("Channel {} is already in use", channel)));
+        }
+        let (frametx, framerx) = crossbeam_channel::bounded(SERVICE_QUEUE);
+        self.routes.insert(channel, frametx);
+
+        let (svctx, svcrx) = crossbeam_channel::bounded(5);
+        let writer = self.writer.clone();
+        let open_services = self.open_services.clone();
+        if counted {
+            open_services.fetch_add(1, Ordering::SeqCst);
+        }
+        thread::spawn(move || {
+            for item in svcrx.iter() {
+                let item = match item {
+                    XBTX::TXData(dest, data) => XBTX::TXChannelData(dest, channel, data),
+                    XBTX::TXChannelData(dest, _, data) => XBTX::TXChannelData(dest, channel, data),
+                    XBTX::TXTracked(dest, _, data, tracker) => {
+                        XBTX::TXTracked(dest, channel, data, tracker)
+                    }
+                    XBTX::TXRaw(frame) => XBTX::TXRaw(frame),
+                    XBTX::Shutdown => break,
+                };
+                if writer.send(item).is_err() {
+                    return;
+                }
+            }
+            debug!("MUX: service on channel {} closed", channel);
+            if counted && open_services.fetch_sub(1, Ordering::SeqCst) == 1 {
+                let _ = writer.send(XBTX::Shutdown);
+            }
+        });
+        Ok((svctx, framerx))
+    }
+
+    /** Receive frames until reading from the radio fails, passing each to the service on
+    its channel.  A service with SERVICE_QUEUE frames still waiting misses the frame,
+    rather than holding up the others.  If stats::wants_rssi, the radio is also asked
+    for the RSSI of a frame, at most every RSSI_INTERVAL for each peer and only when
+    nothing else is waiting to be sent; the reader records the answer in stats. */
+    pub fn run(self, mut xbreframer: XBReframer, mut ser: XBSerReader) {
+        let mut rssiasked: HashMap<u64, Instant> = HashMap::new();
+        let mut rssiframeid: u8 = 0;
+        loop {
+            let (fromu64, fromu16, channel, payload) = match xbreframer.rxframe(&mut ser) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    error!("MUX: failed to read from the radio: {}", e);
+                    return;
+                }
+            };
+            let recent = rssiasked
+                .get(&fromu64)
+                .is_some_and(|asked| asked.elapsed() < RSSI_INTERVAL);
+            // Only ask while nothing is waiting to be sent, so the query never takes a
+            // place in the queue that data needs; a later frame will ask again.
+            if stats::wants_rssi() && !recent && self.writer.is_empty() {
+                rssiframeid = if rssiframeid == u8::MAX {
+                    1
+                } else {
+                    rssiframeid + 1
+                };
+                let query = atframe(rssiframeid, "DB", &[]).unwrap();
+                if self.writer.try_send(XBTX::TXRaw(query)).is_ok() {
+                    stats::rssi_query(rssiframeid, fromu64);
+                    rssiasked.insert(fromu64, Instant::now());
+                }
+            }
+            match self.routes.get(&channel) {
+                Some(frametx) => match frametx.try_send((fromu64, fromu16, payload)) {
+                    Ok(()) => (),
+                    Err(crossbeam_channel::TrySendError::Full(_)) => {
+                        debug!(
+                            "MUX: service on channel {} is behind; dropping frame",
+                            channel
+                        );
+                        stats::dropped(stats::DROP_SERVICE_BUSY);
+                    }
+                    Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
+                        debug!(
+                            "MUX: service on channel {} is gone; dropping frame",
+                            channel
+                        );
+                        stats::dropped(stats::DROP_SERVICE_GONE);
+                    }
+                },
+                None => {
+                    debug!(
+                        "MUX: dropping frame from {:x} on unused channel {}",
+                        fromu64, channel
+                    );
+                    stats::dropped(stats::DROP_UNUSED_CHANNEL);
+                }
+            }
+        }
+    }
+
+    /// Run in a new thread.
+    pub fn spawn(self, xbreframer: XBReframer, ser: XBSerReader) -> thread::JoinHandle<()> {
+        thread::spawn(move || self.run(xbreframer, ser))
+    }
+}
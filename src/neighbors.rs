@@ -0,0 +1,264 @@
+/*! The quality of the link to each neighboring radio */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* A neighbor is a radio we have heard from, or sent to and had a transmit report
+about.  stats feeds the table from the same places it counts, so like stats it is one
+global.  The delivery ratio and retries are moving averages over the transmit reports,
+and so are only known with --request-xbee-tx-reports.
+
+A link is down once its delivery ratio falls below DOWN_BELOW over at least
+MIN_REPORTS reports.  tun and tap then stop unicasting to it, as the radio can lock up
+when sent too much for a radio that is not there.  Hearing from the neighbor again
+brings the link up, with its delivery ratio started afresh.
+
+Node discovery, answered in the receive path as ATDB is, and beacons fill in names,
+device types, and more RSSI readings. */
+
+use crate::events;
+use crate::xbpacket::NodeInfo;
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// The weight of each new transmit report in the moving averages
+const ALPHA: f64 = 0.125;
+
+/// The delivery ratio below which a link is down
+const DOWN_BELOW: f64 = 0.25;
+
+/// The transmit reports needed before a link can be judged down
+const MIN_REPORTS: u64 = 8;
+
+/// The 64-bit address used for broadcasts, which is no neighbor
+const BROADCAST: u64 = 0xffff;
+
+#[derive(Clone, Debug)]
+pub struct Neighbor {
+    /// The name it goes by, once it has told us
+    pub name: Option<String>,
+
+    /// Its device type, as from node discovery
+    pub device_type: Option<u8>,
+
+    /// When node discovery last found it
+    pub discovered: Option<SystemTime>,
+
+    /// When a packet was last received from it
+    pub last_seen: Option<SystemTime>,
+
+    /// The signal strength of the last packet received from it, in -dBm
+    pub last_rssi: Option<u8>,
+
+    /// The moving average of the packets to it that were delivered, from 0 to 1
+    pub delivery: Option<f64>,
+
+    /// The transmit reports since the delivery ratio was started
+    pub tx_reports: u64,
+
+    /// All the retransmissions reported for packets to it
+    pub retries: u64,
+
+    /// The moving average of the retransmissions of each packet
+    pub avg_retries: Option<f64>,
+
+    /// Whether tun and tap should avoid sending to it
+    pub down: bool,
+}
+
+impl Neighbor {
+    const fn new() -> Self {
+        Neighbor {
+            name: None,
+            device_type: None,
+            discovered: None,
+            last_seen: None,
+            last_rssi: None,
+            delivery: None,
+            tx_reports: 0,
+            retries: 0,
+            avg_retries: None,
+            down: false,
+        }
+    }
+
+    /// The neighbor as name=value pairs, as in the output of stats
+    pub fn describe(&self) -> String {
+        let last_seen = match self.last_seen {
+            Some(t) => DateTime::<Local>::from(t).format("%Y-%m-%dT%H:%M:%S").to_string(),
+            None => String::from("never"),
+        };
+        let rssi = match self.last_rssi {
+            Some(r) => format!("-{}", r),
+            None => String::from("none"),
+        };
+        let average = |value: Option<f64>| match value {
+            Some(v) => format!("{:.2}", v),
+            None => String::from("none"),
+        };
+        format!(
+            "name={} type={} last_seen={} rssi={} delivery={} tx_reports={} retries={} avg_retries={} link={}",
+            self.name.as_deref().unwrap_or("none"),
+            self.device_type.map_or_else(|| String::from("none"), device_type),
+            last_seen,
+            rssi,
+            average(self.delivery),
+            self.tx_reports,
+            self.retries,
+            average(self.avg_retries),
+            if self.down { "down" } else { "up" }
+        )
+    }
+}
+
+/// The name of a device type from node discovery
+pub fn device_type(t: u8) -> String {
+    match t {
+        0 => String::from("coordinator"),
+        1 => String::from("router"),
+        2 => String::from("end_device"),
+        t => t.to_string(),
+    }
+}
+
+static NEIGHBORS: Mutex<BTreeMap<u64, Neighbor>> = Mutex::new(BTreeMap::new());
+
+/// Move a moving average toward sample.
+fn average(value: Option<f64>, sample: f64) -> f64 {
+    match value {
+        Some(v) => v + ALPHA * (sample - v),
+        None => sample,
+    }
+}
+
+/// A packet was received from addr.
+pub fn heard(addr: u64) {
+    let up = {
+        let mut neighbors = NEIGHBORS.lock().unwrap();
+        let n = neighbors.entry(addr).or_insert_with(Neighbor::new);
+        n.last_seen = Some(SystemTime::now());
+        if n.down {
+            n.down = false;
+            n.delivery = None;
+            n.tx_reports = 0;
+            true
+        } else {
+            false
+        }
+    };
+    if up {
+        events::emit("link_up", vec![("addr", events::addr(addr))]);
+    }
+}
+
+/// The radio reported the RSSI of the last packet received from addr.
+pub fn rssi(addr: u64, value: u8) {
+    let mut neighbors = NEIGHBORS.lock().unwrap();
+    neighbors.entry(addr).or_insert_with(Neighbor::new).last_rssi = Some(value);
+}
+
+/// name with whitespace and control characters replaced, to keep the table readable
+fn clean(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_whitespace() || c.is_control() { '_' } else { c })
+        .collect()
+}
+
+/// addr goes by name.
+pub fn named(addr: u64, name: &str) {
+    let mut neighbors = NEIGHBORS.lock().unwrap();
+    neighbors.entry(addr).or_insert_with(Neighbor::new).name = Some(clean(name));
+}
+
+/** Node discovery found node.  A blank node identifier, which is the default, leaves
+any name from beacons alone. */
+pub fn discovered(node: &NodeInfo) {
+    let first = {
+        let mut neighbors = NEIGHBORS.lock().unwrap();
+        let n = neighbors.entry(node.addr64).or_insert_with(Neighbor::new);
+        if !node.name.trim().is_empty() {
+            n.name = Some(clean(&node.name));
+        }
+        n.device_type = Some(node.device_type);
+        if node.rssi.is_some() {
+            n.last_rssi = node.rssi;
+        }
+        n.discovered.replace(SystemTime::now()).is_none()
+    };
+    if first {
+        let mut fields = vec![
+            ("addr", events::addr(node.addr64)),
+            ("name", node.name.as_str().into()),
+            ("device_type", device_type(node.device_type).into()),
+        ];
+        if let Some(rssi) = node.rssi {
+            fields.push(("rssi", events::Field::from(rssi)));
+        }
+        events::emit("node_discovered", fields);
+    }
+}
+
+/// The radio reported on a packet sent to addr.
+pub fn tx_report(addr: u64, delivered: bool, retries: u8) {
+    if addr == BROADCAST {
+        return;
+    }
+    let down = {
+        let mut neighbors = NEIGHBORS.lock().unwrap();
+        let n = neighbors.entry(addr).or_insert_with(Neighbor::new);
+        n.tx_reports += 1;
+        n.retries += u64::from(retries);
+        let delivery = average(n.delivery, if delivered { 1.0 } else { 0.0 });
+        n.delivery = Some(delivery);
+        n.avg_retries = Some(average(n.avg_retries, f64::from(retries)));
+        if !n.down && n.tx_reports >= MIN_REPORTS && delivery < DOWN_BELOW {
+            n.down = true;
+            Some(delivery)
+        } else {
+            None
+        }
+    };
+    if let Some(delivery) = down {
+        events::emit(
+            "link_down",
+            vec![
+                ("addr", events::addr(addr)),
+                ("delivery_percent", events::Field::Num((delivery * 100.0) as u64)),
+            ],
+        );
+    }
+}
+
+/// Whether the link to addr is down, so that tun and tap should not send to it
+pub fn is_down(addr: u64) -> bool {
+    NEIGHBORS.lock().unwrap().get(&addr).is_some_and(|n| n.down)
+}
+
+/// A copy of the table as it stands now
+pub fn snapshot() -> BTreeMap<u64, Neighbor> {
+    NEIGHBORS.lock().unwrap().clone()
+}
+
+/// The table as lines of text, one per neighbor.
+pub fn report() -> Vec<String> {
+    snapshot()
+        .iter()
+        .map(|(addr, n)| format!("neighbor {:016x}: {}", addr, n.describe()))
+        .collect()
+}
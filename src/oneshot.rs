@@ -0,0 +1,136 @@
+/*! One-shot transmission and reception, for use from scripts */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+use crate::ser::*;
+use crate::xb::*;
+use crate::xbpacket::*;
+use crate::xbrx::*;
+use bytes::*;
+use log::*;
+use std::collections::BTreeSet;
+use std::io;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/** Transmit a single message on channel.  If confirm_timeout is given, wait up to that long for
+a successful TX status report for every packet of the message, ignoring reports for
+anything else sent through the radio; the caller must have requested XBee TX reports.  Otherwise, return as soon as the message is queued.  Either
+way, the writer thread is told to shut down after the message. */
+pub fn send(
+    mut ser: XBSerReader,
+    dest: u64,
+    channel: u8,
+    data: Bytes,
+    maxpacketsize: usize,
+    confirm_timeout: Option<Duration>,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    if data.is_empty() {
+        return Err(mkerror("Refusing to send an empty message"));
+    }
+    if data.len() > max_frame_len(maxpacketsize, channel) {
+        return Err(mkerror(&format!(
+            "Message of {} bytes is too large to send; at most {} bytes fit in one frame",
+            data.len(),
+            max_frame_len(maxpacketsize, channel)
+        )));
+    }
+    let packetcount = packet_count(maxpacketsize, channel, data.len());
+
+    let timeout = match confirm_timeout {
+        Some(timeout) => timeout,
+        None => {
+            sender
+                .send(XBTX::TXChannelData(XBDestAddr::U64(dest), channel, data))
+                .unwrap();
+            sender.send(XBTX::Shutdown).unwrap();
+            return Ok(());
+        }
+    };
+
+    // Reading the radio is what passes the TX statuses of our packets to the tracker.
+    thread::spawn(move || loop {
+        if let Err(e) = rxxbframe(&mut ser) {
+            debug!("SEND: failed to read from the radio: {}", e);
+            return;
+        }
+    });
+
+    let (statustx, statusrx) = crossbeam_channel::unbounded();
+    sender
+        .send(XBTX::TXTracked(
+            XBDestAddr::U64(dest),
+            channel,
+            data,
+            statustx,
+        ))
+        .unwrap();
+    sender.send(XBTX::Shutdown).unwrap();
+
+    let deadline = Instant::now() + timeout;
+    let mut frame_ids = BTreeSet::new();
+    while frame_ids.len() < packetcount {
+        match statusrx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok((frame_id, delivery_status)) => {
+                if !frame_ids.insert(frame_id) {
+                    debug!("SEND: duplicate TX status for frame ID {:X}", frame_id);
+                    continue;
+                }
+                if delivery_status != 0 {
+                    return Err(mkerror(&format!(
+                        "Delivery failed with status {:X}",
+                        delivery_status
+                    )));
+                }
+            }
+            Err(_) => return Err(mkerror("Timed out waiting for TX status")),
+        }
+    }
+    debug!("All {} packets confirmed by radio", packetcount);
+    Ok(())
+}
+
+/** Wait for a single message, optionally only from the given sender, and write it to
+stdout.  If timeout is given, return an error if nothing arrives in that time. */
+pub fn recv(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    from: Option<u64>,
+    timeout: Option<Duration>,
+) -> io::Result<()> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    let (fromu64, payload) = loop {
+        let (fromu64, _fromu16, payload) = match deadline {
+            None => framerx.recv().map_err(|e| mkerror(&e.to_string()))?,
+            Some(d) => framerx
+                .recv_timeout(d.saturating_duration_since(Instant::now()))
+                .map_err(|_| mkerror("Timed out waiting for a message"))?,
+        };
+        if from.is_none() || from == Some(fromu64) {
+            break (fromu64, payload);
+        }
+        debug!("Ignoring message from {:x}", fromu64);
+    };
+    debug!("Received {} bytes from {:x}", payload.len(), fromu64);
+
+    let mut stdout = io::stdout();
+    stdout.write_all(&payload)?;
+    stdout.flush()
+}
@@ -0,0 +1,171 @@
+/*! Capturing the packets crossing the radio to a pcap file */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The radio never shows us its over-the-air frames, so each transmit request (0x10)
+and receive packet (0x90) is written as the IEEE 802.15.4 data frame that would carry
+it, without FCS, which Wireshark and tcpdump already decode.  The MAC header holds
+the 64-bit source and destination, or the 16-bit broadcast address 0xFFFF, and the
+payload is the xbnet packet, header included.  The PAN ID is always 0xFFFF, and the
+sequence number counts the packets in the file. */
+
+use log::*;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// LINKTYPE_IEEE802_15_4_NOFCS
+const LINKTYPE: u32 = 230;
+const SNAPLEN: u32 = 65535;
+
+/// The 16-bit address for broadcasts
+const BROADCAST: u16 = 0xFFFF;
+
+struct Capture {
+    file: BufWriter<File>,
+    /// The 64-bit address of our radio, once known
+    local: u64,
+    seq: u8,
+}
+
+static CAPTURE: Mutex<Option<Capture>> = Mutex::new(None);
+
+/// Start capturing to the file at path, replacing it.
+pub fn open(path: &Path) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&0xa1b2c3d4u32.to_le_bytes())?;
+    file.write_all(&2u16.to_le_bytes())?;
+    file.write_all(&4u16.to_le_bytes())?;
+    file.write_all(&0i32.to_le_bytes())?; // thiszone
+    file.write_all(&0u32.to_le_bytes())?; // sigfigs
+    file.write_all(&SNAPLEN.to_le_bytes())?;
+    file.write_all(&LINKTYPE.to_le_bytes())?;
+    file.flush()?;
+    *CAPTURE.lock().unwrap() = Some(Capture {
+        file,
+        local: 0,
+        seq: 0,
+    });
+    Ok(())
+}
+
+/// Record the address of our radio, the source of what we send.
+pub fn set_local(mymac: u64) {
+    if let Some(capture) = CAPTURE.lock().unwrap().as_mut() {
+        capture.local = mymac;
+    }
+}
+
+/// Where an 802.15.4 frame is going
+enum Dest {
+    Short(u16),
+    Extended(u64),
+}
+
+impl Capture {
+    fn write(&mut self, dest: Dest, source: u64, ackrequest: bool, payload: &[u8]) -> io::Result<()> {
+        // Data frame, PAN ID compression, and 64-bit source
+        let mut control: u16 = 0x0001 | 0x0040 | (3 << 14);
+        if ackrequest {
+            control |= 0x0020;
+        }
+        control |= match dest {
+            Dest::Short(_) => 2 << 10,
+            Dest::Extended(_) => 3 << 10,
+        };
+        let mut frame = Vec::with_capacity(23 + payload.len());
+        frame.extend_from_slice(&control.to_le_bytes());
+        frame.push(self.seq);
+        self.seq = self.seq.wrapping_add(1);
+        frame.extend_from_slice(&BROADCAST.to_le_bytes());
+        match dest {
+            Dest::Short(addr) => frame.extend_from_slice(&addr.to_le_bytes()),
+            Dest::Extended(addr) => frame.extend_from_slice(&addr.to_le_bytes()),
+        }
+        frame.extend_from_slice(&source.to_le_bytes());
+        frame.extend_from_slice(payload);
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.file.write_all(&(now.as_secs() as u32).to_le_bytes())?;
+        self.file.write_all(&now.subsec_micros().to_le_bytes())?;
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(&(frame.len() as u32).to_le_bytes())?;
+        self.file.write_all(&frame)?;
+        // Keep the file usable if xbnet is killed.
+        self.file.flush()
+    }
+}
+
+/// Record a packet.  A dest or source of None is our radio.
+fn capture(dest: Option<Dest>, source: Option<u64>, ackrequest: bool, payload: &[u8]) {
+    let mut capture = CAPTURE.lock().unwrap();
+    if let Some(cap) = capture.as_mut() {
+        let dest = dest.unwrap_or(Dest::Extended(cap.local));
+        let source = source.unwrap_or(cap.local);
+        if let Err(e) = cap.write(dest, source, ackrequest, payload) {
+            warn!("PCAP: failed to write packet; stopping capture: {}", e);
+            *capture = None;
+        }
+    }
+}
+
+/** Record a complete API frame written to the radio, as serialized for the serial
+port.  Only transmit requests are recorded. */
+pub fn tx_apiframe(frame: &[u8]) {
+    // 0x7E, length u16, type, frame ID, dest u64, dest u16, radius, options, payload,
+    // checksum
+    if frame.len() < 18 || frame[3] != 0x10 {
+        return;
+    }
+    let mut dest64 = [0u8; 8];
+    dest64.copy_from_slice(&frame[5..13]);
+    let dest64 = u64::from_be_bytes(dest64);
+    let dest16 = u16::from_be_bytes([frame[13], frame[14]]);
+    let options = frame[16];
+    let dest = if dest64 == u64::from(BROADCAST) {
+        Dest::Short(BROADCAST)
+    } else if dest64 == 0xFFFF_FFFF_FFFF_FFFF {
+        Dest::Short(dest16)
+    } else {
+        Dest::Extended(dest64)
+    };
+    let ackrequest = options & 0x01 == 0 && !matches!(dest, Dest::Short(BROADCAST));
+    capture(Some(dest), None, ackrequest, &frame[17..frame.len() - 1]);
+}
+
+/** Record the inside of an API frame received from the radio, as from rxapiframe.
+Only receive packets are recorded. */
+pub fn rx_apiframe(inner: &[u8]) {
+    // type, sender u64, sender u16, options, payload
+    if inner.len() < 12 || inner[0] != 0x90 {
+        return;
+    }
+    let mut sender = [0u8; 8];
+    sender.copy_from_slice(&inner[1..9]);
+    let sender = u64::from_be_bytes(sender);
+    let options = inner[11];
+    let dest = if options & 0x02 != 0 {
+        Some(Dest::Short(BROADCAST))
+    } else {
+        None
+    };
+    capture(dest, Some(sender), options & 0x01 != 0, &inner[12..]);
+}
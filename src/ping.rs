@@ -15,61 +15,365 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use crate::ser::*;
+/* A probe is "Ping N", padded with dots to the requested size, and pong replies with
+"Pong" and the rest of it, so probes are matched to replies by N.  Round-trip times
+include the time a probe waits in the transmit queue.
+
+A probe to the broadcast address is "Ping N all", and every node running pong may
+answer it.  Pong waits a random time, up to JITTER, before answering one, so that the
+replies of nodes that heard it at the same moment don't collide; their round-trip
+times include that wait.  Ping keeps each broadcast probe open for the whole timeout,
+collecting replies from every node, and lists the nodes at the end, with the RSSI of
+the last packet heard from each, as stats records it. */
+
+use crate::stats;
+use crate::tap::XB_BROADCAST;
 use crate::xb::*;
 use crate::xbpacket::*;
-use crate::xbrx::*;
 use bytes::*;
-use crossbeam_channel;
+use log::*;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-const INTERVAL: u64 = 5; // FIXME: this should be configurable
+/// The longest ping waits without checking whether it has been interrupted
+const POLL: Duration = Duration::from_millis(200);
 
-pub fn genpings(dest: u64, sender: crossbeam_channel::Sender<XBTX>) -> io::Result<()> {
-    let mut counter: u64 = 1;
-    loop {
-        let sendstr = format!("Ping {}", counter);
-        println!("SEND: {}", sendstr);
-        sender
-            .send(XBTX::TXData(XBDestAddr::U64(dest), Bytes::from(sendstr)))
-            .unwrap();
-        thread::sleep(Duration::from_secs(INTERVAL));
-        counter += 1;
+/// The longest pong waits before answering a broadcast probe
+const JITTER: Duration = Duration::from_millis(500);
+
+/// What follows the sequence number in a broadcast probe
+const BROADCAST_MARK: &str = "all";
+
+/// How ping sends its probes
+#[derive(Clone, Copy, Debug)]
+pub struct PingParams {
+    /// How many probes to send; None to continue until interrupted
+    pub count: Option<u64>,
+    /// The time between probes
+    pub interval: Duration,
+    /// The size of each probe; smaller sizes send the probes unpadded
+    pub size: usize,
+    /// How long to wait for the reply to each probe
+    pub timeout: Duration,
+    /// Stop after this long, however many probes have been sent
+    pub deadline: Option<Duration>,
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Build probe seq, padded to size, marked as broadcast if it is.
+fn probe(seq: u64, size: usize, broadcast: bool) -> Bytes {
+    let mut data = format!("Ping {}", seq).into_bytes();
+    if broadcast {
+        data.push(b' ');
+        data.extend_from_slice(BROADCAST_MARK.as_bytes());
     }
+    if data.len() < size {
+        data.push(b' ');
+        data.resize(size, b'.');
+    }
+    Bytes::from(data)
 }
 
-/// Show pongs
-pub fn displaypongs(xbreframer: &mut XBReframer, ser: &mut XBSerReader) -> () {
-    loop {
-        let (fromu64, _fromu16, payload) = xbreframer.rxframe(ser);
-        println!(
-            "RECV from {}: {}",
-            hex::encode(fromu64.to_be_bytes()),
-            String::from_utf8_lossy(&payload)
+/// The sequence number of the probe a reply answers
+fn replyseq(payload: &[u8]) -> Option<u64> {
+    let rest = payload.strip_prefix(b"Pong ")?;
+    let digits = rest.iter().take_while(|b| b.is_ascii_digit()).count();
+    std::str::from_utf8(&rest[..digits]).ok()?.parse().ok()
+}
+
+/// Whether a probe was sent to the broadcast address
+fn isbroadcast(payload: &[u8]) -> bool {
+    let mut words = payload.split(|b| *b == b' ');
+    words.nth(2) == Some(BROADCAST_MARK.as_bytes())
+}
+
+/// A random time to wait before answering a broadcast probe
+fn jitter() -> Duration {
+    // Each RandomState is seeded differently, which is random enough here.
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(random % (JITTER.as_millis() as u64 + 1))
+}
+
+/// What happened to the probes
+#[derive(Default)]
+struct Tally {
+    transmitted: u64,
+    received: u64,
+    duplicates: u64,
+    /// Replies that came after the probe had timed out
+    late: u64,
+    /// Replies that came after the reply to a later probe
+    reordered: u64,
+    /// The round-trip time of each probe answered, in milliseconds
+    rtts: Vec<f64>,
+    /// For a broadcast ping, the round-trip times of the replies from each node
+    responders: BTreeMap<u64, Vec<f64>>,
+}
+
+/// The minimum, average, maximum, and mean deviation of rtts, which is not empty
+fn rttstats(rtts: &[f64]) -> (f64, f64, f64, f64) {
+    let n = rtts.len() as f64;
+    let min = rtts.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = rtts.iter().cloned().fold(0.0, f64::max);
+    let avg = rtts.iter().sum::<f64>() / n;
+    let sumsq = rtts.iter().map(|r| r * r).sum::<f64>();
+    let mdev = (sumsq / n - avg * avg).max(0.0).sqrt();
+    (min, avg, max, mdev)
+}
+
+impl Tally {
+    fn summary(&self, dest: u64, elapsed: Duration) -> Vec<String> {
+        let loss = if self.transmitted == 0 {
+            0.0
+        } else {
+            100.0 * (self.transmitted - self.received) as f64 / self.transmitted as f64
+        };
+        let mut counts = format!(
+            "{} probes transmitted, {} received, {:.1}% loss",
+            self.transmitted, self.received, loss
         );
+        for (n, what) in [
+            (self.duplicates, "duplicates"),
+            (self.late, "late"),
+            (self.reordered, "out of order"),
+        ] {
+            if n > 0 {
+                counts.push_str(&format!(", {} {}", n, what));
+            }
+        }
+        counts.push_str(&format!(", time {}ms", elapsed.as_millis()));
+        let mut out = vec![format!("--- {:016x} ping statistics ---", dest), counts];
+
+        if !self.rtts.is_empty() {
+            let (min, avg, max, mdev) = rttstats(&self.rtts);
+            out.push(format!(
+                "rtt min/avg/max/mdev = {:.1}/{:.1}/{:.1}/{:.1} ms",
+                min, avg, max, mdev
+            ));
+        }
+        out
+    }
+
+    /// The nodes that answered a broadcast ping, one per line
+    fn responders(&self) -> Vec<String> {
+        let peers = stats::snapshot().peers;
+        let mut out = vec![format!("{} nodes responded", self.responders.len())];
+        for (addr, rtts) in self.responders.iter() {
+            let (min, avg, max, _) = rttstats(rtts);
+            let rssi = match peers.get(addr).and_then(|peer| peer.last_rssi) {
+                Some(rssi) => format!("-{} dBm", rssi),
+                None => String::from("unknown"),
+            };
+            out.push(format!(
+                "{:016x}: {} replies, rtt min/avg/max = {:.1}/{:.1}/{:.1} ms, rssi {}",
+                addr,
+                rtts.len(),
+                min,
+                avg,
+                max,
+                rssi
+            ));
+        }
+        out
     }
 }
 
-/// Reply to pings
-pub fn pong(
-    xbreframer: &mut XBReframer,
-    ser: &mut XBSerReader,
+/** Send probes to dest, reporting each reply and its round-trip time, until done as
+params say or interrupted by SIGINT; then print a summary.  If dest is the broadcast
+address, replies from every node are collected, and the nodes listed.  Fails if no
+replies came. */
+pub fn ping(
+    dest: u64,
     sender: crossbeam_channel::Sender<XBTX>,
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    params: PingParams,
 ) -> io::Result<()> {
+    unsafe {
+        libc::signal(libc::SIGINT, interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t);
+    }
+    let broadcast = dest == XB_BROADCAST;
+    let start = Instant::now();
+    let deadline = params.deadline.map(|d| start + d);
+    let mut tally = Tally::default();
+    let mut nextseq: u64 = 1;
+    let mut nextsend = start;
+    // Probes awaiting a reply, with when they were sent and how many nodes have
+    // answered; a broadcast probe waits for replies until it times out.
+    let mut outstanding: BTreeMap<u64, (Instant, usize)> = BTreeMap::new();
+    // The probes answered, and by whom
+    let mut answered: BTreeSet<(u64, u64)> = BTreeSet::new();
+    // The latest probe answered by each node
+    let mut highest: BTreeMap<u64, u64> = BTreeMap::new();
+
+    println!(
+        "PING {:016x}: {} bytes",
+        dest,
+        probe(1, params.size, broadcast).len()
+    );
     loop {
-        let (fromu64, _addr_16, payload) = xbreframer.rxframe(ser);
-        if payload.starts_with(b"Ping ") {
+        let now = Instant::now();
+        if INTERRUPTED.load(Ordering::SeqCst) || deadline.is_some_and(|d| now >= d) {
+            break;
+        }
+
+        outstanding.retain(|seq, (sent, replies)| {
+            if now.duration_since(*sent) < params.timeout {
+                return true;
+            }
+            if *replies == 0 {
+                println!("seq={} timed out", seq);
+            } else {
+                println!("seq={}: {} nodes responded", seq, replies);
+            }
+            false
+        });
+        let sending = params.count.map_or(true, |count| nextseq <= count);
+        if !sending && outstanding.is_empty() {
+            break;
+        }
+        if sending && now >= nextsend {
+            sender
+                .send(XBTX::TXData(
+                    XBDestAddr::U64(dest),
+                    probe(nextseq, params.size, broadcast),
+                ))
+                .map_err(|e| mkerror(&e.to_string()))?;
+            outstanding.insert(nextseq, (now, 0));
+            tally.transmitted += 1;
+            nextseq += 1;
+            nextsend += params.interval;
+            continue;
+        }
+
+        let mut wake = now + POLL;
+        if sending {
+            wake = wake.min(nextsend);
+        }
+        if let Some((oldest, _)) = outstanding.values().min() {
+            wake = wake.min(*oldest + params.timeout);
+        }
+        if let Some(deadline) = deadline {
+            wake = wake.min(deadline);
+        }
+        let (fromu64, _fromu16, payload) = match framerx.recv_timeout(wake - now) {
+            Ok(frame) => frame,
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => continue,
+            Err(e) => return Err(mkerror(&e.to_string())),
+        };
+        let seq = match replyseq(&payload) {
+            Some(seq) if broadcast || fromu64 == dest => seq,
+            _ => {
+                debug!("PING: ignoring frame from {:x}", fromu64);
+                continue;
+            }
+        };
+        let prefix = format!("{} bytes from {:016x}: seq={}", payload.len(), fromu64, seq);
+        if answered.contains(&(seq, fromu64)) {
+            tally.duplicates += 1;
+            println!("{} DUP!", prefix);
+        } else if let Some((sent, replies)) = outstanding.get_mut(&seq) {
+            let rtt = sent.elapsed().as_secs_f64() * 1000.0;
+            if *replies == 0 {
+                tally.received += 1;
+            }
+            *replies += 1;
+            tally.rtts.push(rtt);
+            answered.insert((seq, fromu64));
+            if broadcast {
+                tally.responders.entry(fromu64).or_default().push(rtt);
+            } else {
+                outstanding.remove(&seq);
+            }
+            let latest = highest.entry(fromu64).or_insert(0);
+            let reordered = seq < *latest;
+            if reordered {
+                tally.reordered += 1;
+            }
+            *latest = (*latest).max(seq);
             println!(
-                "RECV from {}: {}",
-                hex::encode(fromu64.to_be_bytes()),
-                String::from_utf8_lossy(&payload)
+                "{} time={:.1} ms{}",
+                prefix,
+                rtt,
+                if reordered { " (out of order)" } else { "" }
             );
+        } else if seq < nextseq {
+            tally.late += 1;
+            answered.insert((seq, fromu64));
+            println!("{} late, after timing out", prefix);
+        } else {
+            debug!("PING: ignoring reply to unsent probe {}", seq);
+        }
+    }
+
+    println!();
+    for line in tally.summary(dest, start.elapsed()) {
+        println!("{}", line);
+    }
+    if broadcast {
+        for line in tally.responders() {
+            println!("{}", line);
+        }
+    }
+    if tally.received == 0 {
+        return Err(mkerror("no replies received"));
+    }
+    Ok(())
+}
+
+/// Reply to pings, after a random delay if they were broadcast, printing each one
+pub fn pong(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    reply(framerx, sender, true)
+}
+
+/// Like pong, but only log the pings, leaving stdout to another service
+pub fn pong_quietly(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    reply(framerx, sender, false)
+}
+
+fn reply(
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    sender: crossbeam_channel::Sender<XBTX>,
+    print: bool,
+) -> io::Result<()> {
+    loop {
+        let (fromu64, _addr_16, payload) = framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
+        if payload.starts_with(b"Ping ") {
+            if print {
+                println!(
+                    "RECV from {}: {}",
+                    hex::encode(fromu64.to_be_bytes()),
+                    String::from_utf8_lossy(&payload)
+                );
+            } else {
+                debug!(
+                    "PONG: from {:x}: {}",
+                    fromu64,
+                    String::from_utf8_lossy(&payload)
+                );
+            }
+            if isbroadcast(&payload) {
+                thread::sleep(jitter());
+            }
             let resp = Bytes::from(format!("Pong {}", String::from_utf8_lossy(&payload[5..])));
             sender
                 .send(XBTX::TXData(XBDestAddr::U64(fromu64), resp))
-                .unwrap();
+                .map_err(|e| mkerror(&e.to_string()))?;
         }
     }
 }
@@ -15,20 +15,32 @@
     along with this program.  If not, see <http://www.gnu.org/licenses/>.
 */
 
-use crate::ser::*;
 use crate::xb::*;
 use crate::xbpacket::*;
-use crate::xbrx::*;
 use bytes::*;
-use crossbeam_channel;
+use log::*;
 use std::io;
 use std::io::{Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
 
 pub fn stdin_processor(
     dest: u64,
     maxframesize: usize,
+    coalesce_delay: Duration,
+    coalesce_min_fill: u8,
     sender: crossbeam_channel::Sender<XBTX>,
 ) -> io::Result<()> {
+    if coalesce_delay > Duration::from_millis(0) {
+        return coalescing_stdin_processor(
+            dest,
+            maxframesize,
+            coalesce_delay,
+            coalesce_min_fill,
+            sender,
+        );
+    }
+
     let stdin = io::stdin();
     let mut br = io::BufReader::new(stdin);
     let mut buf = vec![0u8; maxframesize - 1];
@@ -50,11 +62,188 @@ pub fn stdin_processor(
     }
 }
 
-pub fn stdout_processor(xbreframer: &mut XBReframer, ser: &mut XBSerReader) -> io::Result<()> {
+/** Like stdin_processor, but batch up small reads from stdin.  Data is held until
+either coalesce_min_fill percent of a radio payload has accumulated, or
+coalesce_delay has passed since the oldest byte in the batch arrived. */
+fn coalescing_stdin_processor(
+    dest: u64,
+    maxframesize: usize,
+    coalesce_delay: Duration,
+    coalesce_min_fill: u8,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let payloadsize = maxframesize - 1;
+    let min_fill = std::cmp::max(1, payloadsize * usize::from(coalesce_min_fill) / 100);
+
+    // stdin reads block, so do them in a separate thread.  None indicates EOF.
+    let (readtx, readrx) = crossbeam_channel::bounded(5);
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        let mut br = io::BufReader::new(stdin);
+        let mut buf = vec![0u8; payloadsize];
+        loop {
+            let res = br.read(&mut buf).expect("Failure reading stdin");
+            if res == 0 {
+                readtx.send(None).unwrap();
+                return;
+            }
+            readtx
+                .send(Some(Bytes::copy_from_slice(&buf[0..res])))
+                .unwrap();
+        }
+    });
+
+    coalesce(dest, payloadsize, min_fill, coalesce_delay, readrx, sender)
+}
+
+/** Transmit the data received from readrx, coalesced as for coalescing_stdin_processor,
+until None marks the end of it. */
+fn coalesce(
+    dest: u64,
+    payloadsize: usize,
+    min_fill: usize,
+    coalesce_delay: Duration,
+    readrx: crossbeam_channel::Receiver<Option<Bytes>>,
+    sender: crossbeam_channel::Sender<XBTX>,
+) -> io::Result<()> {
+    let mut pending = BytesMut::new();
+    let mut deadline: Option<Instant> = None;
+
+    loop {
+        let item = match deadline {
+            None => readrx.recv().map_err(|e| mkerror(&e.to_string()))?,
+            Some(d) => match readrx.recv_timeout(d.saturating_duration_since(Instant::now())) {
+                Ok(item) => item,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    // Waited long enough; send whatever we have.
+                    send_pending(&mut pending, payloadsize, 1, dest, &sender);
+                    deadline = None;
+                    continue;
+                }
+                Err(e) => return Err(mkerror(&e.to_string())),
+            },
+        };
+
+        match item {
+            None => {
+                // EOF
+                send_pending(&mut pending, payloadsize, 1, dest, &sender);
+                sender.send(XBTX::Shutdown).unwrap();
+                return Ok(());
+            }
+            Some(data) => {
+                if pending.is_empty() {
+                    deadline = Some(Instant::now() + coalesce_delay);
+                }
+                pending.extend_from_slice(&data);
+                send_pending(&mut pending, payloadsize, min_fill, dest, &sender);
+                if pending.is_empty() {
+                    deadline = None;
+                }
+            }
+        }
+    }
+}
+
+/// Transmit full payloads from pending, plus any remainder of at least min_fill bytes.
+fn send_pending(
+    pending: &mut BytesMut,
+    payloadsize: usize,
+    min_fill: usize,
+    dest: u64,
+    sender: &crossbeam_channel::Sender<XBTX>,
+) {
+    while !pending.is_empty() && pending.len() >= std::cmp::min(min_fill, payloadsize) {
+        let len = std::cmp::min(pending.len(), payloadsize);
+        let data = pending.split_to(len).freeze();
+        trace!("PIPE: sending coalesced block of {} bytes", len);
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(dest), data))
+            .unwrap();
+    }
+}
+
+pub fn stdout_processor(framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>) -> io::Result<()> {
     let mut stdout = io::stdout();
     loop {
-        let (_fromu64, _fromu16, payload) = xbreframer.rxframe(ser);
+        let (_fromu64, _fromu16, payload) = framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
         stdout.write_all(&payload)?;
         stdout.flush()?;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEST: u64 = 0x0013a20000000002;
+
+    /// The data of each TXData queued, in order; panics on anything else.
+    fn sent(rx: &crossbeam_channel::Receiver<XBTX>) -> Vec<Vec<u8>> {
+        rx.try_iter()
+            .map(|item| match item {
+                XBTX::TXData(XBDestAddr::U64(DEST), data) => data.to_vec(),
+                _ => panic!("unexpected item queued"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn send_pending_holds_short_remainder() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut pending = BytesMut::from(&[1u8; 25][..]);
+        send_pending(&mut pending, 10, 6, DEST, &tx);
+        assert_eq!(sent(&rx), vec![vec![1u8; 10], vec![1u8; 10]]);
+        assert_eq!(pending.len(), 5);
+
+        // At the minimum fill, the remainder goes too.
+        pending.extend_from_slice(&[2]);
+        send_pending(&mut pending, 10, 6, DEST, &tx);
+        assert_eq!(sent(&rx), vec![vec![1, 1, 1, 1, 1, 2]]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn send_pending_min_fill_above_payload() {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let mut pending = BytesMut::from(&[1u8; 10][..]);
+        send_pending(&mut pending, 10, 20, DEST, &tx);
+        assert_eq!(sent(&rx), vec![vec![1u8; 10]]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn coalesce_flushes_after_delay() {
+        let (readtx, readrx) = crossbeam_channel::unbounded();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let delay = Duration::from_millis(200);
+        let coalescer = thread::spawn(move || coalesce(DEST, 10, 8, delay, readrx, tx));
+
+        // Small reads are held together, short of the minimum fill
+        readtx.send(Some(Bytes::from_static(b"ab"))).unwrap();
+        readtx.send(Some(Bytes::from_static(b"cd"))).unwrap();
+        thread::sleep(delay / 4);
+        assert!(sent(&rx).is_empty());
+
+        // until the delay has passed
+        thread::sleep(delay * 2);
+        assert_eq!(sent(&rx), vec![b"abcd".to_vec()]);
+
+        // Reaching the minimum fill sends at once.
+        readtx.send(Some(Bytes::from_static(b"efghijkl"))).unwrap();
+        thread::sleep(delay / 4);
+        assert_eq!(sent(&rx), vec![b"efghijkl".to_vec()]);
+
+        // The end of input sends what is left, then shuts down.
+        readtx.send(Some(Bytes::from_static(b"m"))).unwrap();
+        readtx.send(None).unwrap();
+        coalescer.join().unwrap().unwrap();
+        let items: Vec<XBTX> = rx.try_iter().collect();
+        assert_eq!(items.len(), 2);
+        match &items[0] {
+            XBTX::TXData(_, data) => assert_eq!(&data[..], b"m"),
+            _ => panic!("expected the remainder"),
+        }
+        assert!(matches!(items[1], XBTX::Shutdown));
+    }
+}
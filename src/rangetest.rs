@@ -0,0 +1,297 @@
+/*! Testing the range of a link, with the signal strength at both ends */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* Every message starts with MAGIC and a message type byte.  All integers are
+big-endian.
+
+  PROBE    client -> server   seq: u64
+  REPLY    server -> client   seq: u64, rssi: u8 of the probe at the server, in -dBm,
+                              or 0 if the server couldn't read it
+
+The RSSI at each end is read with ATDB, which the dispatcher sends after frames
+received and stats records along with the frame it followed; each end waits up to
+RSSI_WAIT for the reading of the frame it just got.  Only the 0x80 receive frames of 802.15.4 firmware carry their
+own RSSI, and xbnet does not transmit the frames that firmware understands, so ATDB
+serves for all. */
+
+use crate::stats;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use chrono::Local;
+use log::*;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const MAGIC: &[u8] = b"RT";
+pub const MSG_PROBE: u8 = b'P';
+pub const MSG_REPLY: u8 = b'R';
+
+/// The longest either end waits for the RSSI of a frame it received
+const RSSI_WAIT: Duration = Duration::from_millis(500);
+
+/// How often stats are checked for an RSSI reading
+const RSSI_POLL: Duration = Duration::from_millis(10);
+
+/// How a client runs
+#[derive(Clone, Copy, Debug)]
+pub struct RangeParams {
+    /// How many probes to send; None to continue until killed
+    pub count: Option<u64>,
+    /// The time between probes
+    pub interval: Duration,
+    /// How long to wait for each reply
+    pub timeout: Duration,
+    /// How many of the latest probes the success rate covers
+    pub window: usize,
+}
+
+/// Build a message.
+fn mkmsg(msgtype: u8, seq: u64, rssi: Option<u8>) -> Bytes {
+    let mut msg = BytesMut::with_capacity(MAGIC.len() + 10);
+    msg.put_slice(MAGIC);
+    msg.put_u8(msgtype);
+    msg.put_u64(seq);
+    if let Some(rssi) = rssi {
+        msg.put_u8(rssi);
+    }
+    msg.freeze()
+}
+
+/// Parse a message, returning its type, sequence number, and the rest.
+fn parsemsg(mut payload: Bytes) -> Option<(u8, u64, Bytes)> {
+    if payload.len() < MAGIC.len() + 9 || !payload.starts_with(MAGIC) {
+        return None;
+    }
+    payload.advance(MAGIC.len());
+    let msgtype = payload.get_u8();
+    let seq = payload.get_u64();
+    Some((msgtype, seq, payload))
+}
+
+/** The RSSI, in -dBm, of the last frame from peer, once it has been read; None if it
+isn't within RSSI_WAIT. */
+fn rssi_of_last(peer: u64) -> Option<u8> {
+    let frame = stats::snapshot().peers.get(&peer)?.counters.frames_in;
+    let deadline = Instant::now() + RSSI_WAIT;
+    loop {
+        let reading = stats::snapshot()
+            .peers
+            .get(&peer)
+            .and_then(|p| Some((p.last_rssi?, p.last_rssi_frame?)));
+        match reading {
+            Some((rssi, read)) if read == frame => return Some(rssi),
+            // The frame went unread, and a later one was read instead.
+            Some((_, read)) if read > frame => return None,
+            _ => (),
+        }
+        if Instant::now() >= deadline {
+            return None;
+        }
+        thread::sleep(RSSI_POLL);
+    }
+}
+
+fn showrssi(rssi: Option<u8>) -> String {
+    rssi.map_or_else(String::new, |r| format!("-{}", r))
+}
+
+/// What became of one probe
+struct Outcome {
+    seq: u64,
+    /// The round-trip time, and the RSSI here and at the server, if it was answered
+    reply: Option<(Duration, Option<u8>, Option<u8>)>,
+}
+
+/// The log of probes, as a table on stdout and optionally a CSV file
+struct Log {
+    csv: Option<BufWriter<File>>,
+    /// Whether each of the latest probes was answered
+    recent: VecDeque<bool>,
+    window: usize,
+    sent: u64,
+    answered: u64,
+}
+
+impl Log {
+    fn new(csvpath: Option<&Path>, window: usize) -> io::Result<Self> {
+        let csv = match csvpath {
+            Some(path) => {
+                let mut csv = BufWriter::new(File::create(path)?);
+                writeln!(csv, "time,seq,result,rtt_ms,local_rssi_dbm,remote_rssi_dbm")?;
+                csv.flush()?;
+                Some(csv)
+            }
+            None => None,
+        };
+        println!(
+            "{:<12} {:>6} {:<6} {:>9} {:>7} {:>7} {:>8}",
+            "TIME", "SEQ", "RESULT", "RTT", "LOCAL", "REMOTE", "SUCCESS"
+        );
+        Ok(Log {
+            csv,
+            recent: VecDeque::with_capacity(window),
+            window,
+            sent: 0,
+            answered: 0,
+        })
+    }
+
+    fn add(&mut self, outcome: Outcome) -> io::Result<()> {
+        let now = Local::now();
+        self.sent += 1;
+        if outcome.reply.is_some() {
+            self.answered += 1;
+        }
+        if self.recent.len() == self.window {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(outcome.reply.is_some());
+        let success =
+            100.0 * self.recent.iter().filter(|ok| **ok).count() as f64 / self.recent.len() as f64;
+
+        let (result, rtt, local, remote) = match outcome.reply {
+            Some((rtt, local, remote)) => (
+                "ok",
+                format!("{:.1}", rtt.as_secs_f64() * 1000.0),
+                showrssi(local),
+                showrssi(remote),
+            ),
+            None => ("lost", String::new(), String::new(), String::new()),
+        };
+        let rttshown = if outcome.reply.is_some() {
+            format!("{} ms", rtt)
+        } else {
+            String::new()
+        };
+        println!(
+            "{:<12} {:>6} {:<6} {:>9} {:>7} {:>7} {:>7.1}%",
+            now.format("%H:%M:%S%.3f"),
+            outcome.seq,
+            result,
+            rttshown,
+            local,
+            remote,
+            success
+        );
+        if let Some(csv) = self.csv.as_mut() {
+            writeln!(
+                csv,
+                "{},{},{},{},{},{}",
+                now.format("%Y-%m-%dT%H:%M:%S%.3f%:z"),
+                outcome.seq,
+                result,
+                rtt,
+                local,
+                remote
+            )?;
+            // Keep the file usable if xbnet is killed.
+            csv.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/** Exchange probes with a rangetest server at dest, printing a row for each and
+logging it to csvpath if given, until done as params say. */
+pub fn client(
+    dest: u64,
+    sender: crossbeam_channel::Sender<XBTX>,
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+    params: RangeParams,
+    csvpath: Option<&Path>,
+) -> io::Result<()> {
+    let mut log = Log::new(csvpath, params.window.max(1))?;
+    let mut nextsend = Instant::now();
+    let mut seq = 1;
+    while params.count.map_or(true, |count| seq <= count) {
+        let now = Instant::now();
+        if now < nextsend {
+            thread::sleep(nextsend - now);
+        }
+        nextsend += params.interval;
+
+        let sent = Instant::now();
+        sender
+            .send(XBTX::TXData(XBDestAddr::U64(dest), mkmsg(MSG_PROBE, seq, None)))
+            .map_err(|e| mkerror(&e.to_string()))?;
+        let deadline = sent + params.timeout;
+        let reply = loop {
+            let (fromu64, _fromu16, payload) =
+                match framerx.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(frame) => frame,
+                    Err(crossbeam_channel::RecvTimeoutError::Timeout) => break None,
+                    Err(e) => return Err(mkerror(&e.to_string())),
+                };
+            let rtt = sent.elapsed();
+            match parsemsg(payload) {
+                Some((MSG_REPLY, s, rest)) if fromu64 == dest && s == seq => {
+                    let remote = rest.first().cloned().filter(|r| *r != 0);
+                    break Some((rtt, rssi_of_last(dest), remote));
+                }
+                _ => debug!("RANGETEST: ignoring frame from {:x}", fromu64),
+            }
+        };
+        log.add(Outcome { seq, reply })?;
+        seq += 1;
+    }
+    println!(
+        "{} probes, {} answered, {:.1}% success",
+        log.sent,
+        log.answered,
+        100.0 * log.answered as f64 / log.sent.max(1) as f64
+    );
+    Ok(())
+}
+
+/// Answer probes forever, with the RSSI of each.
+pub fn server(
+    sender: crossbeam_channel::Sender<XBTX>,
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+) -> io::Result<()> {
+    loop {
+        let (fromu64, _fromu16, payload) = framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
+        let seq = match parsemsg(payload) {
+            Some((MSG_PROBE, seq, _)) => seq,
+            _ => {
+                debug!("RANGETEST: ignoring frame from {:x}", fromu64);
+                continue;
+            }
+        };
+        let rssi = rssi_of_last(fromu64);
+        println!(
+            "{} probe {} from {:016x}, RSSI {}",
+            Local::now().format("%H:%M:%S%.3f"),
+            seq,
+            fromu64,
+            rssi.map_or_else(|| String::from("unknown"), |r| format!("-{} dBm", r))
+        );
+        sender
+            .send(XBTX::TXData(
+                XBDestAddr::U64(fromu64),
+                mkmsg(MSG_REPLY, seq, Some(rssi.unwrap_or(0))),
+            ))
+            .map_err(|e| mkerror(&e.to_string()))?;
+    }
+}
@@ -16,21 +16,38 @@
 
 */
 
+/* A recording of a session is a text file with one line per read or write:
+
+  SECONDS DIRECTION HEX
+
+where SECONDS is the time since recording started, DIRECTION is < for bytes from
+the radio and > for bytes to it, and HEX is the bytes.  Blank lines and lines
+beginning with # are ignored, so recordings can be annotated or written by hand. */
+
 use bytes::*;
 use log::*;
 use serialport::prelude::*;
+use std::fs;
+use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader, Write};
-use std::path::PathBuf;
-use std::time::Duration;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// Reads from the radio: normally a serial port, but possibly a broker socket.
 pub struct XBSerReader {
-    pub br: BufReader<Box<dyn SerialPort>>,
+    pub br: BufReader<Box<dyn Read + Send>>,
     pub portname: PathBuf,
+    /// Bytes put back with unread, to be read again before br
+    pending: Vec<u8>,
 }
 
 pub struct XBSerWriter {
-    pub swrite: Box<dyn SerialPort>,
+    pub swrite: Box<dyn Write + Send>,
     pub portname: PathBuf,
 }
 
@@ -48,18 +65,237 @@ pub fn new(portname: PathBuf, speed: u32) -> io::Result<(XBSerReader, XBSerWrite
     let writeport = readport.try_clone()?;
 
     Ok((
-        XBSerReader {
-            br: BufReader::new(readport),
-            portname: portname.clone(),
-        },
+        XBSerReader::new(Box::new(readport), portname.clone()),
         XBSerWriter {
-            swrite: writeport,
+            swrite: Box::new(writeport),
             portname,
         },
     ))
 }
 
+/// Connect to the socket of an xbnet broker, which then stands in for the serial port.
+pub fn connect(socketname: PathBuf) -> io::Result<(XBSerReader, XBSerWriter)> {
+    let readsock = UnixStream::connect(&socketname)?;
+    let writesock = readsock.try_clone()?;
+
+    Ok((
+        XBSerReader::new(Box::new(readsock), socketname.clone()),
+        XBSerWriter {
+            swrite: Box::new(writesock),
+            portname: socketname,
+        },
+    ))
+}
+
+/** Listen on a Unix socket at socketpath, first removing a socket left behind by an
+earlier run.  Anything else there, including a socket still in use, is an error rather
+than removed. */
+pub fn bind_socket(socketpath: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(socketpath) {
+        Ok(meta) if !meta.file_type().is_socket() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", socketpath.display()),
+            ));
+        }
+        Ok(_) if UnixStream::connect(socketpath).is_ok() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another process", socketpath.display()),
+            ));
+        }
+        Ok(_) => fs::remove_file(socketpath)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    UnixListener::bind(socketpath)
+}
+
+/// A recording in progress, shared by the reader and writer
+struct Recording {
+    file: File,
+    start: Instant,
+}
+
+impl Recording {
+    fn add(&mut self, direction: char, data: &[u8]) {
+        let elapsed = self.start.elapsed();
+        let res = writeln!(
+            self.file,
+            "{}.{:06} {} {}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros(),
+            direction,
+            hex::encode(data)
+        );
+        if let Err(e) = res {
+            warn!("Failed to write to recording: {}", e);
+        }
+    }
+}
+
+struct RecordingReader {
+    inner: Box<dyn Read + Send>,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl Read for RecordingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.inner.read(buf)?;
+        if size > 0 {
+            self.recording.lock().unwrap().add('<', &buf[..size]);
+        }
+        Ok(size)
+    }
+}
+
+struct RecordingWriter {
+    inner: Box<dyn Write + Send>,
+    recording: Arc<Mutex<Recording>>,
+}
+
+impl Write for RecordingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = self.inner.write(buf)?;
+        self.recording.lock().unwrap().add('>', &buf[..size]);
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/** Record everything read by reader and written by writer to the file at path,
+which is replaced.  Must be called before anything is read. */
+pub fn record(
+    reader: XBSerReader,
+    writer: XBSerWriter,
+    path: &Path,
+) -> io::Result<(XBSerReader, XBSerWriter)> {
+    let mut file = File::create(path)?;
+    writeln!(file, "# xbnet recording of {}", reader.portname.display())?;
+    let recording = Arc::new(Mutex::new(Recording {
+        file,
+        start: Instant::now(),
+    }));
+    Ok((
+        XBSerReader::new(
+            Box::new(RecordingReader {
+                inner: Box::new(reader.br.into_inner()),
+                recording: recording.clone(),
+            }),
+            reader.portname,
+        ),
+        XBSerWriter {
+            swrite: Box::new(RecordingWriter {
+                inner: writer.swrite,
+                recording,
+            }),
+            portname: writer.portname,
+        },
+    ))
+}
+
+/// Returns what the radio sent in a recording.
+struct ReplayReader {
+    data: io::Cursor<Vec<u8>>,
+    /// At the end, block rather than return end of file
+    wait_at_end: bool,
+}
+
+impl Read for ReplayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let size = self.data.read(buf)?;
+        if size == 0 && !buf.is_empty() {
+            info!("REPLAY: end of recording");
+            if self.wait_at_end {
+                // Like a radio that has gone quiet, rather than one that has gone away
+                loop {
+                    thread::park();
+                }
+            }
+        }
+        Ok(size)
+    }
+}
+
+/// Discards what is written to a replayed session.
+struct ReplayWriter;
+
+impl Write for ReplayWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        trace!("REPLAY: discarding {}", hex::encode(buf));
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/** Replay a recording made with record, in place of the radio.  The reader returns
+the bytes that came from the radio, as fast as they are read, and then either blocks
+forever, if wait_at_end, or reaches end of file.  What is written is discarded. */
+pub fn replay(path: &Path, wait_at_end: bool) -> io::Result<(XBSerReader, XBSerWriter)> {
+    let text = fs::read_to_string(path)?;
+    let mut data = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: line {}: invalid recording", path.display(), i + 1),
+            )
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        match fields.as_slice() {
+            [_, "<", bytes] => data.extend(hex::decode(bytes).map_err(|_| err())?),
+            [_, ">", _] => (),
+            _ => return Err(err()),
+        }
+    }
+
+    Ok((
+        XBSerReader::new(
+            Box::new(ReplayReader {
+                data: io::Cursor::new(data),
+                wait_at_end,
+            }),
+            path.to_path_buf(),
+        ),
+        XBSerWriter {
+            swrite: Box::new(ReplayWriter),
+            portname: path.to_path_buf(),
+        },
+    ))
+}
+
 impl XBSerReader {
+    pub fn new(reader: Box<dyn Read + Send>, portname: PathBuf) -> Self {
+        XBSerReader {
+            br: BufReader::new(reader),
+            portname,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Fill buf, first with any bytes put back by unread.
+    pub fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let frompending = self.pending.len().min(buf.len());
+        buf[..frompending].copy_from_slice(&self.pending[..frompending]);
+        self.pending.drain(..frompending);
+        self.br.read_exact(&mut buf[frompending..])
+    }
+
+    /// Put back data, to be read again ahead of anything not yet read.
+    pub fn unread(&mut self, data: &[u8]) {
+        self.pending.splice(0..0, data.iter().cloned());
+    }
+
     /// Read a line from the port.  Return it with EOL characters removed.
     /// None if EOF reached.
     pub fn readln(&mut self) -> io::Result<Option<String>> {
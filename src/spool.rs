@@ -0,0 +1,290 @@
+/*! Store-and-forward message queue for intermittently reachable nodes */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The spool is a directory with one subdirectory per destination, named by its
+64-bit address in hex.  Each message is a file in that subdirectory named
+
+  CREATED.EXPIRES.msg
+
+where CREATED is the enqueue time in nanoseconds since the epoch, zero-padded so
+that names sort in order, and EXPIRES is the expiration time in seconds since the
+epoch.  The contents of the file are the message itself.  Files beginning with a
+dot are ignored, so messages can be written under a temporary name and renamed
+into place. */
+
+use crate::stats;
+use crate::xb::*;
+use crate::xbpacket::*;
+use bytes::*;
+use log::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How often to look for new messages and expire old ones
+const SCAN_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long to wait for the TX status of a delivery attempt
+const STATUS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Add a message for dest to the spool.
+pub fn enqueue(spooldir: &Path, dest: u64, data: &[u8], ttl: Duration) -> io::Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| mkerror(&e.to_string()))?;
+    let destdir = spooldir.join(format!("{:016x}", dest));
+    fs::create_dir_all(&destdir)?;
+
+    let name = format!("{:020}.{}.msg", now.as_nanos(), (now + ttl).as_secs());
+    let tmppath = destdir.join(format!(".{}", name));
+    fs::write(&tmppath, data)?;
+    fs::rename(&tmppath, destdir.join(&name))?;
+    debug!("SPOOL: queued {} for {:x}", name, dest);
+    Ok(())
+}
+
+/// A message waiting in the spool
+struct Queued {
+    path: PathBuf,
+    expires: u64,
+}
+
+/// A delivery attempt awaiting TX status reports
+struct InFlight {
+    dest: u64,
+    path: PathBuf,
+    /// The (frame ID, delivery status) of each packet of the delivery
+    statuses: crossbeam_channel::Receiver<(u8, u8)>,
+    /// The frame IDs whose status has been reported
+    frame_ids: BTreeSet<u8>,
+    statuses_remaining: usize,
+    failed: bool,
+    deadline: Instant,
+}
+
+/// Read the spool directory, returning the queued messages for each destination, oldest first.
+fn scan(spooldir: &Path) -> io::Result<BTreeMap<u64, Vec<Queued>>> {
+    let mut queues = BTreeMap::new();
+    for destentry in fs::read_dir(spooldir)? {
+        let destentry = destentry?;
+        let dest = match u64::from_str_radix(&destentry.file_name().to_string_lossy(), 16) {
+            Ok(dest) if destentry.file_type()?.is_dir() => dest,
+            _ => continue,
+        };
+
+        let mut msgs: Vec<(String, Queued)> = Vec::new();
+        for msgentry in fs::read_dir(destentry.path())? {
+            let msgentry = msgentry?;
+            let name = msgentry.file_name().to_string_lossy().into_owned();
+            let fields: Vec<&str> = name.split('.').collect();
+            if name.starts_with('.') || fields.len() != 3 || fields[2] != "msg" {
+                continue;
+            }
+            match fields[1].parse() {
+                Ok(expires) => msgs.push((
+                    name.clone(),
+                    Queued {
+                        path: msgentry.path(),
+                        expires,
+                    },
+                )),
+                Err(_) => warn!("SPOOL: ignoring malformed file name {:?}", msgentry.path()),
+            }
+        }
+        if !msgs.is_empty() {
+            msgs.sort_by(|a, b| a.0.cmp(&b.0));
+            queues.insert(dest, msgs.into_iter().map(|(_, q)| q).collect());
+        }
+    }
+    Ok(queues)
+}
+
+/** Deliver messages from the spool.  Each destination is tried once at startup.  When
+a delivery fails, further attempts to that destination wait until we receive any
+packet from it, on any channel, or until retry_interval has passed, if given.
+Messages are sent on the service's channel; frames received on it are discarded.  The
+caller must have requested XBee TX reports. */
+pub fn forward(
+    spooldir: &Path,
+    maxpacketsize: usize,
+    channel: u8,
+    retry_interval: Option<Duration>,
+    sender: crossbeam_channel::Sender<XBTX>,
+    framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
+) -> io::Result<()> {
+    fs::create_dir_all(spooldir)?;
+
+    thread::spawn(move || {
+        for (fromu64, _fromu16, _payload) in framerx.iter() {
+            debug!("SPOOL: discarding frame from {:x}", fromu64);
+        }
+    });
+
+    let mut queues = scan(spooldir)?;
+    let mut lastscan = Instant::now();
+    // Destinations whose last delivery failed, and when that was
+    let mut unreachable: BTreeMap<u64, SystemTime> = BTreeMap::new();
+    let mut inflight: Option<InFlight> = None;
+
+    loop {
+        if lastscan.elapsed() >= SCAN_INTERVAL {
+            queues = scan(spooldir)?;
+            lastscan = Instant::now();
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| mkerror(&e.to_string()))?
+            .as_secs();
+        for (dest, msgs) in queues.iter_mut() {
+            for msg in msgs.iter().filter(|m| m.expires <= now) {
+                info!("SPOOL: message {:?} for {:x} expired", msg.path, dest);
+                let _ = fs::remove_file(&msg.path);
+            }
+            msgs.retain(|m| m.expires > now);
+        }
+        queues.retain(|_, msgs| !msgs.is_empty());
+
+        unreachable.retain(|dest, failed| {
+            if stats::last_seen(*dest).is_some_and(|seen| seen > *failed) {
+                info!("SPOOL: heard from {:x}; will retry delivery", dest);
+                return false;
+            }
+            match retry_interval {
+                Some(interval) => failed.elapsed().is_ok_and(|e| e < interval),
+                None => true,
+            }
+        });
+
+        if inflight.is_none() {
+            inflight = start_delivery(&queues, &unreachable, maxpacketsize, channel, &sender)?;
+        }
+
+        let flight = match &mut inflight {
+            Some(flight) => flight,
+            None => {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            }
+        };
+
+        match flight.statuses.recv_timeout(Duration::from_secs(1)) {
+            Ok((frame_id, delivery_status)) => {
+                if !flight.frame_ids.insert(frame_id) {
+                    debug!("SPOOL: duplicate TX status for frame ID {:X}", frame_id);
+                    continue;
+                }
+                if delivery_status != 0 && !flight.failed {
+                    info!(
+                        "SPOOL: delivery to {:x} failed with status {:X}; holding messages",
+                        flight.dest, delivery_status
+                    );
+                    flight.failed = true;
+                }
+                flight.statuses_remaining -= 1;
+                if flight.statuses_remaining == 0 {
+                    if flight.failed {
+                        unreachable.insert(flight.dest, SystemTime::now());
+                    } else {
+                        info!("SPOOL: delivered {:?} to {:x}", flight.path, flight.dest);
+                        let _ = fs::remove_file(&flight.path);
+                        if let Some(msgs) = queues.get_mut(&flight.dest) {
+                            msgs.retain(|m| m.path != flight.path);
+                        }
+                    }
+                    inflight = None;
+                }
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                if Instant::now() >= flight.deadline {
+                    warn!("SPOOL: no TX status for delivery to {:x}", flight.dest);
+                    unreachable.insert(flight.dest, SystemTime::now());
+                    inflight = None;
+                }
+            }
+            Err(crossbeam_channel::RecvTimeoutError::Disconnected) => {
+                // The writer gave up on the data without sending it.
+                warn!("SPOOL: delivery to {:x} was not transmitted", flight.dest);
+                unreachable.insert(flight.dest, SystemTime::now());
+                inflight = None;
+            }
+        }
+    }
+}
+
+/// Transmit the oldest message for the first reachable destination, if any.
+fn start_delivery(
+    queues: &BTreeMap<u64, Vec<Queued>>,
+    unreachable: &BTreeMap<u64, SystemTime>,
+    maxpacketsize: usize,
+    channel: u8,
+    sender: &crossbeam_channel::Sender<XBTX>,
+) -> io::Result<Option<InFlight>> {
+    for (dest, msgs) in queues
+        .iter()
+        .filter(|(dest, _)| !unreachable.contains_key(dest))
+    {
+        let msg = match msgs.first() {
+            Some(msg) => msg,
+            None => continue,
+        };
+        let data = match fs::read(&msg.path) {
+            Ok(data) => data,
+            Err(e) => {
+                // Perhaps it was removed by hand; catch it on the next scan.
+                debug!("SPOOL: can't read {:?}: {}", msg.path, e);
+                continue;
+            }
+        };
+        if data.is_empty() || data.len() > max_frame_len(maxpacketsize, channel) {
+            warn!(
+                "SPOOL: {:?} is empty or larger than the {} bytes that fit in one frame; discarding",
+                msg.path,
+                max_frame_len(maxpacketsize, channel)
+            );
+            fs::remove_file(&msg.path)?;
+            continue;
+        }
+        let statuses_remaining = packet_count(maxpacketsize, channel, data.len());
+
+        debug!("SPOOL: attempting delivery of {:?} to {:x}", msg.path, dest);
+        let (statustx, statuses) = crossbeam_channel::unbounded();
+        sender
+            .send(XBTX::TXTracked(
+                XBDestAddr::U64(*dest),
+                channel,
+                Bytes::from(data),
+                statustx,
+            ))
+            .map_err(|e| mkerror(&e.to_string()))?;
+        return Ok(Some(InFlight {
+            dest: *dest,
+            path: msg.path.clone(),
+            statuses,
+            frame_ids: BTreeSet::new(),
+            statuses_remaining,
+            failed: false,
+            deadline: Instant::now() + STATUS_TIMEOUT,
+        }));
+    }
+    Ok(None)
+}
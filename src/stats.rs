@@ -0,0 +1,432 @@
+/*! Traffic statistics, per peer and for the radio as a whole */
+
+/*
+    Copyright (C) 2019-2020  John Goerzen <jgoerzen@complete.org
+
+    This program is free software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/* The counters are kept in one global, since they are updated from deep inside the
+receive and transmit paths of every subcommand.  A frame is what a service sends or
+receives; a packet is one radio transmission, of which a frame may take several. */
+
+use crate::events::{self, Field};
+use crate::neighbors;
+use chrono::{DateTime, Local};
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::SystemTime;
+
+/// A packet was queued while the transmit buffer was full
+pub const DROP_TX_FULL: &str = "tx_buffer_full";
+/// No XBee address is known for the destination
+pub const DROP_UNKNOWN_DEST: &str = "unknown_destination";
+/// A packet from the local interface could not be parsed
+pub const DROP_INVALID: &str = "invalid_packet";
+/// The IP version has been disabled
+pub const DROP_IP_DISABLED: &str = "ip_version_disabled";
+/// The local interface would not accept a received frame
+pub const DROP_IFACE_WRITE: &str = "interface_write_failed";
+/// A received packet had no xbnet header
+pub const DROP_NO_HEADER: &str = "no_header";
+/// A received frame was on a channel no service has open
+pub const DROP_UNUSED_CHANNEL: &str = "unused_channel";
+/// The service on a received frame's channel has gone away
+pub const DROP_SERVICE_GONE: &str = "service_gone";
+/// The service on a received frame's channel had too many frames waiting
+pub const DROP_SERVICE_BUSY: &str = "service_busy";
+/// Data to transmit could not be split into packets
+pub const DROP_PACKETIZE: &str = "packetize_failed";
+/// An API frame from the radio was too short for its type
+pub const DROP_MALFORMED: &str = "malformed_frame";
+
+#[derive(Clone, Debug)]
+pub struct Counters {
+    pub frames_in: u64,
+    pub bytes_in: u64,
+    pub frames_out: u64,
+    pub bytes_out: u64,
+    pub packets_in: u64,
+    pub packets_out: u64,
+    /// Transmit statuses reporting success
+    pub tx_ok: u64,
+    /// Transmit statuses reporting any failure
+    pub tx_failed: u64,
+    /// Retransmissions reported in transmit statuses
+    pub tx_retries: u64,
+}
+
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            frames_in: 0,
+            bytes_in: 0,
+            frames_out: 0,
+            bytes_out: 0,
+            packets_in: 0,
+            packets_out: 0,
+            tx_ok: 0,
+            tx_failed: 0,
+            tx_retries: 0,
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "frames_in={} bytes_in={} frames_out={} bytes_out={} packets_in={} packets_out={} tx_ok={} tx_failed={} tx_retries={}",
+            self.frames_in,
+            self.bytes_in,
+            self.frames_out,
+            self.bytes_out,
+            self.packets_in,
+            self.packets_out,
+            self.tx_ok,
+            self.tx_failed,
+            self.tx_retries
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub counters: Counters,
+
+    /// When a packet was last received from the peer
+    pub last_seen: Option<SystemTime>,
+
+    /// The signal strength of the last packet received from the peer, in -dBm
+    pub last_rssi: Option<u8>,
+
+    /// Which of the peer's frames, as counted by frames_in, last_rssi was read after
+    pub last_rssi_frame: Option<u64>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Stats {
+    /// The radio's 64-bit address and maximum packet size, once it is initialized
+    pub radio: Option<(u64, usize)>,
+
+    pub total: Counters,
+
+    /// Transmit statuses, by delivery status code
+    pub tx_statuses: BTreeMap<u8, u64>,
+
+    pub checksum_errors: u64,
+
+    /// Partial frames discarded because a packet of the frame never arrived
+    pub reassembly_failures: u64,
+
+    /// Bytes received from the radio outside of any API frame
+    pub junk_bytes: u64,
+
+    /// Dropped frames and packets, by reason
+    pub drops: BTreeMap<&'static str, u64>,
+
+    pub peers: BTreeMap<u64, PeerStats>,
+
+    /// The destination of each frame ID awaiting a transmit status
+    txpending: BTreeMap<u8, u64>,
+
+    /// Where to report the transmit status of each frame ID that was sent tracked
+    txtrackers: BTreeMap<u8, crossbeam_channel::Sender<(u8, u8)>>,
+
+    /// The peer, and the count of its frames, that each RSSI query was sent after, by
+    /// frame ID
+    rssiqueries: BTreeMap<u8, (u64, u64)>,
+}
+
+static STATS: Mutex<Stats> = Mutex::new(Stats {
+    radio: None,
+    total: Counters::new(),
+    tx_statuses: BTreeMap::new(),
+    checksum_errors: 0,
+    reassembly_failures: 0,
+    junk_bytes: 0,
+    drops: BTreeMap::new(),
+    peers: BTreeMap::new(),
+    txpending: BTreeMap::new(),
+    txtrackers: BTreeMap::new(),
+    rssiqueries: BTreeMap::new(),
+});
+
+static PRINT_ON_EXIT: AtomicBool = AtomicBool::new(false);
+
+/// Whether anything shows RSSI readings, which each cost a query of the radio
+static WANT_RSSI: AtomicBool = AtomicBool::new(false);
+
+impl Stats {
+    fn peer(&mut self, addr: u64) -> &mut PeerStats {
+        self.peers.entry(addr).or_insert(PeerStats {
+            counters: Counters::new(),
+            last_seen: None,
+            last_rssi: None,
+            last_rssi_frame: None,
+        })
+    }
+}
+
+fn with<R, F: FnOnce(&mut Stats) -> R>(f: F) -> R {
+    f(&mut STATS.lock().unwrap())
+}
+
+/// A copy of the statistics as they stand now
+pub fn snapshot() -> Stats {
+    STATS.lock().unwrap().clone()
+}
+
+/// The radio has been initialized.
+pub fn radio_ready(mymac: u64, maxpacketsize: usize) {
+    with(|s| s.radio = Some((mymac, maxpacketsize)))
+}
+
+/// A packet was received from sender.
+pub fn rx_packet(sender: u64) {
+    let first = with(|s| {
+        s.total.packets_in += 1;
+        let peer = s.peer(sender);
+        peer.counters.packets_in += 1;
+        peer.last_seen.replace(SystemTime::now()).is_none()
+    });
+    neighbors::heard(sender);
+    if first {
+        events::emit("peer_first_seen", vec![("addr", events::addr(sender))]);
+    }
+}
+
+/// When a packet was last received from addr
+pub fn last_seen(addr: u64) -> Option<SystemTime> {
+    with(|s| s.peers.get(&addr).and_then(|p| p.last_seen))
+}
+
+/// A complete frame of len bytes was received from sender.
+pub fn rx_frame(sender: u64, len: usize) {
+    with(|s| {
+        s.total.frames_in += 1;
+        s.total.bytes_in += len as u64;
+        let peer = s.peer(sender);
+        peer.counters.frames_in += 1;
+        peer.counters.bytes_in += len as u64;
+    })
+}
+
+/// A frame of len bytes is being sent to dest, which is None for a 16-bit address.
+pub fn tx_frame(dest: Option<u64>, len: usize) {
+    with(|s| {
+        s.total.frames_out += 1;
+        s.total.bytes_out += len as u64;
+        if let Some(dest) = dest {
+            let peer = s.peer(dest);
+            peer.counters.frames_out += 1;
+            peer.counters.bytes_out += len as u64;
+        }
+    })
+}
+
+/// A packet with the given frame ID, 0 for none, was written to the radio for dest.
+pub fn tx_packet(dest: Option<u64>, frame_id: u8) {
+    with(|s| {
+        s.total.packets_out += 1;
+        if let Some(dest) = dest {
+            s.peer(dest).counters.packets_out += 1;
+            if frame_id != 0 {
+                s.txpending.insert(frame_id, dest);
+            }
+        }
+    })
+}
+
+/** Send (frame_id, delivery status) to tracker when the radio reports the outcome of the
+packet with frame_id.  This replaces any tracker left over from an earlier use of the
+frame ID. */
+pub fn track_tx(frame_id: u8, tracker: crossbeam_channel::Sender<(u8, u8)>) {
+    with(|s| s.txtrackers.insert(frame_id, tracker));
+}
+
+/// The radio reported the outcome of sending the packet with frame_id, after retries.
+pub fn tx_status(frame_id: u8, delivery_status: u8, retries: u8) {
+    let (dest, tracker) = with(|s| {
+        let ok = delivery_status == 0;
+        *s.tx_statuses.entry(delivery_status).or_insert(0) += 1;
+        if ok {
+            s.total.tx_ok += 1;
+        } else {
+            s.total.tx_failed += 1;
+        }
+        s.total.tx_retries += u64::from(retries);
+        let tracker = s.txtrackers.remove(&frame_id);
+        let dest = if let Some(dest) = s.txpending.remove(&frame_id) {
+            let peer = s.peer(dest);
+            peer.counters.tx_retries += u64::from(retries);
+            if ok {
+                peer.counters.tx_ok += 1;
+            } else {
+                peer.counters.tx_failed += 1;
+            }
+            Some(dest)
+        } else {
+            None
+        };
+        (dest, tracker)
+    });
+    if let Some(tracker) = tracker {
+        // The tracker may have stopped waiting.
+        let _ = tracker.send((frame_id, delivery_status));
+    }
+    if let Some(dest) = dest {
+        neighbors::tx_report(dest, delivery_status == 0, retries);
+    }
+    if delivery_status != 0 {
+        let mut fields = vec![
+            ("frame_id", Field::from(frame_id)),
+            ("status", Field::from(delivery_status)),
+        ];
+        if let Some(dest) = dest {
+            fields.push(("addr", events::addr(dest)));
+        }
+        events::emit("tx_failed", fields);
+    }
+}
+
+/// Ask for RSSI readings of received frames, for something that shows them.
+pub fn want_rssi() {
+    WANT_RSSI.store(true, Ordering::SeqCst);
+}
+
+/// Whether want_rssi was called
+pub fn wants_rssi() -> bool {
+    WANT_RSSI.load(Ordering::SeqCst)
+}
+
+/// The radio was asked for the RSSI, with frame_id, just after a frame from sender.
+pub fn rssi_query(frame_id: u8, sender: u64) {
+    with(|s| {
+        let frame = s.peer(sender).counters.frames_in;
+        s.rssiqueries.insert(frame_id, (sender, frame));
+    })
+}
+
+/** The radio answered the RSSI query with frame_id, as from ATDB.  The reading is
+credited to the sender of the frame the query followed; an answer to no query of
+ours is ignored. */
+pub fn rssi(frame_id: u8, value: u8) {
+    let addr = with(|s| {
+        let (addr, frame) = s.rssiqueries.remove(&frame_id)?;
+        let peer = s.peer(addr);
+        peer.last_rssi = Some(value);
+        peer.last_rssi_frame = Some(frame);
+        Some(addr)
+    });
+    if let Some(addr) = addr {
+        neighbors::rssi(addr, value);
+    }
+}
+
+pub fn dropped(reason: &'static str) {
+    with(|s| *s.drops.entry(reason).or_insert(0) += 1)
+}
+
+pub fn reassembly_failure() {
+    with(|s| s.reassembly_failures += 1)
+}
+
+pub fn checksum_error() {
+    with(|s| s.checksum_errors += 1)
+}
+
+pub fn junk(len: usize) {
+    with(|s| s.junk_bytes += len as u64)
+}
+
+/** The statistics as lines of text, one for the totals, one for drops, one per peer,
+and one per neighbor, giving the quality of its link. */
+pub fn report() -> Vec<String> {
+    let s = snapshot();
+    let mut out = vec![format!(
+        "total: {} checksum_errors={} junk_bytes={} reassembly_failures={}",
+        s.total.describe(),
+        s.checksum_errors,
+        s.junk_bytes,
+        s.reassembly_failures
+    )];
+    if s.drops.is_empty() {
+        out.push(String::from("drops: none"));
+    } else {
+        let drops: Vec<String> = s.drops.iter().map(|(r, n)| format!("{}={}", r, n)).collect();
+        out.push(format!("drops: {}", drops.join(" ")));
+    }
+    for (addr, peer) in s.peers.iter() {
+        let last_seen = match peer.last_seen {
+            Some(t) => DateTime::<Local>::from(t).format("%Y-%m-%dT%H:%M:%S").to_string(),
+            None => String::from("never"),
+        };
+        let rssi = match peer.last_rssi {
+            Some(r) => format!("-{}", r),
+            None => String::from("none"),
+        };
+        out.push(format!(
+            "peer {:016x}: {} last_seen={} rssi={}",
+            addr,
+            peer.counters.describe(),
+            last_seen,
+            rssi
+        ));
+    }
+    out.extend(neighbors::report());
+    out
+}
+
+/** Print the statistics to stderr when xbnet exits, whether normally, through
+exit_on_error, or on SIGINT or SIGTERM.  Must be called before any other thread is
+started, so that they all leave those signals to the thread started here. */
+pub fn print_on_exit() -> io::Result<()> {
+    print_at_end();
+    let set = unsafe {
+        let mut set: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, libc::SIGINT);
+        libc::sigaddset(&mut set, libc::SIGTERM);
+        match libc::pthread_sigmask(libc::SIG_BLOCK, &set, ptr::null_mut()) {
+            0 => set,
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    };
+    thread::spawn(move || {
+        let mut sig = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } == 0 {
+            exiting();
+            process::exit(128 + sig);
+        }
+    });
+    Ok(())
+}
+
+/// Like print_on_exit, but leave SIGINT and SIGTERM alone.
+pub fn print_at_end() {
+    PRINT_ON_EXIT.store(true, Ordering::SeqCst);
+}
+
+/// Print the statistics, if print_on_exit or print_at_end was called.
+pub fn exiting() {
+    if PRINT_ON_EXIT.load(Ordering::SeqCst) {
+        for line in report() {
+            eprintln!("{}", line);
+        }
+    }
+}
@@ -20,24 +20,30 @@
 
 use tun_tap::{Iface, Mode};
 
-use crate::ser::*;
+use crate::events;
+use crate::neighbors;
+use crate::stats;
 use crate::xb::*;
 use crate::xbpacket::*;
-use crate::xbrx::*;
 use bytes::*;
-use crossbeam_channel;
 use etherparse::*;
 use log::*;
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::io;
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 pub const ETHER_BROADCAST: [u8; 6] = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
 pub const XB_BROADCAST: u64 = 0xffff;
 
+/// The XBee for each Ethernet address, and when that was last learned
+pub type MacDests = HashMap<[u8; 6], (u64, Instant)>;
+
 #[derive(Clone)]
 pub struct XBTap {
+    // Not read since startup, but kept for callers
+    #[allow(dead_code)]
     pub myxbmac: u64,
     pub name: String,
     pub broadcast_unknown: bool,
@@ -47,8 +53,8 @@ pub struct XBTap {
     /** We can't just blindly generate destination MACs because there is a bug
     in the firmware that causes the radio to lock up if we send too many
     packets to a MAC that's not online.  So, we keep a translation map of
-    MACs we've seen. */
-    pub dests: Arc<Mutex<HashMap<[u8; 6], u64>>>,
+    MACs we've seen, with when each was last learned. */
+    pub dests: Arc<Mutex<MacDests>>,
 }
 
 impl XBTap {
@@ -62,9 +68,13 @@ impl XBTap {
         let name = tap.name();
 
         println!("Interface {} (XBee MAC {:x}) ready", name, myxbmac,);
+        events::emit(
+            "interface_up",
+            vec![("interface", name.into()), ("kind", "tap".into())],
+        );
 
         let mut desthm = HashMap::new();
-        desthm.insert(ETHER_BROADCAST, XB_BROADCAST);
+        desthm.insert(ETHER_BROADCAST, (XB_BROADCAST, Instant::now()));
 
         Ok(XBTap {
             myxbmac,
@@ -82,14 +92,35 @@ impl XBTap {
         }
 
         match self.dests.lock().unwrap().get(ethermac) {
-            None => {
+            // A destination whose link is down is treated as unknown, for the sake of
+            // the firmware bug above.
+            Some((dest, _)) if !neighbors::is_down(*dest) => Some(*dest),
+            _ => {
                 if self.broadcast_unknown {
                     Some(XB_BROADCAST)
                 } else {
                     None
                 }
             }
-            Some(dest) => Some(*dest),
+        }
+    }
+
+    /// Send frames for the Ethernet address ethermac to the XBee xbmac.
+    pub fn learn(&self, ethermac: [u8; 6], xbmac: u64) {
+        let old = self
+            .dests
+            .lock()
+            .unwrap()
+            .insert(ethermac, (xbmac, Instant::now()));
+        if old.map(|(dest, _)| dest) != Some(xbmac) {
+            events::emit(
+                "cache_learned",
+                vec![
+                    ("cache", "mac".into()),
+                    ("key", showmac(&ethermac).into()),
+                    ("addr", events::addr(xbmac)),
+                ],
+            );
         }
     }
 
@@ -105,6 +136,7 @@ impl XBTap {
             match SlicedPacket::from_ethernet(tapdata) {
                 Err(x) => {
                     warn!("Error parsing packet from tap; discarding: {:?}", x);
+                    stats::dropped(stats::DROP_INVALID);
                 }
                 Ok(packet) => {
                     if let Some(LinkSlice::Ethernet2(header)) = packet.link {
@@ -114,7 +146,10 @@ impl XBTap {
                             hex::encode(header.destination())
                         );
                         match self.get_xb_dest_mac(header.destination().try_into().unwrap()) {
-                            None => warn!("Destination MAC address unknown; discarding packet"),
+                            None => {
+                                warn!("Destination MAC address unknown; discarding packet");
+                                stats::dropped(stats::DROP_UNKNOWN_DEST);
+                            }
                             Some(destxbmac) => {
                                 let res = sender.try_send(XBTX::TXData(
                                     XBDestAddr::U64(destxbmac),
@@ -123,14 +158,16 @@ impl XBTap {
                                 match res {
                                     Ok(()) => (),
                                     Err(crossbeam_channel::TrySendError::Full(_)) => {
-                                        debug!("Dropped packet due to full TX buffer")
+                                        debug!("Dropped packet due to full TX buffer");
+                                        stats::dropped(stats::DROP_TX_FULL);
                                     }
-                                    Err(e) => Err(e).unwrap(),
+                                    Err(e) => return Err(mkerror(&e.to_string())),
                                 }
                             }
                         }
                     } else {
                         warn!("Unable to get Ethernet2 header from tap packet; discarding");
+                        stats::dropped(stats::DROP_INVALID);
                     }
                 }
             }
@@ -138,11 +175,11 @@ impl XBTap {
     }
     pub fn frames_from_xb_processor(
         &self,
-        xbreframer: &mut XBReframer,
-        ser: &mut XBSerReader,
+        framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
     ) -> io::Result<()> {
         loop {
-            let (fromu64, _fromu16, payload) = xbreframer.rxframe(ser);
+            let (fromu64, _fromu16, payload) =
+                framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
 
             // Register the sender in our map of known MACs
             match SlicedPacket::from_ethernet(&payload) {
@@ -160,10 +197,7 @@ impl XBTap {
                             hex::encode(header.destination())
                         );
                         if !self.broadcast_everything {
-                            self.dests
-                                .lock()
-                                .unwrap()
-                                .insert(header.source().try_into().unwrap(), fromu64);
+                            self.learn(header.source().try_into().unwrap(), fromu64);
                         }
                     }
                 }
@@ -20,12 +20,12 @@
 
 use tun_tap::{Iface, Mode};
 
-use crate::ser::*;
+use crate::events;
+use crate::neighbors;
+use crate::stats;
 use crate::xb::*;
 use crate::xbpacket::*;
-use crate::xbrx::*;
 use bytes::*;
-use crossbeam_channel;
 use etherparse::*;
 use log::*;
 use std::collections::HashMap;
@@ -38,6 +38,8 @@ pub const XB_BROADCAST: u64 = 0xffff;
 
 #[derive(Clone)]
 pub struct XBTun {
+    // Not read since startup, but kept for callers
+    #[allow(dead_code)]
     pub myxbmac: u64,
     pub name: String,
     pub broadcast_everything: bool,
@@ -64,6 +66,10 @@ impl XBTun {
         let name = tun.name();
 
         println!("Interface {} (XBee MAC {:x}) ready", name, myxbmac,);
+        events::emit(
+            "interface_up",
+            vec![("interface", name.into()), ("kind", "tun".into())],
+        );
 
         let desthm = HashMap::new();
 
@@ -84,22 +90,59 @@ impl XBTun {
             return XB_BROADCAST;
         }
 
-        match self.dests.lock().unwrap().get(ipaddr) {
+        let mut dests = self.dests.lock().unwrap();
+        match dests.get(ipaddr) {
             // Broadcast if we don't know it
             None => {
                 XB_BROADCAST
             },
-            Some((dest, expiration)) => {
-                if Instant::now() >= *expiration {
-                    // Broadcast it if the cache entry has expired
+            Some(&(dest, expiration)) => {
+                let expired = Instant::now() >= expiration;
+                if expired || neighbors::is_down(dest) {
+                    // Broadcast it if the cache entry has expired, or the link to the
+                    // destination is down; its next packet will teach us again.
+                    dests.remove(ipaddr);
+                    if expired {
+                        events::emit(
+                            "cache_expired",
+                            vec![
+                                ("cache", "ip".into()),
+                                ("key", ipaddr.to_string().into()),
+                                ("addr", events::addr(dest)),
+                            ],
+                        );
+                    }
                     XB_BROADCAST
                 } else {
-                    *dest
+                    dest
                 }
             }
         }
     }
 
+    /** Send packets for ipaddr to the XBee xbmac until expiration.  An entry for the
+    same XBee is never cut short. */
+    pub fn learn(&self, ipaddr: IpAddr, xbmac: u64, expiration: Instant) {
+        let now = Instant::now();
+        let mut dests = self.dests.lock().unwrap();
+        let old = dests.get(&ipaddr).cloned();
+        let expiration = match old {
+            Some((dest, oldexpiration)) if dest == xbmac => expiration.max(oldexpiration),
+            _ => expiration,
+        };
+        dests.insert(ipaddr, (xbmac, expiration));
+        if !old.is_some_and(|(dest, expiration)| dest == xbmac && now < expiration) {
+            events::emit(
+                "cache_learned",
+                vec![
+                    ("cache", "ip".into()),
+                    ("key", ipaddr.to_string().into()),
+                    ("addr", events::addr(xbmac)),
+                ],
+            );
+        }
+    }
+
     pub fn frames_from_tun_processor(
         &self,
         sender: crossbeam_channel::Sender<XBTX>,
@@ -112,6 +155,7 @@ impl XBTun {
             match SlicedPacket::from_ip(tundata) {
                 Err(x) => {
                     warn!("Error parsing packet from tun; discarding: {:?}", x);
+                    stats::dropped(stats::DROP_INVALID);
                 }
                 Ok(packet) => {
                     let ips = extract_ips(&packet);
@@ -120,11 +164,13 @@ impl XBTun {
                             IpAddr::V6(_) =>
                                 if self.disable_ipv6 {
                                     debug!("Dropping packet because --disable-ipv6 given");
+                                    stats::dropped(stats::DROP_IP_DISABLED);
                                     continue;
                                 },
                             IpAddr::V4(_) =>
                                 if self.disable_ipv4 {
                                     debug!("Dropping packet because --disable-ipv4 given");
+                                    stats::dropped(stats::DROP_IP_DISABLED);
                                     continue;
                                 }
                         };
@@ -143,12 +189,14 @@ impl XBTun {
                         match res {
                             Ok(()) => (),
                             Err(crossbeam_channel::TrySendError::Full(_)) => {
-                                debug!("Dropped packet due to full TX buffer")
+                                debug!("Dropped packet due to full TX buffer");
+                                stats::dropped(stats::DROP_TX_FULL);
                             }
-                            Err(e) => Err(e).unwrap(),
+                            Err(e) => return Err(mkerror(&e.to_string())),
                         }
                     } else {
                         warn!("Unable to get IP header from tun packet; discarding");
+                        stats::dropped(stats::DROP_INVALID);
                     }
                 }
             }
@@ -157,11 +205,10 @@ impl XBTun {
 
     pub fn frames_from_xb_processor(
         &self,
-        xbreframer: &mut XBReframer,
-        ser: &mut XBSerReader,
+        framerx: crossbeam_channel::Receiver<(u64, u16, Bytes)>,
     ) -> io::Result<()> {
         loop {
-            let (fromu64, _fromu16, payload) = xbreframer.rxframe(ser);
+            let (fromu64, _fromu16, payload) = framerx.recv().map_err(|e| mkerror(&e.to_string()))?;
 
             // Register the sender in our map of known MACs
             match SlicedPacket::from_ip(&payload) {
@@ -179,22 +226,19 @@ impl XBTun {
                             IpAddr::V6(_) =>
                                 if self.disable_ipv6 {
                                     debug!("Dropping packet because --disable-ipv6 given");
+                                    stats::dropped(stats::DROP_IP_DISABLED);
                                     continue;
                                 },
                             IpAddr::V4(_) =>
                                 if self.disable_ipv4 {
                                     debug!("Dropping packet because --disable-ipv4 given");
+                                    stats::dropped(stats::DROP_IP_DISABLED);
                                     continue;
                                 }
                         }
                         if !self.broadcast_everything {
-                            self.dests.lock().unwrap().insert(
-                                source,
-                                (
-                                    fromu64,
-                                    Instant::now().checked_add(self.max_ip_cache).unwrap(),
-                                ),
-                            );
+                            let expiration = Instant::now().checked_add(self.max_ip_cache).unwrap();
+                            self.learn(source, fromu64, expiration);
                         }
                     }
                 }
@@ -204,6 +248,7 @@ impl XBTun {
                 Ok(_) => (),
                 Err(e) => {
                     warn!("Failure to send packet to tun interface; have you given it an IP?  Error: {}", e);
+                    stats::dropped(stats::DROP_IFACE_WRITE);
                 }
             }
         }
@@ -16,12 +16,14 @@
 
 */
 
+use crate::pcap;
 use crate::ser::*;
+use crate::stats;
 use crate::xbpacket::*;
+use crate::xbrx::*;
 use bytes::Bytes;
-use crossbeam_channel;
-use hex;
 use log::*;
+use std::convert::TryInto;
 use std::fs;
 use std::io;
 use std::io::{BufRead, BufReader, Error, ErrorKind};
@@ -34,9 +36,19 @@ pub fn mkerror(msg: &str) -> Error {
 }
 
 /// Data to be transmitted out XBee.
+// Spelled like the TX and RX names used throughout
+#[allow(clippy::upper_case_acronyms)]
 pub enum XBTX {
     /// Transmit this data
     TXData(XBDestAddr, Bytes),
+    /// Transmit this data on the given channel.  TXData uses channel 0.
+    TXChannelData(XBDestAddr, u8, Bytes),
+    /** Transmit this data on the given channel, like TXChannelData, and send the frame ID
+    and delivery status of each of its packets to the sender as the radio reports them.
+    XBee TX reports must have been requested. */
+    TXTracked(XBDestAddr, u8, Bytes, crossbeam_channel::Sender<(u8, u8)>),
+    /// Write this complete API frame to the radio as is
+    TXRaw(Bytes),
     /// Shut down the transmitting thread
     Shutdown,
 }
@@ -56,6 +68,8 @@ pub struct XB {
 /// matches the given text.  Return an IOError if either of these
 /// conditions aren't met.  The response type is as given by
 /// ['ser::XBSer::readln'].
+// Not used since the radio is driven in API mode
+#[allow(dead_code)]
 pub fn assert_response(resp: String, expected: String) -> io::Result<()> {
     if resp == expected {
         Ok(())
@@ -84,9 +98,6 @@ impl XB {
         disable_xbee_acks: bool,
         request_xbee_tx_reports: bool,
     ) -> (XB, crossbeam_channel::Sender<XBTX>, thread::JoinHandle<()>) {
-        // FIXME: make this maximum of 5 configurable
-        let (writertx, writerrx) = crossbeam_channel::bounded(5);
-
         debug!("Configuring radio");
         thread::sleep(Duration::from_secs(2));
         trace!("Sending +++");
@@ -111,7 +122,7 @@ impl XB {
             let reader = BufReader::new(f);
             for line in reader.lines() {
                 let line = line.unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     ser_writer.writeln(&line).unwrap();
                     assert_eq!(ser_reader.readln().unwrap().unwrap(), String::from("OK"));
                 }
@@ -148,6 +159,57 @@ impl XB {
 
         debug!("Radio configuration complete");
 
+        XB::start(
+            ser_reader,
+            ser_writer,
+            mymac,
+            maxpacketsize,
+            disable_xbee_acks,
+            request_xbee_tx_reports,
+        )
+    }
+
+    /** Like new, but for a connection to an xbnet broker, which has already initialized
+    the radio.  The radio is queried with API frames instead of command mode. */
+    pub fn new_client(
+        mut ser_reader: XBSerReader,
+        mut ser_writer: XBSerWriter,
+        disable_xbee_acks: bool,
+        request_xbee_tx_reports: bool,
+    ) -> io::Result<(XB, crossbeam_channel::Sender<XBTX>, thread::JoinHandle<()>)> {
+        let mut query = |command: &str| -> io::Result<u64> {
+            ser_writer.swrite.write_all(&atframe(1, command, &[])?)?;
+            ser_writer.swrite.flush()?;
+            let data = atresponse(&mut ser_reader, 1)?;
+            Ok(data.iter().fold(0, |acc, b| acc << 8 | u64::from(*b)))
+        };
+        let mymac = query("SH")? << 32 | query("SL")?;
+        let maxpacketsize = query("NP")? as usize;
+        debug!("Broker reports MAC {:x}, NP {}", mymac, maxpacketsize);
+
+        Ok(XB::start(
+            ser_reader,
+            ser_writer,
+            mymac,
+            maxpacketsize,
+            disable_xbee_acks,
+            request_xbee_tx_reports,
+        ))
+    }
+
+    /// Spawn the writer thread for a radio in API mode.
+    fn start(
+        ser_reader: XBSerReader,
+        ser_writer: XBSerWriter,
+        mymac: u64,
+        maxpacketsize: usize,
+        disable_xbee_acks: bool,
+        request_xbee_tx_reports: bool,
+    ) -> (XB, crossbeam_channel::Sender<XBTX>, thread::JoinHandle<()>) {
+        stats::radio_ready(mymac, maxpacketsize);
+        pcap::set_local(mymac);
+        // FIXME: make this maximum of 5 configurable
+        let (writertx, writerrx) = crossbeam_channel::bounded(5);
         let writerthread = thread::spawn(move || {
             writerthread(
                 ser_writer,
@@ -170,6 +232,43 @@ impl XB {
     }
 }
 
+/// Build the API frame for an AT command, such as "SH".  parameter is empty for a query.
+pub fn atframe(frame_id: u8, command: &str, parameter: &[u8]) -> io::Result<Bytes> {
+    let command = command.to_ascii_uppercase();
+    let command: [u8; 2] = command
+        .as_bytes()
+        .try_into()
+        .map_err(|_| mkerror(&format!("Invalid AT command {:?}", command)))?;
+    XBATCommand {
+        frame_id,
+        command,
+        parameter: Bytes::copy_from_slice(parameter),
+    }
+    .serialize()
+    .map_err(|e| mkerror(&format!("{:?}", e)))
+}
+
+/** Wait for the response to the AT command sent with frame_id, returning its data.
+Other frames received in the meantime are discarded.  An error status from the radio
+is returned as an error. */
+pub fn atresponse(ser_reader: &mut XBSerReader, frame_id: u8) -> io::Result<Bytes> {
+    loop {
+        if let Some(RXFrame::ATResponse(resp)) = rxxbframe(ser_reader)? {
+            if resp.frame_id != frame_id {
+                continue;
+            }
+            if resp.status != 0 {
+                return Err(mkerror(&format!(
+                    "AT{} failed with status {}",
+                    String::from_utf8_lossy(&resp.command),
+                    resp.status
+                )));
+            }
+            return Ok(resp.data);
+        }
+    }
+}
+
 fn writerthread(
     mut ser: XBSerWriter,
     maxpacketsize: usize,
@@ -179,43 +278,67 @@ fn writerthread(
 ) {
     let mut packetstream = PacketStream::new();
     for item in writerrx.iter() {
-        match item {
+        let (dest, channel, data, tracker) = match item {
             XBTX::Shutdown => return,
-            XBTX::TXData(dest, data) => {
-                // Here we receive a block of data, which hasn't been
-                // packetized.  Packetize it and send out the result.
-
-                match packetstream.packetize_data(
-                    maxpacketsize,
-                    &dest,
-                    &data,
-                    disable_xbee_acks,
-                    request_xbee_tx_reports,
-                ) {
-                    Ok(packets) => {
-                        for packet in packets.into_iter() {
-                            match packet.serialize() {
-                                Ok(datatowrite) => {
-                                    trace!(
-                                        "TX ID {:X} to {:?} data {}",
-                                        packet.frame_id,
-                                        &dest,
-                                        hex::encode(&datatowrite)
-                                    );
-                                    ser.swrite.write_all(&datatowrite).unwrap();
-                                    ser.swrite.flush().unwrap();
-                                }
-                                Err(e) => {
-                                    error!("Serialization error: {:?}", e);
+            XBTX::TXRaw(frame) => {
+                trace!("TX raw frame {}", hex::encode(&frame));
+                pcap::tx_apiframe(&frame);
+                ser.swrite.write_all(&frame).unwrap();
+                ser.swrite.flush().unwrap();
+                continue;
+            }
+            XBTX::TXData(dest, data) => (dest, 0, data, None),
+            XBTX::TXChannelData(dest, channel, data) => (dest, channel, data, None),
+            XBTX::TXTracked(dest, channel, data, tracker) => (dest, channel, data, Some(tracker)),
+        };
+        // Here we receive a block of data, which hasn't been
+        // packetized.  Packetize it and send out the result.
+        let dest_u64 = match dest {
+            XBDestAddr::U64(addr) => Some(addr),
+            XBDestAddr::U16(_) => None,
+        };
+
+        match packetstream.packetize_data(
+            maxpacketsize,
+            &dest,
+            channel,
+            &data,
+            disable_xbee_acks,
+            request_xbee_tx_reports,
+        ) {
+            Ok(packets) => {
+                stats::tx_frame(dest_u64, data.len());
+                for packet in packets.into_iter() {
+                    match packet.serialize() {
+                        Ok(datatowrite) => {
+                            trace!(
+                                "TX ID {:X} to {:?} data {}",
+                                packet.frame_id,
+                                &dest,
+                                hex::encode(&datatowrite)
+                            );
+                            // Before writing, since the TX status may be read at once.
+                            stats::tx_packet(dest_u64, packet.frame_id);
+                            match &tracker {
+                                Some(tracker) if packet.frame_id != 0 => {
+                                    stats::track_tx(packet.frame_id, tracker.clone())
                                 }
-                            };
+                                _ => (),
+                            }
+                            pcap::tx_apiframe(&datatowrite);
+                            ser.swrite.write_all(&datatowrite).unwrap();
+                            ser.swrite.flush().unwrap();
                         }
-                    }
-                    Err(e) => {
-                        error!("Packetization error: {}", e);
-                    }
+                        Err(e) => {
+                            error!("Serialization error: {:?}", e);
+                        }
+                    };
                 }
             }
+            Err(e) => {
+                error!("Packetization error: {}", e);
+                stats::dropped(stats::DROP_PACKETIZE);
+            }
         }
     }
 }
@@ -18,7 +18,6 @@
 */
 
 use bytes::*;
-use log::*;
 use std::convert::{TryFrom, TryInto};
 use std::fmt;
 
@@ -27,6 +26,8 @@ address.  This permits the user to select one. */
 #[derive(Eq, PartialEq, Clone)]
 pub enum XBDestAddr {
     /// A 16-bit destination address.  When a 64-bit address is given, this is transmitted as 0xFFFE.
+    // Every subcommand addresses by 64-bit address, but the radio accepts either.
+    #[allow(dead_code)]
     U16(u16),
 
     /// The 64-bit destination address.  0xFFFF for broadcast.
@@ -84,12 +85,6 @@ impl XBTXRequest {
             return Err(TXGenError::InvalidLen);
         }
 
-        // We generate the bits that are outside the length & checksum parts, then the
-        // inner parts, then combine them.
-        let mut fullframe = BytesMut::new();
-
-        fullframe.put_u8(0x7e); // Start delimeter
-
         let mut innerframe = BytesMut::new();
         // Frame type
         innerframe.put_u8(0x10);
@@ -111,20 +106,49 @@ impl XBTXRequest {
         innerframe.put_slice(&self.payload);
 
         // That's it for the inner frame.  Now fill in the outer frame.
-        if let Ok(lenu16) = u16::try_from(innerframe.len()) {
-            fullframe.put_u16(lenu16);
-            fullframe.put_slice(&innerframe);
-            fullframe.put_u8(xbchecksum(&innerframe));
-            Ok(fullframe.freeze())
-        } else {
-            Err(TXGenError::InvalidLen)
-        }
+        mkapiframe(&innerframe)
+    }
+}
+
+/** A Digi local AT command, frame type 0x08 */
+#[derive(Eq, PartialEq, Debug)]
+pub struct XBATCommand {
+    /// The frame ID, which will be returned in the AT command response.
+    pub frame_id: u8,
+
+    /// The two-letter command, such as SH
+    pub command: [u8; 2],
+
+    /// The parameter to set; empty to query the current value
+    pub parameter: Bytes,
+}
+
+impl XBATCommand {
+    pub fn serialize(&self) -> Result<Bytes, TXGenError> {
+        let mut innerframe = BytesMut::new();
+        innerframe.put_u8(0x08);
+        innerframe.put_u8(self.frame_id);
+        innerframe.put_slice(&self.command);
+        innerframe.put_slice(&self.parameter);
+        mkapiframe(&innerframe)
     }
 }
 
+/** Wrap the inside of an API frame, from the frame type on, with the start delimiter,
+length, and checksum. */
+pub fn mkapiframe(inner: &[u8]) -> Result<Bytes, TXGenError> {
+    let lenu16 = u16::try_from(inner.len()).map_err(|_| TXGenError::InvalidLen)?;
+    let mut fullframe = BytesMut::with_capacity(inner.len() + 4);
+    fullframe.put_u8(0x7e); // Start delimeter
+    fullframe.put_u16(lenu16);
+    fullframe.put_slice(inner);
+    fullframe.put_u8(xbchecksum(inner));
+    Ok(fullframe.freeze())
+}
+
 /// Calculate an XBee checksum over a slice
 pub fn xbchecksum(data: &[u8]) -> u8 {
-    let sumu64: u64 = data.into_iter().map(|x| u64::from(*x)).sum();
+    let sumu64: u64 = data.iter().map(|x| u64::from(*x)).sum();
     0xffu8 - (sumu64 as u8)
 }
 
@@ -138,7 +162,7 @@ use xbnet::xbpacket::*;
 let mac64 = 0x123456789abcdeffu64;
 let mac48 = mac64to48(mac64);
 assert_eq!([0x56, 0x78, 0x9a, 0xbc, 0xde, 0xff], mac48);
-assert_eq(mac64, mac48to64(mac48, mac64));
+assert_eq!(mac64, mac48to64(&mac48, mac64));
 ```
 */
 pub fn mac64to48(mac64: u64) -> [u8; 6] {
@@ -157,11 +181,61 @@ pub fn mac48to64(mac48: &[u8; 6], pattern64: u64) -> u64 {
     mac64
 }
 
+/** Every XBee packet we send starts with a header byte giving the number of packets
+remaining in the frame.  If the high bit of that byte is set, a second byte follows
+with the channel number.  Channel 0 frames omit it, so they look exactly like those
+of older versions that had no channels. */
+pub const HEADER_CHANNEL_FLAG: u8 = 0x80;
+
+/** The most XBee packets in a single frame.  This was 255 before channels were added;
+now the high bit of the header byte is HEADER_CHANNEL_FLAG, leaving 7 bits for the
+number of packets remaining. */
+pub const MAX_FRAME_PACKETS: usize = 128;
+
+/// The length of the header on each XBee packet sent on channel
+pub fn header_len(channel: u8) -> usize {
+    if channel == 0 {
+        1
+    } else {
+        2
+    }
+}
+
+/** Parse the header of a received XBee packet.  Return the number of packets
+remaining, the channel, and the length of the header; or None if the payload is
+too short to hold one. */
+pub fn parse_header(payload: &[u8]) -> Option<(u8, u8, usize)> {
+    let first = *payload.first()?;
+    if first & HEADER_CHANNEL_FLAG == 0 {
+        Some((first, 0, 1))
+    } else {
+        let channel = *payload.get(1)?;
+        Some((first & !HEADER_CHANNEL_FLAG, channel, 2))
+    }
+}
+
+/// The largest frame, in bytes, that can be sent on channel.
+pub fn max_frame_len(maxpacketsize: usize, channel: u8) -> usize {
+    MAX_FRAME_PACKETS * (maxpacketsize - header_len(channel))
+}
+
+/// The number of XBee packets that packetize_data will generate for datalen bytes.
+pub fn packet_count(maxpacketsize: usize, channel: u8, datalen: usize) -> usize {
+    let perpacket = maxpacketsize - header_len(channel);
+    (datalen + perpacket - 1) / perpacket
+}
+
 pub struct PacketStream {
     /// The counter for the frame
     framecounter: u8,
 }
 
+impl Default for PacketStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl PacketStream {
     pub fn new() -> Self {
         PacketStream { framecounter: 1 }
@@ -169,7 +243,7 @@ impl PacketStream {
 
     pub fn get_and_incr_framecounter(&mut self) -> u8 {
         let retval = self.framecounter;
-        if self.framecounter == std::u8::MAX {
+        if self.framecounter == u8::MAX {
             self.framecounter = 1
         } else {
             self.framecounter += 1
@@ -180,11 +254,14 @@ impl PacketStream {
     /** Convert the given data into zero or more packets for transmission.
 
     We create a leading byte that indicates how many more XBee packets are remaining
-    for the block.  When zero, the receiver should process the accumulated data. */
+    for the block.  When zero, the receiver should process the accumulated data.
+    Frames on a channel other than 0 also carry the channel number; see
+    HEADER_CHANNEL_FLAG. */
     pub fn packetize_data(
         &mut self,
         maxpacketsize: usize,
         dest: &XBDestAddr,
+        channel: u8,
         data: &[u8],
         disable_xbee_acks: bool,
         request_xbee_tx_reports: bool,
@@ -195,14 +272,26 @@ impl PacketStream {
         }
 
         // trace!("xbpacket: data len {}", data.len());
-        let chunks: Vec<&[u8]> = data.chunks(maxpacketsize - 1).collect();
+        let chunks: Vec<&[u8]> = data.chunks(maxpacketsize - header_len(channel)).collect();
         // trace!("xbpacket: chunk count {}", chunks.len());
-        let mut chunks_remaining: u8 = u8::try_from(chunks.len())
-            .map_err(|e| String::from("More than 255 chunks to transmit"))?;
+        if chunks.len() > MAX_FRAME_PACKETS {
+            return Err(format!(
+                "Frame of {} bytes is too large; at most {} bytes fit in {} packets",
+                data.len(),
+                max_frame_len(maxpacketsize, channel),
+                MAX_FRAME_PACKETS
+            ));
+        }
+        let mut chunks_remaining = chunks.len() as u8;
         for chunk in chunks {
             // trace!("xbpacket: chunks_remaining: {}", chunks_remaining);
             let mut payload = BytesMut::new();
-            payload.put_u8(chunks_remaining - 1);
+            if channel == 0 {
+                payload.put_u8(chunks_remaining - 1);
+            } else {
+                payload.put_u8(HEADER_CHANNEL_FLAG | (chunks_remaining - 1));
+                payload.put_u8(channel);
+            }
             payload.put_slice(chunk);
             let frame_id = if request_xbee_tx_reports {
                 self.get_and_incr_framecounter()
@@ -247,3 +336,125 @@ pub struct ExtTxStatus {
     pub delivery_status: u8,
     pub discovery_status: u8,
 }
+
+/** A Digi local AT command response, 0x88 */
+#[derive(PartialEq, Eq, Debug)]
+pub struct ATResponse {
+    pub frame_id: u8,
+    pub command: [u8; 2],
+    /// 0 for OK; otherwise an error
+    pub status: u8,
+    pub data: Bytes,
+}
+
+/** One node's answer to node discovery, ATND or ATFN, which comes as the data of an
+AT command response.  The layout is that of DigiMesh and Zigbee firmware: MY, SH, SL,
+NI ending in a null, the parent's address, the device type, a status, the profile and
+manufacturer IDs, then the Digi device type and RSSI if ATNO asks for them. */
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct NodeInfo {
+    pub addr64: u64,
+    /// The node identifier, ATNI
+    pub name: String,
+    /// 0 for a coordinator, 1 for a router, 2 for an end device
+    pub device_type: u8,
+    /// The signal strength of the answer, in -dBm, if given
+    pub rssi: Option<u8>,
+}
+
+impl NodeInfo {
+    pub fn parse(mut data: Bytes) -> Option<NodeInfo> {
+        if data.len() < 10 {
+            return None;
+        }
+        data.advance(2);
+        let addr64 = data.get_u64();
+        let nul = data.iter().position(|b| *b == 0)?;
+        let name = String::from_utf8_lossy(&data[..nul]).into_owned();
+        data.advance(nul + 1);
+        if data.len() < 8 {
+            return None;
+        }
+        data.advance(2);
+        let device_type = data.get_u8();
+        data.advance(5);
+        let rssi = match data.len() {
+            1 | 5 => data.last().cloned(),
+            _ => None,
+        };
+        Some(NodeInfo {
+            addr64,
+            name,
+            device_type,
+            rssi,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(addr64: u64, name: &str, device_type: u8, rssi: Option<u8>) -> Option<NodeInfo> {
+        Some(NodeInfo {
+            addr64,
+            name: String::from(name),
+            device_type,
+            rssi,
+        })
+    }
+
+    #[test]
+    fn nodeinfo_parse() {
+        // The data of ATND responses: MY, SH SL, NI and its null, parent, device type,
+        // status, profile, manufacturer, then DD and RSSI as ATNO asks.
+        let cases: &[(&str, &str, Option<NodeInfo>)] = &[
+            (
+                "DigiMesh router",
+                "fffe 0013a20041c5b3a2 6e6f727468746f77657200 fffe 01 00 c105 101e",
+                node(0x0013a20041c5b3a2, "northtower", 1, None),
+            ),
+            (
+                "DigiMesh with DD and RSSI",
+                "fffe 0013a20041c5b3a2 6e6f727468746f77657200 fffe 01 00 c105 101e 000c0000 2a",
+                node(0x0013a20041c5b3a2, "northtower", 1, Some(0x2a)),
+            ),
+            (
+                "DigiMesh with RSSI only",
+                "fffe 0013a20041c5b3a2 00 fffe 02 00 c105 101e 51",
+                node(0x0013a20041c5b3a2, "", 2, Some(0x51)),
+            ),
+            (
+                "DigiMesh with DD only",
+                "fffe 0013a20041c5b3a2 00 fffe 01 00 c105 101e 000c0000",
+                node(0x0013a20041c5b3a2, "", 1, None),
+            ),
+            (
+                "Zigbee coordinator",
+                "0000 0013a200409a0b1c 434f4f524400 fffe 00 00 c105 101e",
+                node(0x0013a200409a0b1c, "COORD", 0, None),
+            ),
+            (
+                "Zigbee end device with DD and RSSI",
+                "7d84 0013a20040a1b2c3 73656e736f7200 0000 02 00 c105 101e 00030000 3c",
+                node(0x0013a20040a1b2c3, "sensor", 2, Some(0x3c)),
+            ),
+            ("empty", "", None),
+            ("addresses cut short", "fffe 0013a200", None),
+            (
+                "name without its null",
+                "fffe 0013a20041c5b3a2 6e6f72",
+                None,
+            ),
+            (
+                "cut short after the name",
+                "fffe 0013a20041c5b3a2 6e6f727468746f77657200 fffe 01 00 c1",
+                None,
+            ),
+        ];
+        for (what, data, expected) in cases {
+            let data = hex::decode(data.replace(' ', "")).unwrap();
+            assert_eq!(&NodeInfo::parse(Bytes::from(data)), expected, "{}", what);
+        }
+    }
+}
@@ -18,31 +18,93 @@
 
 */
 
+use crate::events::{self, Field};
+use crate::neighbors;
+use crate::pcap;
 use crate::ser::*;
+use crate::stats;
 use crate::xbpacket::*;
 use bytes::*;
-use hex;
 use log::*;
 use std::collections::HashMap;
-use std::io::Read;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// How long a partial frame is kept without hearing another packet of it
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/** The longest API frame believed, from the frame type through the data.  Radios carry
+at most a few hundred bytes in a packet, so a longer length is noise, and waiting for
+that much data would swallow the good frames that follow. */
+pub const MAX_API_FRAME_LEN: usize = 1024;
+
+/** An API frame received from the XBee */
+#[derive(PartialEq, Eq, Debug)]
+pub enum RXFrame {
+    /// A receive packet, 0x90
+    RX(RXPacket),
+    /// An extended transmit status, 0x8B
+    TXStatus(ExtTxStatus),
+    /// A local AT command response, 0x88
+    ATResponse(ATResponse),
+    /// Any other frame type, with the data following the frame type byte
+    Other(u8, Bytes),
+    /// A frame too short for its type, whole
+    Malformed(Bytes),
+}
 
 /** Attempts to read a packet from the port.  Returns
 None if it's not an RX frame, or if there is a checksum mismatch. */
-pub fn rxxbpacket(ser: &mut XBSerReader) -> Option<RXPacket> {
+pub fn rxxbpacket(ser: &mut XBSerReader) -> io::Result<Option<RXPacket>> {
+    match rxxbframe(ser)? {
+        Some(RXFrame::RX(packet)) => Ok(Some(packet)),
+        _ => Ok(None),
+    }
+}
+
+/** Attempts to read an API frame of any type from the port, taking note of it as
+noteframe does.  Returns None if there is a checksum mismatch. */
+pub fn rxxbframe(ser: &mut XBSerReader) -> io::Result<Option<RXFrame>> {
+    Ok(rxapiframe(ser)?.map(|inner| {
+        let frame = parseframe(inner);
+        noteframe(&frame);
+        frame
+    }))
+}
+
+/** Read the inside of an API frame, from the frame type on, without interpreting it.
+Returns None if there is a checksum mismatch.  A frame whose length is implausible is
+taken to be noise, and the search for a frame resumes just after its start delimiter;
+after a checksum mismatch, the next search begins at any start delimiter within the bad
+frame, since its length may have been noise. */
+pub fn rxapiframe(ser: &mut XBSerReader) -> io::Result<Option<Bytes>> {
     let mut junkbytes = BytesMut::new();
-    loop {
+    let length = loop {
         let mut startdelim = [0u8; 1];
-        ser.br.read_exact(&mut startdelim).unwrap();
+        ser.read_exact(&mut startdelim)?;
         if startdelim[0] != 0x7e {
             if junkbytes.is_empty() {
                 error!("Receiving junk");
             }
 
             junkbytes.put_u8(startdelim[0]);
-        } else {
-            break;
+            continue;
         }
-    }
+
+        // Read the length.
+        let mut lenbytes = [0u8; 2];
+        ser.read_exact(&mut lenbytes)?;
+        let length = usize::from(u16::from_be_bytes(lenbytes));
+        if length == 0 || length > MAX_API_FRAME_LEN {
+            if junkbytes.is_empty() {
+                error!("Receiving junk");
+            }
+            junkbytes.put_u8(startdelim[0]);
+            ser.unread(&lenbytes);
+            continue;
+        }
+        break length;
+    };
 
     // OK, got the start delimeter.  Log the junk, if any.
     if !junkbytes.is_empty() {
@@ -50,80 +112,154 @@ pub fn rxxbpacket(ser: &mut XBSerReader) -> Option<RXPacket> {
             "Found start delimeter after reading junk: {}",
             hex::encode(&junkbytes)
         );
+        stats::junk(junkbytes.len());
         junkbytes.clear();
     }
 
-    // Read the length.
-
-    let mut lenbytes = [0u8; 2];
-    ser.br.read_exact(&mut lenbytes).unwrap();
-    let length = usize::from(u16::from_be_bytes(lenbytes));
-
     // Now read the rest of the frame.
     let mut inner = vec![0u8; length];
 
-    ser.br.read_exact(&mut inner).unwrap();
+    ser.read_exact(&mut inner)?;
 
     // And the checksum.
     let mut checksum = [0u8; 1];
-    ser.br.read_exact(&mut checksum).unwrap();
+    ser.read_exact(&mut checksum)?;
 
     if xbchecksum(&inner) != checksum[0] {
-        error!("SERIN: Checksum mismatch; data: {}", hex::encode(inner));
-        return None;
+        error!("SERIN: Checksum mismatch; data: {}", hex::encode(&inner));
+        stats::checksum_error();
+        inner.push(checksum[0]);
+        if let Some(resync) = inner.iter().position(|b| *b == 0x7e) {
+            ser.unread(&inner[resync..]);
+        }
+        return Ok(None);
     }
 
-    let mut inner = Bytes::from(inner);
+    pcap::rx_apiframe(&inner);
+    Ok(Some(Bytes::from(inner)))
+}
+
+/// The shortest inside of an API frame of each type that parseframe interprets
+fn min_frame_len(frametype: u8) -> usize {
+    match frametype {
+        0x8B => 7,
+        0x88 => 5,
+        0x90 => 12,
+        _ => 1,
+    }
+}
+
+/** Interpret the inside of an API frame, as from rxapiframe.  Nothing is recorded about
+it; see noteframe. */
+pub fn parseframe(mut inner: Bytes) -> RXFrame {
+    match inner.first() {
+        Some(frametype) if inner.len() >= min_frame_len(*frametype) => (),
+        _ => return RXFrame::Malformed(inner),
+    }
     let frametype = inner.get_u8();
     match frametype {
-        0x8B => {
-            // Delivery status update.  Log and ignore.
-            let frame_id = inner.get_u8();
-            let dest_addr_16 = inner.get_u16();
-            let tx_retry_count = inner.get_u8();
-            let delivery_status = inner.get_u8();
-            let discovery_status = inner.get_u8();
+        0x8B => RXFrame::TXStatus(ExtTxStatus {
+            frame_id: inner.get_u8(),
+            dest_addr_16: inner.get_u16(),
+            tx_retry_count: inner.get_u8(),
+            delivery_status: inner.get_u8(),
+            discovery_status: inner.get_u8(),
+        }),
+        0x88 => RXFrame::ATResponse(ATResponse {
+            frame_id: inner.get_u8(),
+            command: [inner.get_u8(), inner.get_u8()],
+            status: inner.get_u8(),
+            data: inner.to_bytes(),
+        }),
+        0x90 => RXFrame::RX(RXPacket {
+            sender_addr64: inner.get_u64(),
+            sender_addr16: inner.get_u16(),
+            rx_options: inner.get_u8(),
+            payload: inner.to_bytes(),
+        }),
+        _ => RXFrame::Other(frametype, inner),
+    }
+}
+
+/** Log a frame received from the radio, and record what it says in stats, neighbors,
+and events.  Kept apart from parseframe so that frames can be examined, as by monitor,
+without being acted on. */
+pub fn noteframe(frame: &RXFrame) {
+    match frame {
+        RXFrame::Malformed(inner) => {
+            debug!("SERIN: frame too short; data: {}", hex::encode(inner));
+            stats::dropped(stats::DROP_MALFORMED);
+        }
+        RXFrame::TXStatus(status) => {
             trace!("TX STATUS: frame_id: {:X}, dest_addr_16: {:X}, tx_retry_count: {:X}, delivery_status: {:X}, discovery_status: {:X}",
-                   frame_id, dest_addr_16, tx_retry_count, delivery_status, discovery_status);
-            None
+                   status.frame_id, status.dest_addr_16, status.tx_retry_count, status.delivery_status, status.discovery_status);
+            stats::tx_status(status.frame_id, status.delivery_status, status.tx_retry_count);
         }
-        0x90 => {
-            let sender_addr64 = inner.get_u64();
-            let sender_addr16 = inner.get_u16();
-            let rx_options = inner.get_u8();
-            let payload = inner.to_bytes();
+        RXFrame::ATResponse(resp) => {
+            trace!(
+                "SERIN: AT {} response: frame_id: {:X}, status: {:X}, data: {}",
+                String::from_utf8_lossy(&resp.command),
+                resp.frame_id,
+                resp.status,
+                hex::encode(&resp.data)
+            );
+            if &resp.command == b"DB" && resp.status == 0 && resp.data.len() == 1 {
+                stats::rssi(resp.frame_id, resp.data[0]);
+            }
+            // Node discovery ends with an empty response.
+            if (&resp.command == b"ND" || &resp.command == b"FN")
+                && resp.status == 0
+                && !resp.data.is_empty()
+            {
+                match NodeInfo::parse(resp.data.clone()) {
+                    Some(node) => neighbors::discovered(&node),
+                    None => debug!("SERIN: malformed node discovery response"),
+                }
+            }
+        }
+        RXFrame::RX(packet) => {
             trace!(
                 "SERIN: packet from {} / {}, payload {}",
-                hex::encode(sender_addr64.to_be_bytes()),
-                hex::encode(sender_addr16.to_be_bytes()),
-                hex::encode(&payload)
+                hex::encode(packet.sender_addr64.to_be_bytes()),
+                hex::encode(packet.sender_addr16.to_be_bytes()),
+                hex::encode(&packet.payload)
             );
-            Some(RXPacket {
-                sender_addr64,
-                sender_addr16,
-                rx_options,
-                payload,
-            })
-        }
-        _ => {
-            debug!("SERIN: Non-0x90 frame; data: {}", hex::encode(inner));
-            None
+        }
+        RXFrame::Other(0x8A, data) if data.len() == 1 => {
+            // Modem status
+            let status = data[0];
+            debug!("SERIN: modem status {:X}", status);
+            // 0 is a hardware reset, and 1 a watchdog timer reset.
+            if status <= 1 {
+                events::emit("radio_reset", vec![("status", Field::from(status))]);
+            }
+        }
+        RXFrame::Other(_, data) => {
+            debug!("SERIN: Non-0x90 frame; data: {}", hex::encode(data));
         }
     }
 }
 
 /// Like rxxbpacket, but wait until we have a valid packet.
-pub fn rxxbpacket_wait(ser: &mut XBSerReader) -> RXPacket {
+pub fn rxxbpacket_wait(ser: &mut XBSerReader) -> io::Result<RXPacket> {
     loop {
-        if let Some(packet) = rxxbpacket(ser) {
-            return packet;
+        if let Some(packet) = rxxbpacket(ser)? {
+            return Ok(packet);
         }
     }
 }
 
 /// Receives XBee packets, recomposes into larger frames.
 pub struct XBReframer {
-    buf: HashMap<u64, BytesMut>,
+    /// Partial frames, by sender and channel, with the packets remaining in the last
+    /// packet received and when it arrived
+    buf: HashMap<(u64, u8), (u8, Instant, BytesMut)>,
+}
+
+impl Default for XBReframer {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /** Receive a frame that may have been split up into multiple XBee frames.  Reassemble
@@ -135,28 +271,295 @@ impl XBReframer {
         }
     }
 
-    /// Receive a frame.  Indicate the sender (u64, u16) and payload.
-    pub fn rxframe(&mut self, ser: &mut XBSerReader) -> (u64, u16, Bytes) {
+    /// Receive a frame.  Indicate the sender (u64, u16), channel, and payload.
+    pub fn rxframe(&mut self, ser: &mut XBSerReader) -> io::Result<(u64, u16, u8, Bytes)> {
         loop {
-            let packet = rxxbpacket_wait(ser);
-            let mut frame = BytesMut::new();
-            if let Some(olddata) = self.buf.get(&packet.sender_addr64) {
-                frame.extend_from_slice(olddata);
-            };
-
-            frame.extend_from_slice(&packet.payload[1..]);
-            if packet.payload[0] == 0x0 {
-                self.buf.remove(&packet.sender_addr64);
-                return (packet.sender_addr64, packet.sender_addr16, frame.freeze());
+            let packet = rxxbpacket_wait(ser)?;
+            if let Some(frame) = self.reassemble(packet) {
+                return Ok(frame);
+            }
+        }
+    }
+
+    /** Add a received packet to any data already received from its sender on the same
+    channel.  Return the sender (u64, u16), channel, and payload if this packet completed
+    a frame. */
+    pub fn reassemble(&mut self, packet: RXPacket) -> Option<(u64, u16, u8, Bytes)> {
+        stats::rx_packet(packet.sender_addr64);
+        let (remaining, channel, headerlen) = match parse_header(&packet.payload) {
+            Some(header) => header,
+            None => {
+                debug!(
+                    "Dropping packet from {:x} with no xbnet header",
+                    packet.sender_addr64
+                );
+                stats::dropped(stats::DROP_NO_HEADER);
+                return None;
+            }
+        };
+        let key = (packet.sender_addr64, channel);
+        self.expire();
+
+        let mut frame = BytesMut::new();
+        if let Some((lastremaining, _, olddata)) = self.buf.remove(&key) {
+            // Each packet counts down to 0; anything else means one went missing.
+            if remaining.checked_add(1) == Some(lastremaining) {
+                frame.extend_from_slice(&olddata);
             } else {
-                self.buf.insert(packet.sender_addr64, frame);
+                debug!(
+                    "Discarding partial frame from {:x} on channel {}: expected {} packets remaining, got {}",
+                    packet.sender_addr64,
+                    channel,
+                    lastremaining - 1,
+                    remaining
+                );
+                stats::reassembly_failure();
+                events::emit(
+                    "reassembly_failed",
+                    vec![
+                        ("addr", events::addr(packet.sender_addr64)),
+                        ("channel", Field::from(channel)),
+                    ],
+                );
             }
+        };
+
+        frame.extend_from_slice(&packet.payload[headerlen..]);
+        if remaining == 0 {
+            stats::rx_frame(packet.sender_addr64, frame.len());
+            Some((
+                packet.sender_addr64,
+                packet.sender_addr16,
+                channel,
+                frame.freeze(),
+            ))
+        } else {
+            self.buf.insert(key, (remaining, Instant::now(), frame));
+            None
         }
     }
 
-    pub fn discardframes(&mut self, ser: &mut XBSerReader) -> () {
+    /// Discard partial frames that have waited too long for their next packet.
+    fn expire(&mut self) {
+        self.buf.retain(|(sender, channel), (_, last, _)| {
+            if last.elapsed() < REASSEMBLY_TIMEOUT {
+                return true;
+            }
+            debug!(
+                "Discarding partial frame from {:x} on channel {}: timed out",
+                sender, channel
+            );
+            stats::reassembly_failure();
+            events::emit(
+                "reassembly_timeout",
+                vec![
+                    ("addr", events::addr(*sender)),
+                    ("channel", Field::from(*channel)),
+                ],
+            );
+            false
+        });
+    }
+
+    pub fn discardframes(&mut self, ser: &mut XBSerReader) {
         loop {
             let _ = self.rxframe(ser);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::path::PathBuf;
+
+    fn reader(data: Vec<u8>) -> XBSerReader {
+        XBSerReader::new(Box::new(io::Cursor::new(data)), PathBuf::from("test"))
+    }
+
+    /// Everything rxapiframe returns from data before reaching the end.
+    fn readall(data: Vec<u8>) -> Vec<Option<Bytes>> {
+        let mut ser = reader(data);
+        let mut frames = Vec::new();
+        while let Ok(frame) = rxapiframe(&mut ser) {
+            frames.push(frame);
+        }
+        frames
+    }
+
+    fn frame(inner: &[u8]) -> Vec<u8> {
+        mkapiframe(inner).unwrap().to_vec()
+    }
+
+    fn packet(sender: u64, payload: &[u8]) -> RXPacket {
+        RXPacket {
+            sender_addr64: sender,
+            sender_addr16: 0xfffe,
+            rx_options: 0,
+            payload: Bytes::copy_from_slice(payload),
+        }
+    }
+
+    #[test]
+    fn short_frames_are_malformed() {
+        for inner in [&[][..], &[0x90], &[0x90, 0, 0x13, 0xa2], &[0x8b, 1, 2], &[0x88, 1, b'D']] {
+            let inner = Bytes::copy_from_slice(inner);
+            assert_eq!(parseframe(inner.clone()), RXFrame::Malformed(inner));
+        }
+    }
+
+    #[test]
+    fn empty_payload_is_dropped() {
+        let mut reframer = XBReframer::new();
+        assert_eq!(reframer.reassemble(packet(1, &[])), None);
+        assert_eq!(reframer.reassemble(packet(1, &[HEADER_CHANNEL_FLAG])), None);
+    }
+
+    #[test]
+    fn implausible_length_resyncs() {
+        let good = frame(&[0x8a, 0]);
+        for bogus in [&[0x7e, 0xff, 0xff][..], &[0x7e, 0, 0], &[0x7e, 0x7e]] {
+            let mut data = bogus.to_vec();
+            data.extend_from_slice(&good);
+            assert_eq!(readall(data), vec![Some(Bytes::from_static(&[0x8a, 0]))]);
+        }
+    }
+
+    #[test]
+    fn checksum_mismatch_resyncs_within_frame() {
+        // A false start claiming 16 bytes, which swallows all of one frame and part
+        // of the next
+        let first = frame(&[0x8a, 0]);
+        let second = frame(&[0x90, 0, 0x13, 0xa2, 0, 0, 0, 0, 1, 0xff, 0xfe, 1, 0, 0x68, 0x69]);
+        let mut data = vec![0x7e, 0, 16];
+        data.extend_from_slice(&first);
+        data.extend_from_slice(&second);
+        assert_eq!(
+            readall(data),
+            vec![
+                None,
+                Some(Bytes::copy_from_slice(&first[3..first.len() - 1])),
+                Some(Bytes::copy_from_slice(&second[3..second.len() - 1])),
+            ]
+        );
+    }
+
+    #[test]
+    fn recording_replays() {
+        let mut data = frame(&[
+            0x90, 0, 0x13, 0xa2, 0, 0, 0, 0, 1, 0xff, 0xfe, 0, 0, 0x68, 0x69,
+        ]);
+        data.extend(frame(&[0x8b, 5, 0xff, 0xfe, 2, 0, 0]));
+        data.extend(frame(&[0x88, 1, b'N', b'I', 0, b'x']));
+
+        // Record a session, as a radio would send it
+        let path = std::env::temp_dir().join(format!("xbnet-test-{}.rec", std::process::id()));
+        let writer = XBSerWriter {
+            swrite: Box::new(io::sink()),
+            portname: PathBuf::from("test"),
+        };
+        let (mut ser, _) = record(reader(data), writer, &path).unwrap();
+        while rxapiframe(&mut ser).is_ok() {}
+        drop(ser);
+
+        let (mut ser, _) = replay(&path, false).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            rxxbframe(&mut ser).unwrap(),
+            Some(RXFrame::RX(packet(0x0013a20000000001, &[0, 0x68, 0x69])))
+        );
+        assert_eq!(
+            rxxbframe(&mut ser).unwrap(),
+            Some(RXFrame::TXStatus(ExtTxStatus {
+                frame_id: 5,
+                dest_addr_16: 0xfffe,
+                tx_retry_count: 2,
+                delivery_status: 0,
+                discovery_status: 0,
+            }))
+        );
+        assert_eq!(
+            rxxbframe(&mut ser).unwrap(),
+            Some(RXFrame::ATResponse(ATResponse {
+                frame_id: 1,
+                command: *b"NI",
+                status: 0,
+                data: Bytes::from_static(b"x"),
+            }))
+        );
+        // The end of the recording is the end of the file, rather than a silent radio.
+        assert!(rxxbframe(&mut ser).is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn parseframe_never_panics(data in proptest::collection::vec(any::<u8>(), 0..64)) {
+            noteframe(&parseframe(Bytes::from(data)));
+        }
+
+        #[test]
+        fn rxapiframe_never_panics(data in proptest::collection::vec(any::<u8>(), 0..4096)) {
+            for frame in readall(data).into_iter().flatten() {
+                prop_assert!(!frame.is_empty() && frame.len() <= MAX_API_FRAME_LEN);
+                parseframe(frame);
+            }
+        }
+
+        #[test]
+        fn frames_survive_junk(
+            frames in proptest::collection::vec(
+                (
+                    proptest::collection::vec(any::<u8>().prop_filter("no delimiter", |b| *b != 0x7e), 0..16),
+                    proptest::collection::vec(any::<u8>(), 1..300),
+                ),
+                1..8,
+            )
+        ) {
+            let mut data = Vec::new();
+            for (junk, inner) in frames.iter() {
+                data.extend_from_slice(junk);
+                data.extend_from_slice(&frame(inner));
+            }
+            let expected: Vec<Option<Bytes>> =
+                frames.into_iter().map(|(_, inner)| Some(Bytes::from(inner))).collect();
+            prop_assert_eq!(readall(data), expected);
+        }
+
+        #[test]
+        fn reassemble_never_panics(
+            packets in proptest::collection::vec(
+                (0u64..4, proptest::collection::vec(any::<u8>(), 0..8)),
+                0..64,
+            )
+        ) {
+            let mut reframer = XBReframer::new();
+            for (sender, payload) in packets {
+                reframer.reassemble(packet(sender, &payload));
+            }
+        }
+
+        #[test]
+        fn reassembly_round_trip(
+            data in proptest::collection::vec(any::<u8>(), 1..2000),
+            maxpacketsize in 3usize..256,
+            channel in any::<u8>(),
+        ) {
+            let packets = PacketStream::new().packetize_data(
+                maxpacketsize,
+                &XBDestAddr::U64(1),
+                channel,
+                &data,
+                false,
+                false,
+            );
+            prop_assume!(packets.is_ok());
+            let mut reframer = XBReframer::new();
+            let mut frames = Vec::new();
+            for p in packets.unwrap() {
+                frames.extend(reframer.reassemble(packet(2, &p.payload)));
+            }
+            prop_assert_eq!(frames, vec![(2, 0xfffe, channel, Bytes::from(data))]);
+        }
+    }
+}